@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+
+use jsonwebtoken::{decode, decode_header, Algorithm, Validation};
+
+use serde_json::Value;
+
+use channel::to_user_id;
+use cli::{get_api_keys_file, get_auth_subject_mode, get_jwt_issuer, get_jwt_public_key_file,
+          get_jwt_secret};
+
+// Panics
+const PANIC_UNACCEPTABLE_API_KEYS_FILE: &'static str = "Unacceptable api keys file";
+const PANIC_UNACCEPTABLE_AUTH_SUBJECT_MODE: &'static str = "Unacceptable auth subject mode";
+const PANIC_UNACCEPTABLE_JWT_PUBLIC_KEY_FILE: &'static str = "Unacceptable jwt public key file";
+
+// Deliberately vague so as not to help anyone guess
+const INVALID_CREDENTIALS: &'static str = "The credentials are not valid";
+
+/// What the authenticated subject does to the user_id a request carries.
+#[derive(Clone, Copy)]
+pub enum SubjectMode {
+    /// The subject is the user, whatever the request says
+    Override,
+    /// The request may only speak for the subject
+    Validate,
+}
+
+/// Who a request was authenticated as.
+pub struct Principal {
+    mode: SubjectMode,
+    /// API keys may be listed without one, in which case any user_id goes
+    subject: Option<String>,
+}
+
+impl Principal {
+    /// Decides the user_id a request acts as given the one it asked for, if any. Returns
+    /// `Ok(None)` when it is up to the request, and an error when it asked to be someone else.
+    pub fn resolve_user_id(&self, requested: Option<u64>) -> Result<Option<u64>, String> {
+        let subject = match self.subject {
+            Some(ref subject) => subject_to_user_id(subject),
+            None => return Ok(requested),
+        };
+
+        match (self.mode, requested) {
+            (SubjectMode::Validate, Some(requested)) if requested != subject => Err(format!(
+                "The user_id {} does not belong to the authenticated subject",
+                requested
+            )),
+            _ => Ok(Some(subject)),
+        }
+    }
+}
+
+/// Checks the credentials a request presents: static API keys from a file, or HS256/RS256
+/// signed JWT bearer tokens. Authentication is off unless one of them is configured.
+pub struct Authenticator {
+    /// Each key and the subject it authenticates as
+    api_keys: HashMap<String, Option<String>>,
+    hs256_secret: Option<String>,
+    issuer: Option<String>,
+    mode: SubjectMode,
+    /// DER encoded (PKCS#1) RSA public key
+    rs256_public_key: Option<Vec<u8>>,
+}
+
+impl Authenticator {
+    pub fn authenticate(&self, credentials: Option<&str>) -> Result<Principal, String> {
+        // Sanity check: Must have presented something
+        let credentials = match credentials {
+            Some(c) if !c.trim().is_empty() => c.trim(),
+            _ => return Err("Credentials are required".to_owned()),
+        };
+
+        if let Some(subject) = self.api_keys.get(credentials) {
+            return Ok(Principal {
+                mode: self.mode,
+                subject: subject.clone(),
+            });
+        }
+
+        // Anything else had better be a token we signed
+        let subject = self.try_verify_jwt(credentials)?;
+        Ok(Principal {
+            mode: self.mode,
+            subject: Some(subject),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.api_keys.is_empty() || self.hs256_secret.is_some()
+            || self.rs256_public_key.is_some()
+    }
+
+    /// Verifies the signature, expiry and issuer of the token and returns its subject.
+    fn try_verify_jwt(&self, token: &str) -> Result<String, String> {
+        let header = decode_header(token).map_err(|_| INVALID_CREDENTIALS.to_owned())?;
+
+        // Only trust the algorithms we have a key for, each with its own key, so a token can't
+        // be HMAC signed with our public key
+        let key = match header.alg {
+            Algorithm::HS256 => self.hs256_secret.as_ref().map(|s| s.as_bytes()),
+            Algorithm::RS256 => self.rs256_public_key.as_ref().map(|k| k.as_slice()),
+            _ => None,
+        };
+        let key =
+            key.ok_or_else(|| format!("Tokens signed with {:?} are not accepted", header.alg))?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.iss = self.issuer.clone();
+        let token =
+            decode::<Value>(token, key, &validation).map_err(|_| INVALID_CREDENTIALS.to_owned())?;
+
+        token.claims["sub"]
+            .as_str()
+            .map(|s| s.to_owned())
+            .ok_or_else(|| "The token has no subject".to_owned())
+    }
+}
+
+impl Default for Authenticator {
+    fn default() -> Self {
+        Self {
+            api_keys: get_api_keys_file().map(load_api_keys).unwrap_or_default(),
+            hs256_secret: get_jwt_secret(),
+            issuer: get_jwt_issuer(),
+            mode: match get_auth_subject_mode().as_ref() {
+                "override" => SubjectMode::Override,
+                "validate" => SubjectMode::Validate,
+                _ => panic!(PANIC_UNACCEPTABLE_AUTH_SUBJECT_MODE),
+            },
+            rs256_public_key: get_jwt_public_key_file().map(|path| {
+                let mut key = vec![];
+                File::open(path)
+                    .and_then(|mut file| file.read_to_end(&mut key))
+                    .expect(PANIC_UNACCEPTABLE_JWT_PUBLIC_KEY_FILE);
+                key
+            }),
+        }
+    }
+}
+
+/// Compares secrets without bailing early so the time taken doesn't give them away.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Reads "{key} [{subject}]" lines; blank lines and #comments are skipped.
+fn load_api_keys(path: String) -> HashMap<String, Option<String>> {
+    let file = File::open(path).expect(PANIC_UNACCEPTABLE_API_KEYS_FILE);
+    let mut api_keys = HashMap::new();
+    for line in BufReader::new(file).lines() {
+        let line = line.expect(PANIC_UNACCEPTABLE_API_KEYS_FILE);
+        let mut parts = line.split_whitespace();
+        match parts.next() {
+            Some(key) if !key.starts_with('#') => {
+                api_keys.insert(key.to_owned(), parts.next().map(|s| s.to_owned()));
+            }
+            _ => (),
+        }
+    }
+
+    api_keys
+}
+
+/// Numeric subjects are user ids already; others are hashed like any other network's users.
+fn subject_to_user_id(subject: &str) -> u64 {
+    subject
+        .parse()
+        .unwrap_or_else(|_| to_user_id(&["auth", subject]))
+}
+
+/// Gets the token from an "Authorization: Bearer {token}" header.
+pub fn try_get_bearer_token(authorization: &str) -> Option<String> {
+    let authorization = authorization.trim();
+    if authorization.len() > 7 && authorization[..7].eq_ignore_ascii_case("bearer ") {
+        Some(authorization[7..].to_owned())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn principal(mode: SubjectMode, subject: Option<&str>) -> Principal {
+        Principal {
+            mode: mode,
+            subject: subject.map(|s| s.to_owned()),
+        }
+    }
+
+    #[test]
+    fn bearer_token() {
+        assert_eq!(try_get_bearer_token("Bearer abc"), Some("abc".to_owned()));
+        assert_eq!(try_get_bearer_token(" bearer abc "), Some("abc".to_owned()));
+        assert_eq!(try_get_bearer_token("Basic abc"), None);
+        assert_eq!(try_get_bearer_token("Bearer "), None);
+    }
+
+    #[test]
+    fn constant_time_eq_compares_everything() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secrets"));
+        assert!(!constant_time_eq(b"", b"secret"));
+    }
+
+    #[test]
+    fn override_uses_subject() {
+        let principal = principal(SubjectMode::Override, Some("42"));
+        assert_eq!(principal.resolve_user_id(None), Ok(Some(42)));
+        assert_eq!(principal.resolve_user_id(Some(7)), Ok(Some(42)));
+    }
+
+    #[test]
+    fn subjectless_key_acts_as_anyone() {
+        let principal = principal(SubjectMode::Validate, None);
+        assert_eq!(principal.resolve_user_id(None), Ok(None));
+        assert_eq!(principal.resolve_user_id(Some(7)), Ok(Some(7)));
+    }
+
+    #[test]
+    fn subjects_hash_unless_numeric() {
+        assert_eq!(subject_to_user_id("42"), 42);
+        assert_eq!(subject_to_user_id("alice"), to_user_id(&["auth", "alice"]));
+        assert!(subject_to_user_id("alice") != subject_to_user_id("bob"));
+    }
+
+    #[test]
+    fn validate_refuses_others() {
+        let principal = principal(SubjectMode::Validate, Some("42"));
+        assert_eq!(principal.resolve_user_id(None), Ok(Some(42)));
+        assert_eq!(principal.resolve_user_id(Some(42)), Ok(Some(42)));
+        assert!(principal.resolve_user_id(Some(7)).is_err());
+    }
+}
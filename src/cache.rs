@@ -0,0 +1,63 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use lru_cache::LruCache;
+
+use cli::get_cache_max_stale;
+use metrics::{observe_cache_lookup, observe_stale_cache_use};
+
+// Panics
+const PANIC_POISONED_CACHE: &'static str = "Poisoned cache";
+
+/// What we got from an upstream API, shared by every thread. Entries are fresh for a while and
+/// then stale; stale entries are only used when the upstream can't be reached, and not at all
+/// once they are more than `--cache-max-stale` past fresh.
+pub struct StaleCache<V> {
+    entries: Mutex<LruCache<String, (Instant, V)>>,
+    fresh_for: Duration,
+    max_age: Duration,
+    name: &'static str,
+}
+
+impl<V: Clone> StaleCache<V> {
+    pub fn new(name: &'static str, capacity: usize, fresh_for: Duration) -> Self {
+        Self {
+            entries: Mutex::new(LruCache::new(capacity)),
+            fresh_for: fresh_for,
+            max_age: fresh_for + get_cache_max_stale(),
+            name: name,
+        }
+    }
+
+    pub fn insert(&self, key: String, val: V) {
+        let mut entries = self.entries.lock().expect(PANIC_POISONED_CACHE);
+        entries.insert(key, (Instant::now(), val));
+    }
+
+    pub fn try_get_fresh(&self, key: &str) -> Option<V> {
+        let val = self.try_get(key, self.fresh_for).map(|(val, _)| val);
+        observe_cache_lookup(self.name, val.is_some());
+
+        val
+    }
+
+    /// Gets an entry which may no longer be fresh, along with how old it is.
+    pub fn try_get_stale(&self, key: &str) -> Option<(V, Duration)> {
+        let entry = self.try_get(key, self.max_age);
+        if entry.is_some() {
+            observe_stale_cache_use(self.name);
+        }
+
+        entry
+    }
+
+    fn try_get(&self, key: &str, max_age: Duration) -> Option<(V, Duration)> {
+        let mut entries = self.entries.lock().expect(PANIC_POISONED_CACHE);
+        match entries.get_mut(key) {
+            Some(&mut (fetched_at, ref val)) if fetched_at.elapsed() < max_age => {
+                Some((val.clone(), fetched_at.elapsed()))
+            }
+            _ => None,
+        }
+    }
+}
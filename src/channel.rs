@@ -0,0 +1,157 @@
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use futures::future::join_all;
+use futures::Future;
+
+use json::JsonValue;
+
+use tokio_minihttp::Request;
+
+use harris::{Event, Harris, Message};
+use intent::Reply;
+use logging;
+use matrix::MatrixChannel;
+use session::SessionStore;
+use subscription::SubscriptionStore;
+use telegram::TelegramChannel;
+
+// A polling channel which fails waits this long before trying again
+const POLL_RETRY_SECS: u64 = 5;
+
+/// A message someone sent Harris over a chat network.
+pub struct Inbound {
+    /// Where the reply goes: a chat, room or channel id
+    pub conversation: String,
+    pub text: String,
+    pub user_id: u64,
+}
+
+/// A chat network Harris can be reached on. Networks either push updates to a webhook route
+/// (/channels/{name}) or are polled; either way the updates are json which the channel knows
+/// how to pull messages out of.
+pub trait Channel: Send {
+    fn name(&self) -> &'static str;
+
+    /// Whether a webhook request really came from the network. Networks which are polled
+    /// never post to us, so by default nothing is accepted.
+    fn accepts(&self, _request: &Request) -> bool {
+        false
+    }
+
+    fn parse(&self, updates: &JsonValue) -> Vec<Inbound>;
+
+    /// Waits for the next updates from networks which must be polled. Networks which push to
+    /// a webhook instead return `None`.
+    fn poll(&mut self) -> Option<Result<JsonValue, String>> {
+        None
+    }
+
+    fn send(&self, conversation: &str, reply: &Reply) -> Result<(), String>;
+}
+
+/// Answers each message in the updates, pairing each reply with the conversation it goes to.
+fn answer(
+    channel: &Channel,
+    harris: &Harris,
+    updates: &JsonValue,
+) -> Box<Future<Item = Vec<(String, Reply)>, Error = ()>> {
+    let replies: Vec<_> = channel
+        .parse(updates)
+        .into_iter()
+        .map(|inbound| {
+            let conversation = inbound.conversation;
+            harris
+                .respond(&Event::Message(Message::new(inbound.user_id, inbound.text)))
+                .map(move |reply| (conversation, reply))
+        })
+        .collect();
+
+    Box::new(join_all(replies))
+}
+
+/// Answers each message in the updates and sends the replies back where they came from,
+/// blocking until it's done; only for threads of our own.
+pub fn dispatch(channel: &Channel, harris: &Harris, updates: &JsonValue) {
+    // This will always succeed because replies don't fail
+    let replies = answer(channel, harris, updates).wait().unwrap();
+    send_replies(channel, replies);
+}
+
+/// Polls the channel and answers what arrives until it says it can't be polled.
+pub fn poll_forever(
+    mut channel: Box<Channel>,
+    sessions: SessionStore,
+    subscriptions: SubscriptionStore,
+) {
+    let harris = Harris::new(sessions, subscriptions);
+    loop {
+        match channel.poll() {
+            Some(Ok(updates)) => dispatch(channel.as_ref(), &harris, &updates),
+            Some(Err(e)) => {
+                logging::warn(format!("Unable to poll {}: {}", channel.name(), e));
+                thread::sleep(Duration::from_secs(POLL_RETRY_SECS));
+            }
+            None => return,
+        }
+    }
+}
+
+/// The configured channels which must be polled.
+pub fn polling_channels() -> Vec<Box<Channel>> {
+    let mut channels: Vec<Box<Channel>> = vec![];
+    if let Some(matrix) = MatrixChannel::try_new() {
+        channels.push(Box::new(matrix));
+    }
+
+    channels
+}
+
+/// Maps a user of some other network onto our numeric user ids using FNV-1a, which (unlike the
+/// std hasher) is guaranteed to give the same answer across builds so sessions and
+/// subscriptions stick. The first part should name the network.
+pub fn to_user_id(parts: &[&str]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in parts.join(":").bytes() {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+
+    hash
+}
+
+/// Sends the replies back over the channel they came from.
+fn send_replies(channel: &Channel, replies: Vec<(String, Reply)>) {
+    for (conversation, reply) in replies {
+        if let Err(e) = channel.send(&conversation, &reply) {
+            logging::error(format!("Unable to reply on {}: {}", channel.name(), e));
+        }
+    }
+}
+
+/// The configured channels which push to a webhook route.
+pub fn webhook_channels() -> Vec<Arc<Channel + Sync>> {
+    let mut channels: Vec<Arc<Channel + Sync>> = vec![];
+    if let Some(telegram) = TelegramChannel::try_new() {
+        channels.push(Arc::new(telegram));
+    }
+
+    channels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn user_ids_are_fnv_1a() {
+        // The published FNV-1a test vectors, which must never change under existing users
+        assert_eq!(to_user_id(&[""]), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(to_user_id(&["a"]), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(to_user_id(&["foobar"]), 0x8594_4171_f739_67e8);
+
+        assert_eq!(to_user_id(&["telegram", "42"]), to_user_id(&["telegram:42"]));
+        assert!(to_user_id(&["telegram", "42"]) != to_user_id(&["slack", "42"]));
+    }
+}
@@ -1,5 +1,40 @@
+use std::net::IpAddr;
+use std::time::Duration;
+
 use clap::App;
 
+// Panics
+const PANIC_UNACCEPTABLE_CACHE_MAX_STALE: &'static str = "Unacceptable cache max stale";
+const PANIC_UNACCEPTABLE_CORS_MAX_AGE: &'static str = "Unacceptable CORS max age";
+const PANIC_UNACCEPTABLE_DRAIN_TIMEOUT: &'static str = "Unacceptable drain timeout";
+const PANIC_UNACCEPTABLE_INTENT_CONFIRM_THRESHOLD: &'static str =
+    "Unacceptable intent confirm threshold";
+const PANIC_UNACCEPTABLE_INTENT_THRESHOLD: &'static str = "Unacceptable intent threshold";
+const PANIC_UNACCEPTABLE_NOTIFY_INTERVAL: &'static str = "Unacceptable notify interval";
+const PANIC_UNACCEPTABLE_RATE_LIMIT: &'static str = "Unacceptable rate limit";
+const PANIC_UNACCEPTABLE_SEED: &'static str = "Unacceptable seed";
+const PANIC_UNACCEPTABLE_TRUSTED_PROXIES: &'static str = "Unacceptable trusted proxies";
+const PANIC_UNACCEPTABLE_UPSTREAM_BREAKER_COOLDOWN: &'static str =
+    "Unacceptable upstream breaker cooldown";
+const PANIC_UNACCEPTABLE_UPSTREAM_BREAKER_FAILURES: &'static str =
+    "Unacceptable upstream breaker failures";
+const PANIC_UNACCEPTABLE_UPSTREAM_BUDGET: &'static str = "Unacceptable upstream budget";
+const PANIC_UNACCEPTABLE_UPSTREAM_CONNECT_TIMEOUT: &'static str =
+    "Unacceptable upstream connect timeout";
+const PANIC_UNACCEPTABLE_UPSTREAM_DEADLINE: &'static str = "Unacceptable upstream deadline";
+const PANIC_UNACCEPTABLE_UPSTREAM_READ_TIMEOUT: &'static str =
+    "Unacceptable upstream read timeout";
+const PANIC_UNACCEPTABLE_UPSTREAM_RETRIES: &'static str = "Unacceptable upstream retries";
+const PANIC_UNACCEPTABLE_WEBHOOK_RETRIES: &'static str = "Unacceptable webhook retries";
+const PANIC_UNACCEPTABLE_WS_MAX_CONNECTIONS: &'static str =
+    "Unacceptable websocket max connections";
+
+fn app_is_present(key: &str) -> bool {
+    let cli = load_yaml!("../cli.yml");
+    let app = App::from_yaml(&cli).get_matches();
+    app.is_present(key)
+}
+
 fn app_value_of(key: &str) -> Option<String> {
     // Load the command-line-argument-parser (CLAP) library
     let cli = load_yaml!("../cli.yml");
@@ -15,10 +50,79 @@ fn app_value_of(key: &str) -> Option<String> {
     }
 }
 
+/// Parses an optional rate limit, which must allow something through.
+fn app_rate_limit_of(key: &str) -> Option<u32> {
+    app_value_of(key).map(|limit| {
+        let limit: u32 = limit.parse().expect(PANIC_UNACCEPTABLE_RATE_LIMIT);
+        if limit == 0 {
+            panic!(PANIC_UNACCEPTABLE_RATE_LIMIT);
+        }
+
+        limit
+    })
+}
+
+/// Splits a comma separated option into its (trimmed, non-empty) items.
+fn app_values_of(key: &str) -> Vec<String> {
+    app_value_of(key)
+        .unwrap_or_default()
+        .split(',')
+        .map(|v| v.trim().to_owned())
+        .filter(|v| !v.is_empty())
+        .collect()
+}
+
+pub fn get_api_keys_file() -> Option<String> {
+    app_value_of("api-keys")
+}
+
+pub fn get_auth_subject_mode() -> String {
+    app_value_of("auth-subject-mode").unwrap()
+}
+
+pub fn get_cache_max_stale() -> Duration {
+    let secs = app_value_of("cache-max-stale")
+        .unwrap()
+        .parse()
+        .expect(PANIC_UNACCEPTABLE_CACHE_MAX_STALE);
+    Duration::from_secs(secs)
+}
+
+pub fn get_cors_allow_credentials() -> bool {
+    app_is_present("cors-allow-credentials")
+}
+
+pub fn get_cors_headers() -> Vec<String> {
+    app_values_of("cors-headers")
+}
+
+pub fn get_cors_max_age() -> u32 {
+    app_value_of("cors-max-age")
+        .unwrap()
+        .parse()
+        .expect(PANIC_UNACCEPTABLE_CORS_MAX_AGE)
+}
+
+pub fn get_cors_methods() -> Vec<String> {
+    app_values_of("cors-methods")
+}
+
+pub fn get_cors_origins() -> Vec<String> {
+    app_values_of("cors-origins")
+}
+
 pub fn get_dark_sky_api_key() -> String {
     app_value_of("dark-sky-api-key").unwrap()
 }
 
+pub fn get_drain_timeout() -> Duration {
+    let secs = app_value_of("drain-timeout")
+        .unwrap()
+        .parse()
+        .expect(PANIC_UNACCEPTABLE_DRAIN_TIMEOUT);
+    Duration::from_secs(secs)
+}
+
 pub fn get_google_api_key() -> String {
     app_value_of("google-api-key").unwrap()
 }
@@ -29,6 +133,219 @@ pub fn get_http_binding() -> String {
     format!("{}:{}", &address, &port)
 }
 
+pub fn get_intent_confirm_threshold() -> f32 {
+    let threshold: f32 = app_value_of("intent-confirm-threshold")
+        .unwrap()
+        .parse()
+        .expect(PANIC_UNACCEPTABLE_INTENT_CONFIRM_THRESHOLD);
+
+    // Sanity check: Asking for confirmation only makes sense below the intent threshold
+    if threshold < 0. || threshold > get_intent_threshold() {
+        panic!(PANIC_UNACCEPTABLE_INTENT_CONFIRM_THRESHOLD);
+    }
+
+    threshold
+}
+
+pub fn get_intent_threshold() -> f32 {
+    let threshold: f32 = app_value_of("intent-threshold")
+        .unwrap()
+        .parse()
+        .expect(PANIC_UNACCEPTABLE_INTENT_THRESHOLD);
+
+    // Sanity check: Must be a probability
+    if threshold < 0. || threshold > 1. {
+        panic!(PANIC_UNACCEPTABLE_INTENT_THRESHOLD);
+    }
+
+    threshold
+}
+
+pub fn get_jwt_issuer() -> Option<String> {
+    app_value_of("jwt-issuer")
+}
+
+pub fn get_jwt_public_key_file() -> Option<String> {
+    app_value_of("jwt-public-key")
+}
+
+pub fn get_jwt_secret() -> Option<String> {
+    app_value_of("jwt-secret")
+}
+
+pub fn get_log_format() -> String {
+    app_value_of("log-format").unwrap()
+}
+
+pub fn get_log_level() -> String {
+    app_value_of("log-level").unwrap()
+}
+
+pub fn get_log_redact() -> bool {
+    app_is_present("log-redact")
+}
+
+pub fn get_matrix_access_token() -> Option<String> {
+    app_value_of("matrix-access-token")
+}
+
+pub fn get_matrix_homeserver() -> Option<String> {
+    app_value_of("matrix-homeserver")
+}
+
+pub fn get_notify_interval() -> Duration {
+    let secs = app_value_of("notify-interval")
+        .unwrap()
+        .parse()
+        .expect(PANIC_UNACCEPTABLE_NOTIFY_INTERVAL);
+    Duration::from_secs(secs)
+}
+
+pub fn get_probe_upstream() -> bool {
+    app_is_present("probe-upstream")
+}
+
+pub fn get_rate_limit_global() -> Option<u32> {
+    app_rate_limit_of("rate-limit-global")
+}
+
+pub fn get_rate_limit_ip() -> Option<u32> {
+    app_rate_limit_of("rate-limit-ip")
+}
+
+pub fn get_rate_limit_user() -> Option<u32> {
+    app_rate_limit_of("rate-limit-user")
+}
+
+pub fn get_seed() -> Option<u64> {
+    app_value_of("seed").map(|seed| seed.parse().expect(PANIC_UNACCEPTABLE_SEED))
+}
+
+pub fn get_slack_bot_token() -> Option<String> {
+    app_value_of("slack-bot-token")
+}
+
+pub fn get_slack_signing_secret() -> Option<String> {
+    app_value_of("slack-signing-secret")
+}
+
+pub fn get_subscriptions_file() -> String {
+    app_value_of("subscriptions").unwrap()
+}
+
+pub fn get_telegram_api_url() -> String {
+    app_value_of("telegram-api-url").unwrap()
+}
+
+pub fn get_telegram_secret() -> Option<String> {
+    app_value_of("telegram-secret")
+}
+
+pub fn get_telegram_token() -> Option<String> {
+    app_value_of("telegram-token")
+}
+
+pub fn get_tls_cert_file() -> Option<String> {
+    app_value_of("tls-cert")
+}
+
+pub fn get_tls_key_file() -> Option<String> {
+    app_value_of("tls-key")
+}
+
 pub fn get_training_file() -> String {
     app_value_of("training").unwrap()
 }
+
+pub fn get_trusted_proxies() -> Vec<IpAddr> {
+    app_values_of("trusted-proxies")
+        .iter()
+        .map(|proxy| proxy.parse().expect(PANIC_UNACCEPTABLE_TRUSTED_PROXIES))
+        .collect()
+}
+
+pub fn get_upstream_breaker_cooldown() -> Duration {
+    let secs = app_value_of("upstream-breaker-cooldown")
+        .unwrap()
+        .parse()
+        .expect(PANIC_UNACCEPTABLE_UPSTREAM_BREAKER_COOLDOWN);
+    Duration::from_secs(secs)
+}
+
+pub fn get_upstream_breaker_failures() -> u32 {
+    app_value_of("upstream-breaker-failures")
+        .unwrap()
+        .parse()
+        .expect(PANIC_UNACCEPTABLE_UPSTREAM_BREAKER_FAILURES)
+}
+
+pub fn get_upstream_budget() -> Option<u32> {
+    app_value_of("upstream-budget").map(|budget| {
+        budget
+            .parse()
+            .expect(PANIC_UNACCEPTABLE_UPSTREAM_BUDGET)
+    })
+}
+
+pub fn get_upstream_connect_timeout() -> Duration {
+    let secs = app_value_of("upstream-connect-timeout")
+        .unwrap()
+        .parse()
+        .expect(PANIC_UNACCEPTABLE_UPSTREAM_CONNECT_TIMEOUT);
+    Duration::from_secs(secs)
+}
+
+pub fn get_upstream_deadline() -> Duration {
+    let secs = app_value_of("upstream-deadline")
+        .unwrap()
+        .parse()
+        .expect(PANIC_UNACCEPTABLE_UPSTREAM_DEADLINE);
+    Duration::from_secs(secs)
+}
+
+pub fn get_upstream_read_timeout() -> Duration {
+    let secs = app_value_of("upstream-read-timeout")
+        .unwrap()
+        .parse()
+        .expect(PANIC_UNACCEPTABLE_UPSTREAM_READ_TIMEOUT);
+    Duration::from_secs(secs)
+}
+
+pub fn get_upstream_retries() -> u32 {
+    app_value_of("upstream-retries")
+        .unwrap()
+        .parse()
+        .expect(PANIC_UNACCEPTABLE_UPSTREAM_RETRIES)
+}
+
+pub fn get_webhook_dead_letters_file() -> String {
+    app_value_of("webhook-dead-letters").unwrap()
+}
+
+pub fn get_webhook_retries() -> u32 {
+    app_value_of("webhook-retries")
+        .unwrap()
+        .parse()
+        .expect(PANIC_UNACCEPTABLE_WEBHOOK_RETRIES)
+}
+
+pub fn get_webhook_secret() -> Option<String> {
+    app_value_of("webhook-secret")
+}
+
+pub fn get_webhook_url() -> Option<String> {
+    app_value_of("webhook-url")
+}
+
+pub fn get_ws_binding() -> String {
+    let address = app_value_of("address").unwrap();
+    let port = app_value_of("ws-port").unwrap();
+    format!("{}:{}", &address, &port)
+}
+
+pub fn get_ws_max_connections() -> usize {
+    app_value_of("ws-max-connections")
+        .unwrap()
+        .parse()
+        .expect(PANIC_UNACCEPTABLE_WS_MAX_CONNECTIONS)
+}
@@ -0,0 +1,182 @@
+use cli::{get_cors_allow_credentials, get_cors_headers, get_cors_max_age, get_cors_methods,
+          get_cors_origins};
+
+// Headers
+pub const HEADER_ACCESS_CONTROL_REQUEST_HEADERS: &'static str = "Access-Control-Request-Headers";
+pub const HEADER_ACCESS_CONTROL_REQUEST_METHOD: &'static str = "Access-Control-Request-Method";
+pub const HEADER_ORIGIN: &'static str = "Origin";
+const HEADER_ACCESS_CONTROL_ALLOW_CREDENTIALS: &'static str = "Access-Control-Allow-Credentials";
+const HEADER_ACCESS_CONTROL_ALLOW_HEADERS: &'static str = "Access-Control-Allow-Headers";
+const HEADER_ACCESS_CONTROL_ALLOW_METHODS: &'static str = "Access-Control-Allow-Methods";
+const HEADER_ACCESS_CONTROL_ALLOW_ORIGIN: &'static str = "Access-Control-Allow-Origin";
+const HEADER_ACCESS_CONTROL_MAX_AGE: &'static str = "Access-Control-Max-Age";
+const HEADER_VARY: &'static str = "Vary";
+
+// Panics
+const PANIC_UNACCEPTABLE_CORS_ORIGINS: &'static str =
+    "Unacceptable CORS origins: * can't be used with --cors-allow-credentials";
+
+const ANY_ORIGIN: &'static str = "*";
+
+/// Which browser origins may call us, and how, as configured on the command line.
+#[derive(Clone)]
+pub struct CorsPolicy {
+    allow_credentials: bool,
+    headers: Vec<String>,
+    max_age: u32,
+    methods: Vec<String>,
+    origins: Vec<String>,
+}
+
+impl CorsPolicy {
+    /// The headers to add to a response for a request from the origin; none if the origin
+    /// isn't allowed (or there isn't one, which means it's not a cross-origin request).
+    pub fn headers(&self, origin: Option<&str>) -> Vec<(&'static str, String)> {
+        let origin = match origin {
+            Some(origin) if self.allows_origin(origin) => origin,
+            _ => return vec![],
+        };
+
+        // Listed origins are echoed back, in which case caches must know the answer depends on
+        // it (credentials are never allowed with the wildcard; see Default)
+        let mut headers = vec![];
+        if self.origins.iter().any(|o| o == ANY_ORIGIN) {
+            headers.push((HEADER_ACCESS_CONTROL_ALLOW_ORIGIN, ANY_ORIGIN.to_owned()));
+        } else {
+            headers.push((HEADER_ACCESS_CONTROL_ALLOW_ORIGIN, origin.to_owned()));
+            headers.push((HEADER_VARY, HEADER_ORIGIN.to_owned()));
+        }
+
+        if self.allow_credentials {
+            headers.push((HEADER_ACCESS_CONTROL_ALLOW_CREDENTIALS, "true".to_owned()));
+        }
+
+        headers
+    }
+
+    /// The headers answering a preflight (OPTIONS) request, or why it is refused.
+    pub fn preflight_headers(
+        &self,
+        origin: Option<&str>,
+        method: Option<&str>,
+        request_headers: Option<&str>,
+    ) -> Result<Vec<(&'static str, String)>, String> {
+        // Sanity check: A preflight must say who is asking and for which method
+        let origin = origin.ok_or_else(|| format!("The {} header is required", HEADER_ORIGIN))?;
+        let method = method.ok_or_else(|| {
+            format!("The {} header is required", HEADER_ACCESS_CONTROL_REQUEST_METHOD)
+        })?;
+
+        // Sanity check: Everything asked for must be allowed
+        if !self.allows_origin(origin) {
+            return Err(format!("Origin {} is not allowed", origin));
+        }
+
+        if !self.methods.iter().any(|m| m.eq_ignore_ascii_case(method.trim())) {
+            return Err(format!("Method {} is not allowed", method.trim()));
+        }
+
+        for header in request_headers
+            .unwrap_or("")
+            .split(',')
+            .map(|h| h.trim())
+            .filter(|h| !h.is_empty())
+        {
+            if !self.headers.iter().any(|h| h.eq_ignore_ascii_case(header)) {
+                return Err(format!("Header {} is not allowed", header));
+            }
+        }
+
+        let mut headers = self.headers(Some(origin));
+        headers.push((HEADER_ACCESS_CONTROL_ALLOW_METHODS, self.methods.join(", ")));
+        if !self.headers.is_empty() {
+            headers.push((HEADER_ACCESS_CONTROL_ALLOW_HEADERS, self.headers.join(", ")));
+        }
+        headers.push((HEADER_ACCESS_CONTROL_MAX_AGE, self.max_age.to_string()));
+
+        Ok(headers)
+    }
+
+    pub fn allows_origin(&self, origin: &str) -> bool {
+        // Origins are scheme://host[:port] and compared without regard to case
+        let origin = origin.trim().trim_right_matches('/');
+        self.origins
+            .iter()
+            .any(|o| o == ANY_ORIGIN || o.eq_ignore_ascii_case(origin))
+    }
+}
+
+impl Default for CorsPolicy {
+    fn default() -> Self {
+        let policy = Self {
+            allow_credentials: get_cors_allow_credentials(),
+            headers: get_cors_headers(),
+            max_age: get_cors_max_age(),
+            methods: get_cors_methods(),
+            origins: get_cors_origins()
+                .into_iter()
+                .map(|o| o.trim_right_matches('/').to_owned())
+                .collect(),
+        };
+
+        // Sanity check: Credentials from any origin at all would let any site act as our users
+        if policy.allow_credentials && policy.origins.iter().any(|o| o == ANY_ORIGIN) {
+            panic!(PANIC_UNACCEPTABLE_CORS_ORIGINS);
+        }
+
+        policy
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(origins: &[&str], allow_credentials: bool) -> CorsPolicy {
+        CorsPolicy {
+            allow_credentials: allow_credentials,
+            headers: vec!["Content-Type".to_owned()],
+            max_age: 600,
+            methods: vec!["GET".to_owned(), "POST".to_owned()],
+            origins: origins.iter().map(|o| o.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn any_origin_is_a_wildcard() {
+        let policy = policy(&[ANY_ORIGIN], false);
+        assert_eq!(
+            policy.headers(Some("https://example.com")),
+            vec![(HEADER_ACCESS_CONTROL_ALLOW_ORIGIN, ANY_ORIGIN.to_owned())]
+        );
+    }
+
+    #[test]
+    fn listed_origin_is_echoed() {
+        let policy = policy(&["https://example.com"], true);
+        assert!(policy.allows_origin("HTTPS://EXAMPLE.COM/"));
+        assert!(!policy.allows_origin("https://example.org"));
+        assert!(policy.headers(Some("https://example.org")).is_empty());
+        assert_eq!(
+            policy.headers(Some("https://example.com")),
+            vec![
+                (
+                    HEADER_ACCESS_CONTROL_ALLOW_ORIGIN,
+                    "https://example.com".to_owned(),
+                ),
+                (HEADER_VARY, HEADER_ORIGIN.to_owned()),
+                (HEADER_ACCESS_CONTROL_ALLOW_CREDENTIALS, "true".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn preflight_must_ask_for_what_is_allowed() {
+        let policy = policy(&["https://example.com"], false);
+        let origin = Some("https://example.com");
+        assert!(policy.preflight_headers(origin, Some("POST"), Some("content-type")).is_ok());
+        assert!(policy.preflight_headers(origin, Some("DELETE"), None).is_err());
+        assert!(policy.preflight_headers(origin, Some("POST"), Some("X-Other")).is_err());
+        assert!(policy.preflight_headers(None, Some("POST"), None).is_err());
+    }
+}
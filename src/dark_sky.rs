@@ -1,30 +1,55 @@
+use std::time::Duration;
+
 use chrono::{DateTime, TimeZone};
 
-use json;
+use futures::{future, Future};
 
-use reqwest::get;
+use json::JsonValue;
 
+use cache::StaleCache;
 use cli::get_dark_sky_api_key;
+use upstream::{accept_any, try_get_json, Pending};
+
+// Forecasts are only worth reusing for a few minutes
+const FORECAST_FRESH_SECS: u64 = 10 * 60;
+
+const LRU_CACHE_SIZE: usize = 4_096;
+
+const UPSTREAM_DARK_SKY: &'static str = "dark_sky";
 
-pub struct DarkSkyApi {
-    //cache: RefCell<LruCache<.., ..>>,
-    // Caching (locally or via some redis like instance
-    // would be a big win here - but we'd need to keep
-    // in mind how long each forecast might be trusted
-    // for and other issues which make this feature fun
-    // but extra). See GoogleApi type for details.
+// Chance of precipitation at which we say it will rain
+const RAIN_LIKELY_PROBABILITY: f32 = 0.5;
+
+// Also a stand-in for redis; a forecast which is a little out of date beats no answer at all
+// when Dark Sky is down
+lazy_static! {
+    static ref CACHE: StaleCache<Forecast> = StaleCache::new(
+        "forecast",
+        LRU_CACHE_SIZE,
+        Duration::from_secs(FORECAST_FRESH_SECS),
+    );
 }
 
+pub struct DarkSkyApi {}
+
 impl DarkSkyApi {
     pub fn try_get_forecast<T: TimeZone>(
         &self,
         lat: f64,
         lng: f64,
         dt: Option<DateTime<T>>,
-    ) -> Option<Forecast> {
+    ) -> Pending<Forecast> {
+        let key = match dt {
+            None => format!("{},{}", lat, lng),
+            Some(ref dt) => format!("{},{},{}", lat, lng, dt.timestamp()),
+        };
+        if let Some(forecast) = CACHE.try_get_fresh(&key) {
+            return Box::new(future::ok(Some(forecast)));
+        }
+
         // Make a web request to Dark Sky asking for this data
         let api_key = get_dark_sky_api_key();
-        let response = get(&match dt {
+        let url = match dt {
             None => format!(
                 "https://api.darksky.net/forecast/{}/{},{}?exclude=alerts,flags",
                 api_key, lat, lng
@@ -36,30 +61,51 @@ impl DarkSkyApi {
                 lng,
                 dt.timestamp()
             ),
-        });
+        };
+        let response = try_get_json(UPSTREAM_DARK_SKY, url, accept_any);
 
-        // Sanity check: We have some text at least
-        let response = response.unwrap().text();
-        if response.is_err() {
-            return None;
-        }
+        Box::new(response.map(move |response| {
+            if let Some(forecast) = response.and_then(|r| Self::to_forecast(&r)) {
+                CACHE.insert(key, forecast.clone());
+                return Some(forecast);
+            }
 
-        // Parse the output json structure
-        let response = json::parse(&response.unwrap());
+            // Dark Sky couldn't be reached (or sent a forecast we can't read) so make do with an
+            // older forecast, saying how old
+            CACHE
+                .try_get_stale(&key)
+                .map(|(forecast, age)| Forecast {
+                    stale_age: Some(age),
+                    ..forecast
+                })
+        }))
+    }
 
-        // Sanity check: Good json
-        if response.is_err() {
-            return None;
-        }
+    /// Looks at the next hour and any active alerts for a place; this is what proactive
+    /// notifications are made from.
+    pub fn try_get_outlook(&self, lat: f64, lng: f64) -> Pending<Outlook> {
+        // Make a web request to Dark Sky asking for this data
+        let api_key = get_dark_sky_api_key();
+        let response = try_get_json(
+            UPSTREAM_DARK_SKY,
+            format!(
+                "https://api.darksky.net/forecast/{}/{},{}?exclude=currently,hourly,daily,flags",
+                api_key, lat, lng
+            ),
+            accept_any,
+        );
 
-        let response = response.unwrap();
+        Box::new(response.map(|response| response.and_then(|r| Self::to_outlook(&r))))
+    }
 
+    fn to_forecast(response: &JsonValue) -> Option<Forecast> {
         // Daily fields
         let mut daily = response["daily"]["summary"].as_str();
         let daily_humidity = response["daily"]["data"][0]["humidity"].as_number();
         let daily_uv_index = response["daily"]["data"][0]["uvIndex"].as_number();
         let daily_wind_speed = response["daily"]["data"][0]["windSpeed"].as_number();
         let daily_precip = response["daily"]["data"][0]["precipType"].as_str();
+        let daily_icon = response["daily"]["icon"].as_str();
 
         // Hourly fields
         let hourly = response["hourly"]["summary"].as_str();
@@ -67,10 +113,12 @@ impl DarkSkyApi {
         let hourly_uv_index = response["hourly"]["data"][0]["uvIndex"].as_number();
         let hourly_wind_speed = response["hourly"]["data"][0]["windSpeed"].as_number();
         let hourly_precip = response["hourly"]["data"][0]["precipType"].as_str();
+        let hourly_icon = response["hourly"]["icon"].as_str();
 
         // Minutely fields
         let mut minutely = response["minutely"]["summary"].as_str();
         let mut minutely_precip = response["minutely"]["data"][0]["precipType"].as_str();
+        let mut minutely_icon = response["minutely"]["icon"].as_str();
 
         // Currently fields
         let currently = response["currently"]["summary"].as_str();
@@ -78,6 +126,7 @@ impl DarkSkyApi {
         let currently_uv_index: Option<_> = response["currently"]["uvIndex"].as_number();
         let currently_wind_speed: Option<_> = response["currently"]["windSpeed"].as_number();
         let currently_precip = response["currently"]["precipType"].as_str();
+        let currently_icon = response["currently"]["icon"].as_str();
 
         // Fix up fields we may not have
 
@@ -93,6 +142,10 @@ impl DarkSkyApi {
             minutely_precip = currently_precip;
         }
 
+        if let None = minutely_icon {
+            minutely_icon = currently_icon;
+        }
+
         // Sanity check: fields should be somewhat reasonable - but most should be able to be blank by default
         if daily.is_none() || daily_humidity.is_none() || hourly.is_none()
             || hourly_humidity.is_none() || minutely.is_none() || currently.is_none()
@@ -110,6 +163,7 @@ impl DarkSkyApi {
         Some(Forecast {
             daily: Prediction {
                 humidity: daily_humidity.unwrap().into(),
+                icon: daily_icon.map(|i| i.to_owned()),
                 is_haily: daily_precip == "hail",
                 is_rainy: daily_precip == "rain",
                 is_snowy: daily_precip == "snow",
@@ -119,6 +173,7 @@ impl DarkSkyApi {
             },
             hourly: Prediction {
                 humidity: hourly_humidity.unwrap().into(),
+                icon: hourly_icon.map(|i| i.to_owned()),
                 is_haily: hourly_precip == "hail",
                 is_rainy: hourly_precip == "rain",
                 is_snowy: hourly_precip == "snow",
@@ -128,6 +183,7 @@ impl DarkSkyApi {
             },
             minutely: Prediction {
                 humidity: currently_humidity.unwrap().into(),
+                icon: minutely_icon.map(|i| i.to_owned()),
                 is_haily: minutely_precip == "hail",
                 is_rainy: minutely_precip == "rain",
                 is_snowy: minutely_precip == "snow",
@@ -137,6 +193,7 @@ impl DarkSkyApi {
             },
             currently: Prediction {
                 humidity: currently_humidity.unwrap().into(),
+                icon: currently_icon.map(|i| i.to_owned()),
                 is_haily: currently_precip == "hail",
                 is_rainy: currently_precip == "rain",
                 is_snowy: currently_precip == "snow",
@@ -144,6 +201,43 @@ impl DarkSkyApi {
                 summary: currently.unwrap().to_owned(),
                 wind_speed: currently_wind_speed.unwrap_or(0.into()).into(),
             },
+            stale_age: None,
+        })
+    }
+
+    fn to_outlook(response: &JsonValue) -> Option<Outlook> {
+        // Find the first minute of the next hour in which rain is likely; it only counts as
+        // starting if it isn't already raining
+        let is_rain_likely = |minute: &JsonValue| {
+            minute["precipType"].as_str() == Some("rain")
+                && minute["precipProbability"].as_f32().unwrap_or(0.) >= RAIN_LIKELY_PROBABILITY
+        };
+        let minutes = &response["minutely"]["data"];
+        let rain_starts_in = if minutes.len() > 0 && !is_rain_likely(&minutes[0]) {
+            minutes
+                .members()
+                .position(|m| is_rain_likely(m))
+                .map(|m| m as u32)
+        } else {
+            None
+        };
+
+        // Alerts are optional, but any we find must have at least a title
+        let alerts = response["alerts"]
+            .members()
+            .filter_map(|a| {
+                a["title"].as_str().map(|title| Alert {
+                    expires: a["expires"].as_i64(),
+                    severity: a["severity"].as_str().unwrap_or("advisory").to_owned(),
+                    title: title.to_owned(),
+                    uri: a["uri"].as_str().map(|u| u.to_owned()),
+                })
+            })
+            .collect();
+
+        Some(Outlook {
+            alerts: alerts,
+            rain_starts_in: rain_starts_in,
         })
     }
 }
@@ -154,15 +248,20 @@ impl Default for DarkSkyApi {
     }
 }
 
+#[derive(Clone)]
 pub struct Forecast {
     pub daily: Prediction,
     pub hourly: Prediction,
     pub minutely: Prediction,
     pub currently: Prediction,
+    /// How old the forecast is, when it is out of date because Dark Sky couldn't be reached
+    pub stale_age: Option<Duration>,
 }
 
+#[derive(Clone)]
 pub struct Prediction {
     pub humidity: f32,
+    pub icon: Option<String>,
     pub is_haily: bool,
     pub is_rainy: bool,
     pub is_snowy: bool,
@@ -170,3 +269,16 @@ pub struct Prediction {
     pub summary: String,
     pub wind_speed: f32,
 }
+
+pub struct Alert {
+    pub expires: Option<i64>,
+    pub severity: String,
+    pub title: String,
+    pub uri: Option<String>,
+}
+
+pub struct Outlook {
+    pub alerts: Vec<Alert>,
+    /// Minutes from now until rain starts, if it will within the hour
+    pub rain_starts_in: Option<u32>,
+}
@@ -0,0 +1,108 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+use std::time::Duration;
+
+// How long a test waits for a request before deciding it isn't coming
+const REQUEST_TIMEOUT_SECS: u64 = 10;
+
+/// A request the fake server was sent. Header names are lower cased.
+pub struct FakeRequest {
+    pub body: String,
+    pub headers: Vec<(String, String)>,
+    pub method: String,
+    pub path: String,
+}
+
+impl FakeRequest {
+    pub fn header(&self, key: &str) -> Option<&str> {
+        let key = key.to_lowercase();
+        self.headers
+            .iter()
+            .find(|&&(ref k, _)| k == &key)
+            .map(|&(_, ref v)| v.as_str())
+    }
+}
+
+/// Stands in for the servers we post to (webhooks, Telegram, Matrix, Slack) so tests can see
+/// what we sent. Every request is answered with the same status and json body.
+pub struct FakeServer {
+    requests: Receiver<FakeRequest>,
+    pub url: String,
+}
+
+impl FakeServer {
+    pub fn start(status: &'static str, response: &'static str) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (sender, requests) = channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut reader = BufReader::new(stream.unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut request_line = request_line.split_whitespace();
+                let method = request_line.next().unwrap_or("").to_owned();
+                let path = request_line.next().unwrap_or("").to_owned();
+
+                let mut headers = vec![];
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+
+                    let mut parts = line.splitn(2, ':');
+                    if let (Some(key), Some(val)) = (parts.next(), parts.next()) {
+                        headers.push((key.trim().to_lowercase(), val.trim().to_owned()));
+                    }
+                }
+
+                let len = headers
+                    .iter()
+                    .find(|&&(ref key, _)| key == "content-length")
+                    .map(|&(_, ref val)| val.parse().unwrap())
+                    .unwrap_or(0);
+                let mut body = vec![0; len];
+                reader.read_exact(&mut body).unwrap();
+
+                write!(
+                    reader.get_mut(),
+                    "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+                     Connection: close\r\n\r\n{}",
+                    status,
+                    response.len(),
+                    response
+                ).unwrap();
+                sender
+                    .send(FakeRequest {
+                        body: String::from_utf8(body).unwrap(),
+                        headers: headers,
+                        method: method,
+                        path: path,
+                    })
+                    .ok();
+            }
+        });
+
+        Self {
+            requests: requests,
+            url: url,
+        }
+    }
+
+    /// Whether nothing more arrives for a little while.
+    pub fn is_quiet(&self) -> bool {
+        self.requests
+            .recv_timeout(Duration::from_millis(500))
+            .is_err()
+    }
+
+    pub fn next_request(&self) -> FakeRequest {
+        self.requests
+            .recv_timeout(Duration::from_secs(REQUEST_TIMEOUT_SECS))
+            .expect("No request arrived")
+    }
+}
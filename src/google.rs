@@ -1,86 +1,156 @@
-use std::cell::RefCell;
+use std::time::Duration;
 
-use json;
+use futures::{future, Future};
 
-use lru_cache::LruCache;
-
-use reqwest::get;
+use json::JsonValue;
 
 use urlencoding::encode;
 
+use cache::StaleCache;
 use cli::get_google_api_key;
+use upstream::{try_get_json, Failure, Pending};
+
+// Places don't move, so they stay fresh for a month
+const GEOCODE_FRESH_SECS: u64 = 30 * 24 * 60 * 60;
 
 const LRU_CACHE_SIZE: usize = 16_384;
 
-pub struct GoogleApi {
-    // Basic caching mechanism to stand-in for redis
-    cache: RefCell<LruCache<String, (f64, f64)>>,
+const UPSTREAM_GOOGLE: &'static str = "google";
+
+// Basic caching mechanism to stand-in for redis
+lazy_static! {
+    static ref CACHE: StaleCache<Place> = StaleCache::new(
+        "geocode",
+        LRU_CACHE_SIZE,
+        Duration::from_secs(GEOCODE_FRESH_SECS),
+    );
 }
 
+pub struct GoogleApi {}
+
 impl GoogleApi {
-    pub fn try_get_lat_lng(&self, query: &str) -> Option<(f64, f64)> {
+    pub fn try_get_place(&self, query: &str) -> Pending<Place> {
         // See if we can short-cut all of this using our cache
         // This would be better if it were an external redis..
-        {
-            let mut cache = self.cache.borrow_mut();
-            if let Some(&mut (lat, lng)) = cache.get_mut(query) {
-                return Some((lat, lng));
-            }
+        if let Some(place) = CACHE.try_get_fresh(query) {
+            return Box::new(future::ok(Some(place)));
         }
 
         // Make a web request to Google asking for this data
         let api_key = get_google_api_key();
-        let response = get(&format!(
-            "https://maps.googleapis.com/maps/api/place/textsearch/json?query={}&key={}",
-            encode(query),
-            api_key
-        ));
-        if response.is_err() {
-            return None;
-        }
-
-        // Sanity check: We have some text at least
-        let response = response.unwrap().text();
-        if response.is_err() {
-            return None;
-        }
-
-        // Parse the output json structure
-        let response = json::parse(&response.unwrap());
+        let response = try_get_json(
+            UPSTREAM_GOOGLE,
+            format!(
+                "https://maps.googleapis.com/maps/api/place/textsearch/json?query={}&key={}",
+                encode(query),
+                api_key
+            ),
+            check_status,
+        );
+
+        let query = query.to_owned();
+        Box::new(response.map(move |response| {
+            // Sanity check: We have some json at least; if Google couldn't be reached we make do
+            // with what it told us before
+            let response = match response {
+                Some(response) => response,
+                None => return CACHE.try_get_stale(&query).map(|(place, _)| place),
+            };
+
+            let place = Place::try_from_json(&response["results"][0])?;
+
+            // Stick the results back into our 'redis'
+            CACHE.insert(query, place.clone());
+
+            Some(place)
+        }))
+    }
+}
 
-        // Sanity check: Good json
-        if response.is_err() {
-            return None;
-        }
+/// Where Google found a place, and what it calls it.
+#[derive(Clone)]
+pub struct Place {
+    pub lat: f64,
+    pub lng: f64,
+    pub name: String,
+}
 
+impl Place {
+    fn try_from_json(result: &JsonValue) -> Option<Self> {
         // Grab the fields we want
-        let response = response.unwrap();
-        let location = &response["results"][0]["geometry"]["location"];
-        let lat = &location["lat"];
-        let lng = &location["lng"];
-
-        // Sanity check: lat & lng must look like numbers at least
-        if lat.is_null() || lng.is_null() || !lat.is_number() || !lng.is_number() {
-            return None;
-        }
-
-        let lat = lat.as_f64().unwrap();
-        let lng = lng.as_f64().unwrap();
+        let location = &result["geometry"]["location"];
+        let lat = location["lat"].as_f64();
+        let lng = location["lng"].as_f64();
+
+        // The address says which Paris we found; the name will do if there is no address
+        let name = result["formatted_address"]
+            .as_str()
+            .or_else(|| result["name"].as_str());
+
+        // Sanity check: lat & lng must look like numbers at least, and the place needs a name
+        Some(Self {
+            lat: lat?,
+            lng: lng?,
+            name: name?.to_owned(),
+        })
+    }
+}
 
-        // Stick the results back into our 'redis'
-        {
-            let mut cache = self.cache.borrow_mut();
-            cache.insert(query.to_owned(), (lat, lng));
+/// Google answers 200 even when it refuses us, so the status it puts in the json is what tells.
+/// Hitting a per-second limit or catching Google at a bad moment may pass; a denied key or a bad
+/// request won't.
+fn check_status(response: &JsonValue) -> Result<(), Failure> {
+    match response["status"].as_str() {
+        Some("OK") | Some("ZERO_RESULTS") => Ok(()),
+        Some(status @ "OVER_QUERY_LIMIT") | Some(status @ "UNKNOWN_ERROR") => {
+            Err(Failure::transient(format!("Responded {}", status)))
         }
-
-        Some((lat, lng))
+        Some(status) => Err(Failure::permanent(format!("Responded {}", status))),
+        None => Err(Failure::permanent("Responded without a status")),
     }
 }
 
 impl Default for GoogleApi {
     fn default() -> Self {
-        Self {
-            cache: RefCell::new(LruCache::new(LRU_CACHE_SIZE)),
-        }
+        Self {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn places_are_named_as_google_names_them() {
+        let mut result = object!{
+            "formatted_address" => "Paris, France",
+            "geometry" => object!{
+                "location" => object!{ "lat" => 48.856614, "lng" => 2.3522219 },
+            },
+            "name" => "Paris",
+        };
+        let place = Place::try_from_json(&result).unwrap();
+        assert_eq!(place.name, "Paris, France");
+        assert!((place.lat - 48.856614).abs() < 1e-9 && (place.lng - 2.3522219).abs() < 1e-9);
+
+        result.remove("formatted_address");
+        assert_eq!(Place::try_from_json(&result).unwrap().name, "Paris");
+
+        result.remove("name");
+        assert!(Place::try_from_json(&result).is_none());
+        assert!(Place::try_from_json(&JsonValue::Null).is_none());
+    }
+
+    #[test]
+    fn only_answers_are_good() {
+        assert!(check_status(&object!{ "status" => "OK" }).is_ok());
+        assert!(check_status(&object!{ "status" => "ZERO_RESULTS" }).is_ok());
+
+        let refused = |status: &str| check_status(&object!{ "status" => status }).err().unwrap();
+        assert!(refused("OVER_QUERY_LIMIT").is_transient);
+        assert!(refused("UNKNOWN_ERROR").is_transient);
+        assert!(!refused("REQUEST_DENIED").is_transient);
+        assert!(!refused("INVALID_REQUEST").is_transient);
+        assert!(check_status(&object!{}).is_err());
     }
 }
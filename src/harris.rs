@@ -1,351 +1,275 @@
-use chrono::{DateTime, FixedOffset};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use futures::Future;
 
 use snips_nlu_lib::{FileBasedConfiguration, SnipsNluEngine};
-use snips_nlu_ontology::{Grain, Slot, SlotValue};
 
-use cli::get_training_file;
+use cli::{get_intent_confirm_threshold, get_intent_threshold, get_seed, get_training_file};
 use dark_sky::DarkSkyApi;
 use google::GoogleApi;
+use intent::{Context, IntentSummary, Registry, Reply, ReplyFuture};
+use local_time::{LocalTimeHandler, TimeDifferenceHandler, TimeZoneHandler};
+use logging::{record_intent, record_text};
+use metrics::observe_intent;
+use phrases::{self, Phrasebook};
+use session::{PendingIntent, SessionStore};
+use small_talk::{HelpHandler, SmallTalkHandler};
+use subscription::{SubscribeHandler, SubscriptionStore};
+use weather::WeatherHandler;
 
 // Panics
 const PANIC_UNACCEPTABLE_NLU_CONFIGURATION: &'static str = "Unacceptable nlu configuration";
 const PANIC_UNACCEPTABLE_TRAINING_FILE: &'static str = "Unacceptable training file";
 
+// Intents
+const INTENT_GOODBYE: &'static str = "goodbye";
+const INTENT_GREETING: &'static str = "greeting";
+const INTENT_HELP: &'static str = "help";
+const INTENT_SEARCH_LOCAL_TIME: &'static str = "searchLocalTime";
+const INTENT_SEARCH_TIME_DIFFERENCE: &'static str = "searchTimeDifference";
+const INTENT_SEARCH_TIME_ZONE: &'static str = "searchTimeZone";
+const INTENT_SEARCH_WEATHER_FORECAST: &'static str = "searchWeatherForecast";
+const INTENT_SEARCH_WEATHER_FORECAST_CONDITION: &'static str = "searchWeatherForecastCondition";
+const INTENT_SUBSCRIBE_WEATHER_FORECAST: &'static str = "subscribeWeatherForecast";
+const INTENT_THANKS: &'static str = "thanks";
+
+// What a user may reply when asked to confirm what they meant; the fillers go with either
+const ANSWER_FILLERS: &'static [&'static str] = &["please", "thank", "thanks", "you"];
+const ANSWER_NO: &'static [&'static str] = &["no", "nah", "nope", "wrong"];
+const ANSWER_YES: &'static [&'static str] = &[
+    "correct", "ok", "okay", "right", "sure", "yeah", "yep", "yes", "yup",
+];
+
 pub enum Event {
     Join(Join),
     Message(Message),
 }
 
+impl Event {
+    pub fn user_id(&self) -> u64 {
+        match self {
+            &Event::Join(ref j) => j.user_id,
+            &Event::Message(ref m) => m.user_id,
+        }
+    }
+}
+
 pub struct Harris {
-    dark_sky_api: DarkSkyApi,
-    google_api: GoogleApi,
+    dark_sky_api: Rc<DarkSkyApi>,
+    google_api: Rc<GoogleApi>,
+    intent_confirm_threshold: f32,
+    intent_threshold: f32,
     nlu_engine: SnipsNluEngine,
+    phrasebook: Rc<Phrasebook>,
+    registry: Registry,
+    sessions: SessionStore,
 }
 
 impl Harris {
-    pub fn respond(&self, e: &Event) -> String {
+    pub fn new(sessions: SessionStore, subscriptions: SubscriptionStore) -> Self {
+        let config = FileBasedConfiguration::from_path(get_training_file(), false)
+            .expect(PANIC_UNACCEPTABLE_TRAINING_FILE);
+        let nlu_engine = SnipsNluEngine::new(config).expect(PANIC_UNACCEPTABLE_NLU_CONFIGURATION);
+
+        // These are the skills Harris has; each must also be an intent in the training file
+        let mut registry = Registry::default();
+        registry.register(INTENT_GOODBYE, SmallTalkHandler::new(&phrases::GOODBYE));
+        registry.register(INTENT_GREETING, SmallTalkHandler::new(&phrases::HELLO));
+        registry.register(INTENT_HELP, HelpHandler);
+        registry.register(INTENT_SEARCH_LOCAL_TIME, LocalTimeHandler);
+        registry.register(INTENT_SEARCH_TIME_DIFFERENCE, TimeDifferenceHandler);
+        registry.register(INTENT_SEARCH_TIME_ZONE, TimeZoneHandler);
+        registry.register(INTENT_SEARCH_WEATHER_FORECAST, WeatherHandler);
+        registry.register(INTENT_SEARCH_WEATHER_FORECAST_CONDITION, WeatherHandler);
+        registry.register(
+            INTENT_SUBSCRIBE_WEATHER_FORECAST,
+            SubscribeHandler::new(subscriptions),
+        );
+        registry.register(INTENT_THANKS, SmallTalkHandler::new(&phrases::THANKS));
+
+        Self {
+            dark_sky_api: Default::default(),
+            google_api: Default::default(),
+            intent_confirm_threshold: get_intent_confirm_threshold(),
+            intent_threshold: get_intent_threshold(),
+            nlu_engine: nlu_engine,
+            phrasebook: Rc::new(Phrasebook::new(get_seed())),
+            registry: registry,
+            sessions: sessions,
+        }
+    }
+
+    /// Whether the NLU model understands anything at all, for readiness checks.
+    pub fn try_check_nlu(&self) -> Result<(), String> {
+        self.nlu_engine
+            .parse("hello", None)
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    pub fn respond(&self, e: &Event) -> ReplyFuture {
         match e {
             &Event::Join(ref j) => {
-                // Note: Joining doesn't currently save user_id/name combos so the problem of when to flush that cache isn't created.
-                // If you were saving such details you would want to put them into this type in a private dictionary. You might also
-                // store the other fun details in that dictionary value such as previous conversation bits, etc. Joining responds the
-                // same way every time. This could be extended to either a larger list of interesting 'leads', etc.
-                // https://goo.gl/rFY8XX
-                format!("Hello, {}, this is Harris. I'm in right now, so you can talk to me personally.", j.name)
+                // Remember who this is so later replies can be varied per user
+                self.sessions.with_session(j.user_id, |s| {
+                    s.name = Some(j.name.to_owned());
+                    Reply::text(self.phrasebook.say(
+                        &phrases::GREETING,
+                        s,
+                        &[("name", j.name.as_str())],
+                    )).ready()
+                })
             }
             &Event::Message(ref m) => {
-                let nlu = self.nlu_engine.parse(&m.text, None).expect("nlu failure");
-                match &nlu.intent {
-                    &Some(ref i)
-                        if i.probability > 0.5 && nlu.slots.is_some()
-                            && (&i.intent_name == "searchWeatherForecast"
-                                || &i.intent_name == "searchWeatherForecastCondition") =>
-                    {
-                        self.respond_weather(&nlu.slots.unwrap())
-                    }
-                    _ => Self::respond_unsure(),
-                }
+                // Work on a copy of the session so that other requests aren't held up while
+                // the handler waits on upstream services; only what changed is saved, so the
+                // changes those requests made are kept
+                let loaded = self.sessions.load(m.user_id);
+                let ctx = Context {
+                    dark_sky_api: self.dark_sky_api.clone(),
+                    google_api: self.google_api.clone(),
+                    phrasebook: self.phrasebook.clone(),
+                    session: Rc::new(RefCell::new(loaded.clone())),
+                    user_id: m.user_id,
+                };
+                let reply = self.respond_message(&ctx, &m.text);
+                let sessions = self.sessions.clone();
+                Box::new(reply.map(move |reply| {
+                    sessions.save_changes(ctx.user_id, &loaded, ctx.session.borrow().clone());
+                    reply
+                }))
             }
         }
     }
 
-    fn respond_down() -> String {
-        "Something went terribly wrong deep inside my logic. Put me on the floor and step back."
-            .to_owned()
+    /// Answers on a thread of our own (never an event loop), blocking while upstream services
+    /// are waited on.
+    pub fn respond_blocking(&self, e: &Event) -> Reply {
+        // This will always succeed because replies don't fail
+        self.respond(e).wait().unwrap()
     }
 
-    fn respond_unsure() -> String {
-        // TODO: Add some variety or memory of past utterances
-        "Hmm. That is fascinating. Ask me about the weather where you live.".to_owned()
-    }
+    fn respond_message(&self, ctx: &Context, text: &str) -> ReplyFuture {
+        record_text(text);
 
-    fn respond_weather(&self, slots: &[Slot]) -> String {
-        // Pick out the values from slots; could be simpler but this form allows for the use of all
-        // value formats offered by the library and a way to handle each type according to our needs
-        let mut forecast_condition_name = None;
-        let mut forecast_country = None;
-        let mut forecast_geographical_poi = None;
-        let mut forecast_locality = None;
-        let mut forecast_region = None;
-        let mut forecast_start_datetime = None;
-        for slot in slots {
-            match slot {
-                s if &s.slot_name == "forecast_condition_name" => {
-                    if let &SlotValue::Custom(ref v) = &s.value {
-                        forecast_condition_name = Some(v.value.to_owned());
-                    }
-                }
-                s if &s.slot_name == "forecast_country" => {
-                    if let &SlotValue::Custom(ref v) = &s.value {
-                        forecast_country = Some(v.value.to_owned());
-                    }
-                }
-                s if &s.slot_name == "forecast_geographical_poi" => {
-                    if let &SlotValue::Custom(ref v) = &s.value {
-                        forecast_geographical_poi = Some(v.value.to_owned());
-                    }
-                }
-                s if &s.slot_name == "forecast_locality" => {
-                    if let &SlotValue::Custom(ref v) = &s.value {
-                        forecast_locality = Some(v.value.to_owned());
-                    }
-                }
-                s if &s.slot_name == "forecast_region" => {
-                    if let &SlotValue::Custom(ref v) = &s.value {
-                        forecast_region = Some(v.value.to_owned());
-                    }
-                }
-                s if &s.slot_name == "forecast_start_datetime" => {
-                    if let &SlotValue::InstantTime(ref v) = &s.value {
-                        if let Ok(d) = DateTime::<FixedOffset>::parse_from_str(
-                            &v.value,
-                            "%Y-%m-%d %H:%M:%S %:z",
-                        ) {
-                            forecast_start_datetime = Some((d, v.grain));
-                        }
-                    }
-                }
-                _ => (),
+        // Only the very next message may answer what we asked to confirm; anything but a yes or
+        // a no is understood afresh
+        let pending = ctx.session.borrow_mut().pending.take();
+        if let Some(pending) = pending {
+            match try_parse_answer(text) {
+                Some(true) => return self.respond_confirmed(ctx, &pending),
+                Some(false) => return Reply::text(ctx.say(&phrases::DECLINED, &[])).ready(),
+                None => (),
             }
         }
 
-        // We have four location values so we preference locality, poi, region, then country
-        if let None = forecast_locality {
-            forecast_locality = if let Some(v) = forecast_geographical_poi {
-                Some(v)
-            } else if let Some(v) = forecast_region {
-                Some(v)
-            } else if let Some(v) = forecast_country {
-                Some(v)
-            } else {
-                None
+        let mut nlu = self.nlu_engine.parse(text, None).expect("nlu failure");
+        let mut tried = vec![];
+        loop {
+            let (intent_name, probability) = match nlu.intent {
+                Some(ref i) => (i.intent_name.to_owned(), i.probability),
+                None => return Self::respond_unsure(ctx).ready(),
             };
-        }
 
-        // Sanity check: We should have found a location
-        // A real service might guess using a commercial product such as https://www.maxmind.com
-        if let None = forecast_locality {
-            return Self::respond_unsure();
-        }
+            // Sanity check: We must have a handler for this intent
+            let handler = match self.registry.get(&intent_name) {
+                Some(h) => h,
+                None => return Self::respond_unsure(ctx).ready(),
+            };
 
-        let forecast_locality = forecast_locality.unwrap();
+            // If the handler can't use these slots we rank down to the next most likely intent
+            // the nlu engine can find for this utterance
+            let slots = nlu.slots.take().unwrap_or_else(Vec::new);
+            if handler.accepts(&slots) {
+                let reply = if probability > self.intent_threshold {
+                    handler.respond(ctx, &slots)
+                } else if probability > self.intent_confirm_threshold {
+                    match handler.confirm(ctx, &slots) {
+                        Some(reply) => {
+                            ctx.session.borrow_mut().pending = Some(PendingIntent {
+                                intent_name: intent_name.clone(),
+                                probability: probability,
+                                slots: slots.clone(),
+                            });
+                            reply.ready()
+                        }
+                        None => Self::respond_unsure(ctx).ready(),
+                    }
+                } else {
+                    Self::respond_unsure(ctx).ready()
+                };
+                let intent = IntentSummary::new(&intent_name, probability, &slots);
+                observe_intent(&intent_name, probability);
+                record_intent(&intent);
 
-        // See if we can further answer their specific question (these items must be in the training set)
-        enum SpecificForecast {
-            Snow,
-            Wind,
-            Hail,
-            Humidity,
-            Precipitation,
-            Uv,
-        };
-        let desired_forecast = match &forecast_condition_name {
-            &Some(ref v)
-                if v == "blizzard" || v == "snow" || v == "snowfall" || v == "snowing"
-                    || v == "snowstorm" || v == "snowy" =>
-            {
-                Some(SpecificForecast::Snow)
-            }
-            &Some(ref v) if v == "wind" || v == "windy" => Some(SpecificForecast::Wind),
-            &Some(ref v) if v == "hail" || v == "hailing" => Some(SpecificForecast::Hail),
-            &Some(ref v) if v == "humid" => Some(SpecificForecast::Humidity),
-            &Some(ref v)
-                if v == "storm" || v == "stormy" || v == "rain" || v == "rainfall"
-                    || v == "rainy" =>
-            {
-                Some(SpecificForecast::Precipitation)
-            }
-            &Some(ref v)
-                if v == "cloud" || v == "cloudi" || v == "overcast" || v == "depress" || v == "fog"
-                    || v == "foggy" || v == "sun" || v == "sunni" || v == "hot"
-                    || v == "be sunni" =>
-            {
-                Some(SpecificForecast::Uv)
+                return Box::new(reply.map(|mut reply| {
+                    reply.intent = Some(intent);
+                    reply
+                }));
             }
-            _ => None,
-        };
 
-        // At this point we know they're asking about weather. We also have:
-        // forecast_locality: String
-        // forecast_start_datetime: Option<(DateTime<FixedOffset>, Grain)>
-        // desired_forecast: Option<DesiredForecast>
+            tried.push(intent_name);
+            let remaining: Vec<String> = self.registry
+                .intent_names()
+                .into_iter()
+                .filter(|i| !tried.contains(i))
+                .collect();
 
-        // Step 1: Process locality string into lat/lng
-        let lat_lng = self.google_api.try_get_lat_lng(&forecast_locality);
+            // Sanity check: We may have run out of alternatives
+            if remaining.is_empty() {
+                return Self::respond_unsure(ctx).ready();
+            }
 
-        // Sanity check: We may have been unable to do that
-        if let None = lat_lng {
-            return Self::respond_down();
+            nlu = self.nlu_engine
+                .parse(text, Some(remaining.as_slice()))
+                .expect("nlu failure");
         }
+    }
 
-        // Step 2: Go check the weather
-        let (lat, lng) = lat_lng.unwrap();
-        let mut dt = None;
-        let mut grain = None;
-        if let Some((d, g)) = forecast_start_datetime {
-            dt = Some(d.clone());
-            grain = Some(g);
-        }
-        let forecast = self.dark_sky_api.try_get_forecast(lat, lng, dt);
+    /// Does what the user confirmed they meant, with the slots they said it with.
+    fn respond_confirmed(&self, ctx: &Context, pending: &PendingIntent) -> ReplyFuture {
+        let handler = match self.registry.get(&pending.intent_name) {
+            Some(h) => h,
+            None => return Self::respond_unsure(ctx).ready(),
+        };
 
-        // Sanity check: We may have been unable to do that
-        if let None = forecast {
-            return Self::respond_down();
-        }
+        let intent = IntentSummary::new(&pending.intent_name, pending.probability, &pending.slots);
+        record_intent(&intent);
 
-        // Step 3: Pick the correct combination of desired forecast and granularity so we can respond
-        let forecast = forecast.unwrap();
-        match desired_forecast {
-            None => match grain {
-                None | Some(Grain::Second) => forecast.currently.summary.to_owned(),
-                Some(Grain::Minute) => forecast.minutely.summary.to_owned(),
-                Some(Grain::Hour) => forecast.hourly.summary.to_owned(),
-                Some(Grain::Year) | Some(Grain::Quarter) | Some(Grain::Month)
-                | Some(Grain::Week) | Some(Grain::Day) => forecast.daily.summary.to_owned(),
-            },
-            Some(SpecificForecast::Hail) => match grain {
-                None | Some(Grain::Second) => if forecast.currently.is_haily {
-                    "It is hailing."
-                } else {
-                    "It is not hailing."
-                }.to_owned(),
-                Some(Grain::Minute) => if forecast.minutely.is_haily {
-                    "It will hail."
-                } else {
-                    "It will not hail."
-                }.to_owned(),
-                Some(Grain::Hour) => if forecast.hourly.is_haily {
-                    "It should hail."
-                } else {
-                    "It should not hail."
-                }.to_owned(),
-                Some(Grain::Year) | Some(Grain::Quarter) | Some(Grain::Month)
-                | Some(Grain::Week) | Some(Grain::Day) => if forecast.daily.is_haily {
-                    "Hail is expected."
-                } else {
-                    "Hail is not expected."
-                }.to_owned(),
-            },
-            Some(SpecificForecast::Humidity) => match grain {
-                None | Some(Grain::Second) => format!(
-                    "The current humidity is {:.0}%.",
-                    100. * forecast.currently.humidity
-                ).to_owned(),
-                Some(Grain::Minute) => format!(
-                    "The humidity will be {:.0}%.",
-                    100. * forecast.minutely.humidity
-                ).to_owned(),
-                Some(Grain::Hour) => format!(
-                    "The humidity should be {:.0}%.",
-                    100. * forecast.hourly.humidity
-                ).to_owned(),
-                Some(Grain::Year) | Some(Grain::Quarter) | Some(Grain::Month)
-                | Some(Grain::Week) | Some(Grain::Day) => format!(
-                    "The estimated humidity is {:.0}%.",
-                    100. * forecast.daily.humidity
-                ).to_owned(),
-            },
-            Some(SpecificForecast::Precipitation) => match grain {
-                None | Some(Grain::Second) => if forecast.currently.is_rainy {
-                    "It is raining."
-                } else {
-                    "It is not raining."
-                }.to_owned(),
-                Some(Grain::Minute) => if forecast.minutely.is_rainy {
-                    "It will rain."
-                } else {
-                    "It will not rain."
-                }.to_owned(),
-                Some(Grain::Hour) => if forecast.hourly.is_rainy {
-                    "It should rain."
-                } else {
-                    "It should not rain."
-                }.to_owned(),
-                Some(Grain::Year) | Some(Grain::Quarter) | Some(Grain::Month)
-                | Some(Grain::Week) | Some(Grain::Day) => if forecast.daily.is_rainy {
-                    "Rain is expected."
-                } else {
-                    "Rain is not expected."
-                }.to_owned(),
-            },
-            Some(SpecificForecast::Snow) => match grain {
-                None | Some(Grain::Second) => if forecast.currently.is_snowy {
-                    "It is snowing."
-                } else {
-                    "It is not snowing."
-                }.to_owned(),
-                Some(Grain::Minute) => if forecast.minutely.is_snowy {
-                    "It will snow."
-                } else {
-                    "It will not snow."
-                }.to_owned(),
-                Some(Grain::Hour) => if forecast.hourly.is_snowy {
-                    "It should snow."
-                } else {
-                    "It should not snow."
-                }.to_owned(),
-                Some(Grain::Year) | Some(Grain::Quarter) | Some(Grain::Month)
-                | Some(Grain::Week) | Some(Grain::Day) => if forecast.daily.is_snowy {
-                    "Snow is expected."
-                } else {
-                    "Snow is not expected."
-                }.to_owned(),
-            },
-            Some(SpecificForecast::Uv) => match grain {
-                None | Some(Grain::Second) => {
-                    format!("The current UV index is {}.", forecast.currently.uv_index).to_owned()
-                }
-                Some(Grain::Minute) => {
-                    format!("The UV index will be {}.", forecast.minutely.uv_index).to_owned()
-                }
-                Some(Grain::Hour) => {
-                    format!("The UV index should be {}.", forecast.hourly.uv_index).to_owned()
-                }
-                Some(Grain::Year) | Some(Grain::Quarter) | Some(Grain::Month)
-                | Some(Grain::Week) | Some(Grain::Day) => {
-                    format!("The estimated UV index is {}.", forecast.daily.uv_index).to_owned()
-                }
-            },
-            Some(SpecificForecast::Wind) => match grain {
-                None | Some(Grain::Second) => format!(
-                    "The current wind speed is {:.1}mph.",
-                    forecast.currently.wind_speed
-                ).to_owned(),
-                Some(Grain::Minute) => format!(
-                    "The wind speed will be {:.1}mph.",
-                    forecast.minutely.wind_speed
-                ).to_owned(),
-                Some(Grain::Hour) => format!(
-                    "The wind speed should be {:.1}mph.",
-                    forecast.hourly.wind_speed
-                ).to_owned(),
-                Some(Grain::Year) | Some(Grain::Quarter) | Some(Grain::Month)
-                | Some(Grain::Week) | Some(Grain::Day) => format!(
-                    "The estimated wind speed is {:.1}mph.",
-                    forecast.daily.wind_speed
-                ).to_owned(),
-            },
-        }
+        Box::new(handler.respond(ctx, &pending.slots).map(|mut reply| {
+            reply.intent = Some(intent);
+            reply
+        }))
+    }
+
+    fn respond_unsure(ctx: &Context) -> Reply {
+        Reply::text(ctx.say(&phrases::UNSURE, &[]))
     }
 }
 
-impl Default for Harris {
-    fn default() -> Self {
-        let config = FileBasedConfiguration::from_path(get_training_file(), false)
-            .expect(PANIC_UNACCEPTABLE_TRAINING_FILE);
-        let nlu_engine = SnipsNluEngine::new(config).expect(PANIC_UNACCEPTABLE_NLU_CONFIGURATION);
+/// Whether the text says yes (`Some(true)`) or no (`Some(false)`) and nothing more.
+fn try_parse_answer(text: &str) -> Option<bool> {
+    let text = text.to_lowercase();
+    let words: Vec<&str> = text
+        .split(|c: char| !c.is_alphabetic())
+        .filter(|w| !w.is_empty() && !ANSWER_FILLERS.contains(w))
+        .collect();
 
-        Self {
-            dark_sky_api: Default::default(),
-            google_api: Default::default(),
-            nlu_engine: nlu_engine,
-        }
+    // Sanity check: "Thanks" alone says neither
+    if words.is_empty() {
+        None
+    } else if words.iter().all(|w| ANSWER_YES.contains(w)) {
+        Some(true)
+    } else if words.iter().all(|w| ANSWER_NO.contains(w)) {
+        Some(false)
+    } else {
+        None
     }
 }
 
 pub struct Join {
     name: String,
-    #[allow(dead_code)]
     user_id: u64,
 }
 
@@ -360,7 +284,6 @@ impl Join {
 
 pub struct Message {
     text: String,
-    #[allow(dead_code)]
     user_id: u64,
 }
 
@@ -372,3 +295,54 @@ impl Message {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use json;
+
+    use super::*;
+
+    #[test]
+    fn answers_are_only_yes_or_no() {
+        assert_eq!(try_parse_answer("Yes"), Some(true));
+        assert_eq!(try_parse_answer("yes please!"), Some(true));
+        assert_eq!(try_parse_answer("Yep, thank you"), Some(true));
+        assert_eq!(try_parse_answer("no"), Some(false));
+        assert_eq!(try_parse_answer("Nope. No thanks"), Some(false));
+        assert_eq!(try_parse_answer("thanks"), None);
+        assert_eq!(try_parse_answer("yes and no"), None);
+        assert_eq!(try_parse_answer("no, the weather in Paris"), None);
+        assert_eq!(try_parse_answer(""), None);
+    }
+
+    #[test]
+    fn model_and_dataset_agree() {
+        let dataset = json::parse(include_str!("../dataset.json")).unwrap();
+        let model = json::parse(include_str!("../trained-assistant.json")).unwrap();
+        let slots = &model["dataset_metadata"]["slot_name_mappings"];
+
+        // Every intent with a handler must be in both; a slot must mean the same entity in both
+        for intent in &[
+            INTENT_GOODBYE,
+            INTENT_GREETING,
+            INTENT_HELP,
+            INTENT_SEARCH_LOCAL_TIME,
+            INTENT_SEARCH_TIME_DIFFERENCE,
+            INTENT_SEARCH_TIME_ZONE,
+            INTENT_SEARCH_WEATHER_FORECAST,
+            INTENT_SEARCH_WEATHER_FORECAST_CONDITION,
+            INTENT_SUBSCRIBE_WEATHER_FORECAST,
+            INTENT_THANKS,
+        ] {
+            assert!(dataset["intents"].has_key(intent), "{} isn't in the dataset", intent);
+            assert!(slots.has_key(intent), "{} isn't in the model", intent);
+        }
+        for (intent, examples) in dataset["intents"].entries() {
+            for chunk in examples["utterances"].members().flat_map(|u| u["data"].members()) {
+                if let Some(slot) = chunk["slot_name"].as_str() {
+                    assert_eq!(slots[intent][slot], chunk["entity"], "{} in {}", slot, intent);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use std::fs::File;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use json::{self, JsonValue};
+
+use reqwest::Client;
+
+use cli::{get_probe_upstream, get_training_file};
+
+// Panics
+const PANIC_POISONED_HEALTH: &'static str = "Poisoned health";
+const PANIC_UNACCEPTABLE_TRAINING_FILE: &'static str = "Unacceptable training file";
+
+// Upstream probes are remembered this long so that frequent readiness checks don't hammer
+// Google and Dark Sky
+const PROBE_CACHE_SECS: u64 = 30;
+const PROBE_TIMEOUT_SECS: u64 = 5;
+
+// Any answer at all from these will do; they aren't API calls so they don't cost anything
+const UPSTREAMS: &'static [(&'static str, &'static str)] = &[
+    ("dark_sky", "https://api.darksky.net/"),
+    ("google", "https://maps.googleapis.com/"),
+];
+
+/// Whether each upstream could be reached, and if not why not.
+pub type Probe = Vec<(&'static str, Result<(), String>)>;
+
+/// What the health and version routes report, shared by every connection.
+#[derive(Clone)]
+pub struct Health {
+    last_probe: Arc<Mutex<Option<(Instant, Probe)>>>,
+    model_version: Arc<String>,
+    probe_upstream: bool,
+    training_package_version: Arc<String>,
+}
+
+impl Health {
+    /// Checks the upstreams can be reached, if we were asked to; otherwise `None`.
+    pub fn try_probe_upstream(&self) -> Option<Probe> {
+        if !self.probe_upstream {
+            return None;
+        }
+
+        let mut last_probe = self.last_probe.lock().expect(PANIC_POISONED_HEALTH);
+        if let Some((probed_at, ref probe)) = *last_probe {
+            if probed_at.elapsed() < Duration::from_secs(PROBE_CACHE_SECS) {
+                return Some(probe.clone());
+            }
+        }
+
+        let probe = probe_upstream();
+        *last_probe = Some((Instant::now(), probe.clone()));
+
+        Some(probe)
+    }
+
+    pub fn version_to_json(&self) -> JsonValue {
+        object!{
+            "version" => env!("CARGO_PKG_VERSION"),
+            "model_version" => self.model_version.as_str(),
+            "training_package_version" => self.training_package_version.as_str(),
+        }
+    }
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        // The training file says which versions of snips produced it
+        let mut training = String::new();
+        File::open(get_training_file())
+            .and_then(|mut file| file.read_to_string(&mut training))
+            .expect(PANIC_UNACCEPTABLE_TRAINING_FILE);
+        let training = json::parse(&training).expect(PANIC_UNACCEPTABLE_TRAINING_FILE);
+        let version_of = |key: &str| Arc::new(training[key].as_str().unwrap_or("").to_owned());
+
+        Self {
+            last_probe: Arc::new(Mutex::new(None)),
+            model_version: version_of("model_version"),
+            probe_upstream: get_probe_upstream(),
+            training_package_version: version_of("training_package_version"),
+        }
+    }
+}
+
+fn probe_upstream() -> Probe {
+    let client = Client::builder()
+        .timeout(Duration::from_secs(PROBE_TIMEOUT_SECS))
+        .build();
+    let client = match client {
+        Ok(client) => client,
+        Err(e) => {
+            return UPSTREAMS
+                .iter()
+                .map(|&(name, _)| (name, Err(e.to_string())))
+                .collect()
+        }
+    };
+
+    UPSTREAMS
+        .iter()
+        .map(|&(name, url)| (name, client.head(url).send().map(|_| ()).map_err(|e| e.to_string())))
+        .collect()
+}
@@ -0,0 +1,170 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use futures::{future, Future};
+
+use snips_nlu_ontology::{Slot, SlotValue};
+
+use dark_sky::{DarkSkyApi, Prediction};
+use google::GoogleApi;
+use phrases::{self, Phrasebook, Pool};
+use session::Session;
+use upstream::is_any_unavailable;
+
+/// Everything an intent handler may use while answering one utterance. Cloning it is cheap,
+/// which is how handlers take it along into the replies they are still waiting on.
+#[derive(Clone)]
+pub struct Context {
+    pub dark_sky_api: Rc<DarkSkyApi>,
+    pub google_api: Rc<GoogleApi>,
+    pub phrasebook: Rc<Phrasebook>,
+    pub session: Rc<RefCell<Session>>,
+    pub user_id: u64,
+}
+
+impl Context {
+    pub fn say(&self, pool: &'static Pool, args: &[(&str, &str)]) -> String {
+        self.phrasebook.say(pool, &mut self.session.borrow_mut(), args)
+    }
+
+    /// What to say when we couldn't get what we needed from Google or Dark Sky; if one of them
+    /// is failing so often that we've stopped calling it the user is told so.
+    pub fn say_down(&self) -> String {
+        if is_any_unavailable() {
+            self.say(&phrases::UNAVAILABLE, &[])
+        } else {
+            self.say(&phrases::DOWN, &[])
+        }
+    }
+}
+
+/// A skill Harris can perform, registered against the name of the nlu intent which triggers it.
+pub trait IntentHandler {
+    /// Whether the slots carry enough to act upon; if not Harris ranks down to the next intent.
+    fn accepts(&self, _slots: &[Slot]) -> bool {
+        true
+    }
+
+    /// Asked instead of `respond` when the intent probability is only good enough to check with
+    /// the user first. Returning `None` means Harris tells the user it is unsure instead.
+    fn confirm(&self, _ctx: &Context, _slots: &[Slot]) -> Option<Reply> {
+        None
+    }
+
+    fn respond(&self, ctx: &Context, slots: &[Slot]) -> ReplyFuture;
+}
+
+#[derive(Default)]
+pub struct Registry {
+    handlers: HashMap<String, Box<IntentHandler>>,
+}
+
+impl Registry {
+    pub fn get(&self, intent_name: &str) -> Option<&IntentHandler> {
+        self.handlers.get(intent_name).map(|h| h.as_ref())
+    }
+
+    pub fn intent_names(&self) -> Vec<String> {
+        self.handlers.keys().cloned().collect()
+    }
+
+    pub fn register<N, H>(&mut self, intent_name: N, handler: H)
+    where
+        N: Into<String>,
+        H: IntentHandler + 'static,
+    {
+        self.handlers.insert(intent_name.into(), Box::new(handler));
+    }
+}
+
+/// Structured data which goes along with the text of a reply so that clients can render more
+/// than a sentence.
+pub enum Card {
+    Forecast(ForecastCard),
+    Place(PlaceCard),
+}
+
+pub struct ForecastCard {
+    pub condition: Option<String>,
+    pub prediction: Prediction,
+    pub tense: &'static str,
+}
+
+/// What the nlu engine made of the utterance.
+pub struct IntentSummary {
+    pub name: String,
+    pub probability: f32,
+    pub slots: Vec<SlotSummary>,
+}
+
+impl IntentSummary {
+    pub fn new(name: &str, probability: f32, slots: &[Slot]) -> Self {
+        Self {
+            name: name.to_owned(),
+            probability: probability,
+            slots: slots.iter().map(SlotSummary::from).collect(),
+        }
+    }
+}
+
+pub struct PlaceCard {
+    pub lat: f64,
+    pub lng: f64,
+    pub name: String,
+}
+
+pub struct Reply {
+    pub cards: Vec<Card>,
+    pub intent: Option<IntentSummary>,
+    pub text: String,
+}
+
+impl Reply {
+    /// The reply, ready straight away.
+    pub fn ready(self) -> ReplyFuture {
+        Box::new(future::ok(self))
+    }
+
+    pub fn text<T: Into<String>>(text: T) -> Self {
+        Self {
+            cards: vec![],
+            intent: None,
+            text: text.into(),
+        }
+    }
+
+    pub fn with_card(mut self, card: Card) -> Self {
+        self.cards.push(card);
+        self
+    }
+}
+
+/// A reply which may still be waiting on upstream services. Replies don't fail; when something
+/// goes wrong Harris says so instead.
+pub type ReplyFuture = Box<Future<Item = Reply, Error = ()>>;
+
+pub struct SlotSummary {
+    pub entity: String,
+    pub name: String,
+    pub raw_value: String,
+    pub value: Option<String>,
+}
+
+impl<'a> From<&'a Slot> for SlotSummary {
+    fn from(slot: &Slot) -> Self {
+        // Only the value kinds our training set produces are resolved
+        let value = match &slot.value {
+            &SlotValue::Custom(ref v) => Some(v.value.to_owned()),
+            &SlotValue::InstantTime(ref v) => Some(v.value.to_owned()),
+            _ => None,
+        };
+
+        Self {
+            entity: slot.entity.to_owned(),
+            name: slot.slot_name.to_owned(),
+            raw_value: slot.raw_value.to_owned(),
+            value: value,
+        }
+    }
+}
@@ -0,0 +1,214 @@
+use chrono::{Offset, TimeZone, Utc};
+
+use chrono_tz::Tz;
+
+use futures::Future;
+
+use snips_nlu_ontology::{Slot, SlotValue};
+
+use tz_search;
+
+use intent::{Context, IntentHandler, Reply, ReplyFuture};
+use phrases;
+use upstream::Pending;
+
+/// Tells the user the current local time in a place.
+pub struct LocalTimeHandler;
+
+impl IntentHandler for LocalTimeHandler {
+    fn accepts(&self, slots: &[Slot]) -> bool {
+        try_get_slot(slots, "time_locality").is_some()
+    }
+
+    fn respond(&self, ctx: &Context, slots: &[Slot]) -> ReplyFuture {
+        // This will always succeed because accepts() checked it
+        let locality = try_get_slot(slots, "time_locality").unwrap();
+
+        let tz = try_get_time_zone(ctx, &locality);
+        let ctx = ctx.clone();
+        Box::new(tz.map(move |tz| {
+            // Sanity check: We may be unable to place the locality in a time zone
+            let tz = match tz {
+                Some(Some(tz)) => tz,
+                Some(None) => return say_no_time_zone(&ctx, &locality),
+                None => return Reply::text(ctx.say_down()),
+            };
+
+            let now = Utc::now().with_timezone(&tz);
+            let time = now.format("%-I:%M %p on %A").to_string();
+            Reply::text(ctx.say(
+                &phrases::LOCAL_TIME,
+                &[("place", locality.as_str()), ("time", time.as_str())],
+            ))
+        }))
+    }
+}
+
+/// Tells the user how many hours apart two places are.
+pub struct TimeDifferenceHandler;
+
+impl IntentHandler for TimeDifferenceHandler {
+    fn accepts(&self, slots: &[Slot]) -> bool {
+        try_get_slot(slots, "time_locality").is_some()
+            && try_get_slot(slots, "time_other_locality").is_some()
+    }
+
+    fn respond(&self, ctx: &Context, slots: &[Slot]) -> ReplyFuture {
+        // These will always succeed because accepts() checked them
+        let locality = try_get_slot(slots, "time_locality").unwrap();
+        let other_locality = try_get_slot(slots, "time_other_locality").unwrap();
+
+        // Both places are looked up at once
+        let time_zones =
+            try_get_time_zone(ctx, &locality).join(try_get_time_zone(ctx, &other_locality));
+        let ctx = ctx.clone();
+        Box::new(time_zones.map(move |(tz, other_tz)| {
+            // Sanity check: We may be unable to place either locality in a time zone
+            let (tz, other_tz) = match (tz, other_tz) {
+                (Some(Some(tz)), Some(Some(other_tz))) => (tz, other_tz),
+                (Some(None), _) => return say_no_time_zone(&ctx, &locality),
+                (_, Some(None)) => return say_no_time_zone(&ctx, &other_locality),
+                _ => return Reply::text(ctx.say_down()),
+            };
+
+            // Offsets depend on daylight saving so compare them as of right now
+            let now = Utc::now().naive_utc();
+            let offset = tz.offset_from_utc_datetime(&now).fix();
+            let other_offset = other_tz.offset_from_utc_datetime(&now).fix();
+            let difference = offset.local_minus_utc() - other_offset.local_minus_utc();
+
+            // Sanity check: They may be the same
+            if difference == 0 {
+                return Reply::text(ctx.say(
+                    &phrases::SAME_TIME,
+                    &[
+                        ("place", locality.as_str()),
+                        ("other", other_locality.as_str()),
+                    ],
+                ));
+            }
+
+            let pool = if difference > 0 {
+                &phrases::TIME_AHEAD
+            } else {
+                &phrases::TIME_BEHIND
+            };
+            let duration = format_duration(difference.abs());
+            Reply::text(ctx.say(
+                pool,
+                &[
+                    ("place", locality.as_str()),
+                    ("other", other_locality.as_str()),
+                    ("duration", duration.as_str()),
+                ],
+            ))
+        }))
+    }
+}
+
+/// Tells the user which time zone a place is in.
+pub struct TimeZoneHandler;
+
+impl IntentHandler for TimeZoneHandler {
+    fn accepts(&self, slots: &[Slot]) -> bool {
+        try_get_slot(slots, "time_locality").is_some()
+    }
+
+    fn respond(&self, ctx: &Context, slots: &[Slot]) -> ReplyFuture {
+        // This will always succeed because accepts() checked it
+        let locality = try_get_slot(slots, "time_locality").unwrap();
+
+        let tz = try_get_time_zone(ctx, &locality);
+        let ctx = ctx.clone();
+        Box::new(tz.map(move |tz| {
+            // Sanity check: We may be unable to place the locality in a time zone
+            let tz = match tz {
+                Some(Some(tz)) => tz,
+                Some(None) => return say_no_time_zone(&ctx, &locality),
+                None => return Reply::text(ctx.say_down()),
+            };
+
+            // The abbreviation and offset depend on daylight saving, so give them as of now
+            let now = Utc::now().with_timezone(&tz);
+            let abbreviation = now.format("%Z").to_string();
+            let offset = now.format("%:z").to_string();
+            Reply::text(ctx.say(
+                &phrases::TIME_ZONE,
+                &[
+                    ("place", locality.as_str()),
+                    ("zone", tz.name()),
+                    ("abbreviation", abbreviation.as_str()),
+                    ("offset", offset.as_str()),
+                ],
+            ))
+        }))
+    }
+}
+
+/// Formats a number of seconds as hours and minutes (for the half-hour time zones), leaving out
+/// whichever is zero.
+fn format_duration(seconds: i32) -> String {
+    let hours = seconds / 3_600;
+    let minutes = seconds % 3_600 / 60;
+    match (hours, minutes) {
+        (hours, 0) => format_count(hours, "hour"),
+        (0, minutes) => format_count(minutes, "minute"),
+        (hours, minutes) => format!(
+            "{} {}",
+            format_count(hours, "hour"),
+            format_count(minutes, "minute")
+        ),
+    }
+}
+
+fn format_count(count: i32, unit: &str) -> String {
+    match count {
+        1 => format!("1 {}", unit),
+        count => format!("{} {}s", count, unit),
+    }
+}
+
+/// Owns up to having found the place but not its time zone, which asking again won't fix.
+fn say_no_time_zone(ctx: &Context, place: &str) -> Reply {
+    Reply::text(ctx.say(&phrases::NO_TIME_ZONE, &[("place", place)]))
+}
+
+fn try_get_slot(slots: &[Slot], slot_name: &str) -> Option<String> {
+    for slot in slots {
+        if &slot.slot_name == slot_name {
+            if let &SlotValue::Custom(ref v) = &slot.value {
+                return Some(v.value.to_owned());
+            }
+        }
+    }
+
+    None
+}
+
+/// Geocodes the locality and then finds its time zone using the bundled time zone database.
+/// `None` means the locality couldn't be geocoded; `Some(None)` that it was, but the database
+/// has no time zone there.
+fn try_get_time_zone(ctx: &Context, locality: &str) -> Pending<Option<Tz>> {
+    let place = ctx.google_api.try_get_place(locality);
+    Box::new(place.map(|place| place.map(|p| try_get_time_zone_at(p.lat, p.lng))))
+}
+
+/// Finds the time zone of a point using the bundled time zone database.
+pub fn try_get_time_zone_at(lat: f64, lng: f64) -> Option<Tz> {
+    tz_search::lookup(lat, lng).and_then(|name| name.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations_leave_out_zeros() {
+        assert_eq!(format_duration(3_600), "1 hour");
+        assert_eq!(format_duration(9 * 3_600), "9 hours");
+        assert_eq!(format_duration(30 * 60), "30 minutes");
+        assert_eq!(format_duration(60), "1 minute");
+        assert_eq!(format_duration(3_600 + 60), "1 hour 1 minute");
+        assert_eq!(format_duration(5 * 3_600 + 45 * 60), "5 hours 45 minutes");
+    }
+}
@@ -0,0 +1,333 @@
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::mem;
+use std::time::Duration;
+
+use chrono::Utc;
+
+use futures::{Async, Future, Poll};
+
+use json::JsonValue;
+
+use rand;
+
+use cli::{get_log_format, get_log_level, get_log_redact};
+use intent::IntentSummary;
+
+// Panics
+const PANIC_UNACCEPTABLE_LOG_FORMAT: &'static str = "Unacceptable log format";
+const PANIC_UNACCEPTABLE_LOG_LEVEL: &'static str = "Unacceptable log level";
+
+// Request ids we are given are used as they are, so long as they can't mess up the logs
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+const REDACTED: &'static str = "[redacted]";
+
+/// How much a line matters, most important first.
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+}
+
+enum Format {
+    Json,
+    Logfmt,
+}
+
+struct Config {
+    format: Format,
+    level: Level,
+    redact: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            format: match get_log_format().as_ref() {
+                "json" => Format::Json,
+                "logfmt" => Format::Logfmt,
+                _ => panic!(PANIC_UNACCEPTABLE_LOG_FORMAT),
+            },
+            level: match get_log_level().as_ref() {
+                "error" => Level::Error,
+                "warn" => Level::Warn,
+                "info" => Level::Info,
+                "debug" => Level::Debug,
+                _ => panic!(PANIC_UNACCEPTABLE_LOG_LEVEL),
+            },
+            redact: get_log_redact(),
+        }
+    }
+}
+
+/// What we learn about a request while answering it, which is logged as one line at the end.
+#[derive(Default)]
+struct RequestContext {
+    id: String,
+    intent: Option<JsonValue>,
+    text: Option<String>,
+    upstream: Vec<JsonValue>,
+}
+
+lazy_static! {
+    static ref CONFIG: Config = Config::default();
+}
+
+// The request being worked on by this thread, if any; see RequestLog
+thread_local!(static REQUEST: RefCell<Option<RequestContext>> = RefCell::new(None));
+
+pub fn error<M: AsRef<str>>(message: M) {
+    log(Level::Error, message.as_ref(), vec![]);
+}
+
+pub fn info<M: AsRef<str>>(message: M) {
+    log(Level::Info, message.as_ref(), vec![]);
+}
+
+pub fn warn<M: AsRef<str>>(message: M) {
+    log(Level::Warn, message.as_ref(), vec![]);
+}
+
+/// Writes one line, tagged with the id of the request being answered (if any).
+pub fn log(level: Level, message: &str, fields: Vec<(&'static str, JsonValue)>) {
+    if level > CONFIG.level {
+        return;
+    }
+
+    let mut line: Vec<(&'static str, JsonValue)> = vec![
+        ("ts", Utc::now().to_rfc3339().into()),
+        ("level", level.name().into()),
+        ("msg", message.into()),
+    ];
+    REQUEST.with(|r| {
+        if let Some(ref request) = *r.borrow() {
+            line.push(("request_id", request.id.as_str().into()));
+        }
+    });
+    line.extend(fields);
+
+    let line = match CONFIG.format {
+        Format::Json => {
+            let mut object = JsonValue::new_object();
+            for (key, val) in line {
+                object[key] = val;
+            }
+
+            object.dump()
+        }
+        Format::Logfmt => to_logfmt(&line),
+    };
+
+    // One write per line so lines from different threads don't interleave
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    writeln!(stdout, "{}", line).ok();
+}
+
+/// Work done a bit at a time (among other work on the same event loop) for one request, with
+/// the request's log entered each time it is polled. The log is handed back with the result.
+pub struct Logged<F> {
+    log: Option<RequestLog>,
+    work: F,
+}
+
+impl<F: Future> Future for Logged<F> {
+    type Item = (F::Item, RequestLog);
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let polled = {
+            let work = &mut self.work;
+            // This will always succeed because the log is only taken once we are ready
+            self.log.as_mut().unwrap().enter(|| work.poll())
+        };
+
+        match polled? {
+            // This will always succeed because the log is only taken here
+            Async::Ready(item) => Ok(Async::Ready((item, self.log.take().unwrap()))),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
+/// Collects what happens while answering one request. Many requests may be waiting on
+/// upstream services on a worker at once, so the log is only entered (made the one recorded
+/// to) while its request is being worked on.
+pub struct RequestLog {
+    context: Option<RequestContext>,
+}
+
+impl RequestLog {
+    pub fn begin(request_id: String) -> Self {
+        Self {
+            context: Some(RequestContext {
+                id: request_id,
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// Logs the request, with everything collected while answering it.
+    pub fn end(mut self, level: Level, fields: Vec<(&'static str, JsonValue)>) {
+        let mut fields = fields;
+        if let Some(ref mut request) = self.context {
+            if let Some(text) = request.text.take() {
+                fields.push(("text", text.into()));
+            }
+
+            if let Some(intent) = request.intent.take() {
+                fields.push(("intent", intent));
+            }
+
+            if !request.upstream.is_empty() {
+                let upstream = mem::replace(&mut request.upstream, vec![]);
+                fields.push(("upstream", JsonValue::Array(upstream)));
+            }
+        }
+
+        self.enter(|| log(level, "request", fields));
+    }
+
+    /// Has the work log to this request whenever it is polled, wherever that is.
+    pub fn around<F: Future>(self, work: F) -> Logged<F> {
+        Logged {
+            log: Some(self),
+            work: work,
+        }
+    }
+
+    /// Runs the work with anything logged or recorded going to this request.
+    pub fn enter<T, F: FnOnce() -> T>(&mut self, work: F) -> T {
+        let context = self.context.take();
+        let previous = REQUEST.with(|r| mem::replace(&mut *r.borrow_mut(), context));
+        let result = work();
+        self.context = REQUEST.with(|r| mem::replace(&mut *r.borrow_mut(), previous));
+
+        result
+    }
+}
+
+/// The id of the request this thread is working on, if any, so work it hands on to another
+/// thread can be logged under the same id.
+pub fn current_request_id() -> Option<String> {
+    REQUEST.with(|r| r.borrow().as_ref().map(|request| request.id.clone()))
+}
+
+/// Uses the id the client (or a proxy in front of us) gave the request if it is sensible,
+/// otherwise makes one up.
+pub fn request_id(given: Option<String>) -> String {
+    match given {
+        Some(ref id)
+            if !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN
+                && id.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c)) =>
+        {
+            id.to_owned()
+        }
+        _ => format!("{:016x}", rand::random::<u64>()),
+    }
+}
+
+pub fn record_intent(intent: &IntentSummary) {
+    let mut slots = JsonValue::new_object();
+    for slot in &intent.slots {
+        slots[slot.name.as_str()] = redact(&slot.raw_value).into();
+    }
+
+    let intent = object!{
+        "name" => intent.name.as_str(),
+        "probability" => intent.probability,
+        "slots" => slots,
+    };
+    REQUEST.with(|r| {
+        if let Some(ref mut request) = *r.borrow_mut() {
+            request.intent = Some(intent);
+        }
+    });
+}
+
+pub fn record_text(text: &str) {
+    let text = redact(text);
+    REQUEST.with(|r| {
+        if let Some(ref mut request) = *r.borrow_mut() {
+            request.text = Some(text);
+        }
+    });
+}
+
+pub fn record_upstream(upstream: &str, elapsed: Duration, error: Option<&str>) {
+    let call = object!{
+        "name" => upstream,
+        "duration_ms" => to_millis(elapsed),
+        "error" => error,
+    };
+    REQUEST.with(|r| {
+        if let Some(ref mut request) = *r.borrow_mut() {
+            request.upstream.push(call);
+        }
+    });
+}
+
+/// What users say is kept out of the logs when we were asked to.
+fn redact(text: &str) -> String {
+    if CONFIG.redact {
+        REDACTED.to_owned()
+    } else {
+        text.to_owned()
+    }
+}
+
+pub fn to_millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000 + duration.subsec_nanos() as u64 / 1_000_000
+}
+
+/// Formats the fields as key=value pairs, quoting values which need it.
+fn to_logfmt(fields: &[(&'static str, JsonValue)]) -> String {
+    fields
+        .iter()
+        .map(|&(key, ref val)| {
+            let val = match val.as_str() {
+                Some(val) => val.to_owned(),
+                None => val.dump(),
+            };
+            if val.is_empty()
+                || val.contains(|c: char| c == ' ' || c == '=' || c == '"' || c.is_control())
+            {
+                format!("{}={:?}", key, val)
+            } else {
+                format!("{}={}", key, val)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future::lazy;
+
+    use super::*;
+
+    #[test]
+    fn logged_work_is_tagged_while_polled() {
+        let log = RequestLog::begin("5f0c2bd1a4e97c36".to_owned());
+        let work = lazy(|| Ok::<_, ()>(current_request_id()));
+        let (request_id, _) = log.around(work).wait().unwrap();
+        assert_eq!(request_id, Some("5f0c2bd1a4e97c36".to_owned()));
+        assert_eq!(current_request_id(), None);
+    }
+}
@@ -1,39 +1,169 @@
 extern crate chrono;
+extern crate chrono_tz;
 #[macro_use]
 extern crate clap;
 extern crate futures;
+extern crate hmac;
 #[macro_use]
 extern crate json;
+extern crate jsonwebtoken;
+#[macro_use]
+extern crate lazy_static;
 extern crate lru_cache;
 extern crate multipart;
+extern crate net2;
 extern crate num_cpus;
+#[macro_use]
+extern crate prometheus;
+extern crate rand;
 extern crate reqwest;
+extern crate rustls;
+extern crate serde_json;
+extern crate sha2;
+extern crate signal_hook;
 extern crate snips_nlu_lib;
 extern crate snips_nlu_ontology;
+extern crate tokio_core;
 extern crate tokio_minihttp;
 extern crate tokio_proto;
+extern crate tokio_rustls;
 extern crate tokio_service;
+extern crate tungstenite;
+extern crate tz_search;
 extern crate urlencoding;
 
+mod auth;
+mod cache;
+mod channel;
 mod cli;
+mod cors;
 mod dark_sky;
+#[cfg(test)]
+mod fake_server;
 mod google;
 mod harris;
+mod health;
+mod intent;
+mod local_time;
+mod logging;
+mod matrix;
+mod metrics;
+mod notify;
+mod phrases;
+mod rate_limit;
+mod session;
+mod shutdown;
+mod slack;
+mod small_talk;
+mod socket;
+mod subscription;
+mod telegram;
+mod tls;
+mod upstream;
+mod weather;
 mod web;
+mod webhook;
 
-use cli::get_http_binding;
+use std::thread;
+
+use channel::{poll_forever, polling_channels};
+use cli::{get_http_binding, get_notify_interval, get_subscriptions_file, get_tls_cert_file,
+          get_ws_binding};
+use cors::CorsPolicy;
+use notify::{notify_forever, Hub};
+use rate_limit::RateLimits;
+use session::SessionStore;
+use shutdown::Shutdown;
+use subscription::{deliver_forever, SubscriptionStore};
 use web::Router;
+use webhook::{Deferred, Webhooks};
 
 fn main() {
-    // Figure out what address the web server binds to
+    // Figure out what addresses the web and websocket servers bind to
     let http_binding = get_http_binding();
+    let ws_binding = get_ws_binding();
 
     // Sprinkle some logging
-    println!(
-        "telemacher binding to http://{}\n[press CTRL + C to stop]",
-        &http_binding
-    );
+    let http_scheme = if get_tls_cert_file().is_some() {
+        "https"
+    } else {
+        "http"
+    };
+    logging::info(format!(
+        "telemacher binding to {}://{} and ws://{} [press CTRL + C to stop]",
+        http_scheme, &http_binding, &ws_binding
+    ));
+
+    // Both servers answer browsers the same way; a policy which can't be kept stops us here
+    let cors = CorsPolicy::default();
+
+    // SIGTERM and CTRL + C stop the web server gracefully
+    let shutdown = Shutdown::listen();
+
+    // Users are remembered no matter which server they talk to
+    let sessions = SessionStore::default();
+
+    // Proactive messages are pushed to whichever users are listening, and to the webhook
+    let hub = Hub::default();
+    let webhooks = Webhooks::start();
+    {
+        let hub = hub.clone();
+        let sessions = sessions.clone();
+        let webhooks = webhooks.clone();
+        thread::spawn(move || notify_forever(hub, sessions, webhooks, get_notify_interval()));
+    }
+
+    // Daily forecasts are sent to subscribers at their chosen local time
+    let subscriptions = SubscriptionStore::open(&get_subscriptions_file());
+    {
+        let hub = hub.clone();
+        let sessions = sessions.clone();
+        let subscriptions = subscriptions.clone();
+        let webhooks = webhooks.clone();
+        thread::spawn(move || deliver_forever(subscriptions, sessions, hub, webhooks));
+    }
+
+    // Chat networks which must be polled each get a thread (the others post to a route)
+    for channel in polling_channels() {
+        let sessions = sessions.clone();
+        let subscriptions = subscriptions.clone();
+        thread::spawn(move || poll_forever(channel, sessions, subscriptions));
+    }
+
+    // Chat messages may also be answered later, by posting the reply to the webhook
+    let deferred = Deferred::start(sessions.clone(), subscriptions.clone(), webhooks.clone());
+
+    // Both servers count against the same rate limits
+    let rate_limits = RateLimits::default();
+
+    // The websocket and event stream server runs alongside the web server
+    {
+        let cors = cors.clone();
+        let rate_limits = rate_limits.clone();
+        let sessions = sessions.clone();
+        let subscriptions = subscriptions.clone();
+        thread::spawn(move || {
+            socket::serve_forever(&ws_binding, sessions, subscriptions, hub, cors, rate_limits)
+        });
+    }
 
     // Load the web server and wait for CTRL + C or SIGTERM
-    Router::serve_forever(&http_binding);
+    Router::serve_until_stopped(
+        &http_binding,
+        sessions,
+        subscriptions.clone(),
+        deferred.clone(),
+        cors,
+        rate_limits,
+        &shutdown,
+    );
+
+    // The async replies and webhook deliveries we already have are sent before we go; replies
+    // become deliveries, so they go first
+    deferred.drain(&shutdown);
+    webhooks.drain(&shutdown);
+
+    // Make sure the last deliveries are on disk so nobody gets today's forecast twice
+    subscriptions.flush();
+    logging::info("telemacher stopped");
 }
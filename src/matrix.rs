@@ -0,0 +1,284 @@
+use std::cell::Cell;
+use std::time::Duration;
+
+use chrono::Utc;
+
+use json::{self, JsonValue};
+
+use reqwest::header::{ContentType, Headers};
+use reqwest::{Client, Method};
+
+use urlencoding::encode;
+
+use channel::{to_user_id, Channel, Inbound};
+use cli::{get_matrix_access_token, get_matrix_homeserver};
+use intent::Reply;
+use logging;
+
+// Headers
+const HEADER_AUTHORIZATION: &'static str = "Authorization";
+
+// How long the homeserver may hold a sync open waiting for something to happen
+const SYNC_TIMEOUT_MILLIS: u64 = 30_000;
+
+// Our http timeout must outlast the sync timeout
+const HTTP_TIMEOUT_SECS: u64 = 60;
+
+/// A Matrix account, driven with the client-server sync API. Harris joins any room he is
+/// invited to and answers text messages from everyone but himself.
+pub struct MatrixChannel {
+    access_token: String,
+    client: Client,
+    homeserver: String,
+    /// Where the last sync left off; `None` until the first sync, whose history we skip
+    next_batch: Option<String>,
+    txn_id: Cell<u64>,
+    /// Our own user id, so we can ignore our own messages
+    user_id: Option<String>,
+}
+
+impl MatrixChannel {
+    pub fn try_new() -> Option<Self> {
+        // Sanity check: Need both the homeserver and an account on it
+        let homeserver = get_matrix_homeserver();
+        let access_token = get_matrix_access_token();
+        if homeserver.is_none() || access_token.is_none() {
+            return None;
+        }
+
+        // The default client would give up on a long-poll before the homeserver answers
+        let client = Client::builder()
+            .timeout(Duration::from_secs(HTTP_TIMEOUT_SECS))
+            .build();
+        if let Err(e) = client {
+            logging::error(format!("Unable to start Matrix client: {}", e));
+            return None;
+        }
+
+        Some(Self {
+            access_token: access_token.unwrap(),
+            client: client.unwrap(),
+            homeserver: homeserver.unwrap().trim_right_matches('/').to_owned(),
+            next_batch: None,
+            txn_id: Cell::new(Utc::now().timestamp() as u64),
+            user_id: None,
+        })
+    }
+
+    fn try_request(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<JsonValue>,
+    ) -> Result<JsonValue, String> {
+        let mut headers = Headers::new();
+        headers.set(ContentType::json());
+        headers.set_raw(HEADER_AUTHORIZATION, format!("Bearer {}", &self.access_token));
+
+        let url = format!("{}/_matrix/client/r0{}", &self.homeserver, path);
+        let mut request = self.client.request(method, url.as_str());
+        request.headers(headers);
+        if let Some(body) = body {
+            request.body(body.dump());
+        }
+
+        let mut response = request.send().map_err(|e| e.to_string())?;
+
+        // Sanity check: Matrix errors come back as json with an errcode
+        let text = response.text().map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("Matrix responded {}: {}", response.status(), text));
+        }
+
+        json::parse(&text).map_err(|e| e.to_string())
+    }
+
+    fn try_sync(&mut self) -> Result<JsonValue, String> {
+        // Find out who we are before anything else so we never answer ourselves
+        if let None = self.user_id {
+            let whoami = self.try_request(Method::Get, "/account/whoami", None)?;
+            self.user_id = whoami["user_id"].as_str().map(|u| u.to_owned());
+        }
+
+        // The first sync returns straight away with history we don't want to answer
+        let path = match self.next_batch {
+            Some(ref since) => format!(
+                "/sync?timeout={}&since={}",
+                SYNC_TIMEOUT_MILLIS,
+                encode(since)
+            ),
+            None => "/sync?timeout=0".to_owned(),
+        };
+        let updates = self.try_request(Method::Get, &path, None)?;
+        let is_first = self.next_batch.is_none();
+        self.next_batch = updates["next_batch"].as_str().map(|n| n.to_owned());
+
+        // Accept every invitation
+        for (room_id, _) in updates["rooms"]["invite"].entries() {
+            let path = format!("/rooms/{}/join", encode(room_id));
+            let joined = self.try_request(Method::Post, &path, Some(JsonValue::new_object()));
+            if let Err(e) = joined {
+                logging::warn(format!("Unable to join Matrix room {}: {}", room_id, e));
+            }
+        }
+
+        if is_first {
+            Ok(JsonValue::Null)
+        } else {
+            Ok(updates)
+        }
+    }
+}
+
+impl Channel for MatrixChannel {
+    fn name(&self) -> &'static str {
+        "matrix"
+    }
+
+    fn parse(&self, updates: &JsonValue) -> Vec<Inbound> {
+        let mut inbound = vec![];
+        for (room_id, room) in updates["rooms"]["join"].entries() {
+            for event in room["timeline"]["events"].members() {
+                // Sanity check: We only answer text messages, and not our own
+                let sender = event["sender"].as_str();
+                let text = event["content"]["body"].as_str();
+                if event["type"].as_str() != Some("m.room.message")
+                    || event["content"]["msgtype"].as_str() != Some("m.text")
+                    || sender.is_none() || text.is_none()
+                    || sender == self.user_id.as_ref().map(|u| u.as_str())
+                {
+                    continue;
+                }
+
+                inbound.push(Inbound {
+                    conversation: room_id.to_owned(),
+                    text: text.unwrap().to_owned(),
+                    user_id: to_user_id(&["matrix", sender.unwrap()]),
+                });
+            }
+        }
+
+        inbound
+    }
+
+    fn poll(&mut self) -> Option<Result<JsonValue, String>> {
+        Some(self.try_sync())
+    }
+
+    fn send(&self, conversation: &str, reply: &Reply) -> Result<(), String> {
+        // Each message needs a transaction id unique to this access token
+        let txn_id = self.txn_id.get() + 1;
+        self.txn_id.set(txn_id);
+
+        let path = format!(
+            "/rooms/{}/send/m.room.message/{}",
+            encode(conversation),
+            txn_id
+        );
+        let body = object!{
+            "msgtype" => "m.text",
+            "body" => reply.text.as_str(),
+        };
+        self.try_request(Method::Put, &path, Some(body)).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fake_server::FakeServer;
+
+    use super::*;
+
+    const SYNC: &'static str = "{\"user_id\":\"@harris:example.org\",\"next_batch\":\"s1\",\
+                                \"rooms\":{\"invite\":{\"!new:example.org\":{}}}}";
+
+    fn matrix(homeserver: &str) -> MatrixChannel {
+        MatrixChannel {
+            access_token: "token".to_owned(),
+            client: Client::new(),
+            homeserver: homeserver.to_owned(),
+            next_batch: None,
+            txn_id: Cell::new(1),
+            user_id: Some("@harris:example.org".to_owned()),
+        }
+    }
+
+    fn message(sender: &str, msgtype: &str) -> JsonValue {
+        object!{
+            "type" => "m.room.message",
+            "sender" => sender,
+            "content" => object!{ "msgtype" => msgtype, "body" => "Hi" },
+        }
+    }
+
+    #[test]
+    fn text_from_others_is_parsed() {
+        let mut updates = JsonValue::new_object();
+        updates["rooms"]["join"]["!room:example.org"]["timeline"]["events"] = array![
+            message("@alice:example.org", "m.text"),
+            message("@alice:example.org", "m.image"),
+            message("@harris:example.org", "m.text"),
+        ];
+
+        let inbound = matrix("unused").parse(&updates);
+        assert_eq!(inbound.len(), 1);
+        assert_eq!(inbound[0].conversation, "!room:example.org");
+        assert_eq!(inbound[0].text, "Hi");
+        assert_eq!(inbound[0].user_id, to_user_id(&["matrix", "@alice:example.org"]));
+    }
+
+    #[test]
+    fn first_sync_skips_history_and_joins_invitations() {
+        let server = FakeServer::start("200 OK", SYNC);
+        let mut matrix = matrix(&server.url);
+        matrix.user_id = None;
+        assert_eq!(matrix.try_sync(), Ok(JsonValue::Null));
+        assert_eq!(matrix.user_id.as_ref().map(|u| u.as_str()), Some("@harris:example.org"));
+        assert_eq!(matrix.next_batch.as_ref().map(|n| n.as_str()), Some("s1"));
+
+        let whoami = server.next_request();
+        assert_eq!(whoami.path, "/_matrix/client/r0/account/whoami");
+        assert_eq!(whoami.header("Authorization"), Some("Bearer token"));
+        assert_eq!(server.next_request().path, "/_matrix/client/r0/sync?timeout=0");
+        let join = server.next_request();
+        assert_eq!(join.method, "POST");
+        assert_eq!(join.path, "/_matrix/client/r0/rooms/%21new%3Aexample.org/join");
+
+        // The next sync waits for news from where the last left off
+        assert!(matrix.try_sync().unwrap().is_object());
+        assert_eq!(
+            server.next_request().path,
+            "/_matrix/client/r0/sync?timeout=30000&since=s1"
+        );
+    }
+
+    #[test]
+    fn replies_are_sent_to_the_room() {
+        let server = FakeServer::start("200 OK", "{\"event_id\":\"$1\"}");
+        let matrix = matrix(&server.url);
+        assert!(matrix.send("!room:example.org", &Reply::text("Hello")).is_ok());
+        assert!(matrix.send("!room:example.org", &Reply::text("Again")).is_ok());
+
+        let request = server.next_request();
+        assert_eq!(request.method, "PUT");
+        assert_eq!(
+            request.path,
+            "/_matrix/client/r0/rooms/%21room%3Aexample.org/send/m.room.message/2"
+        );
+        let body = json::parse(&request.body).unwrap();
+        assert_eq!(body["msgtype"].as_str(), Some("m.text"));
+        assert_eq!(body["body"].as_str(), Some("Hello"));
+
+        // Every message gets a transaction id of its own
+        assert!(server.next_request().path.ends_with("/3"));
+    }
+
+    #[test]
+    fn refused_requests_fail() {
+        let server = FakeServer::start("403 Forbidden", "{\"errcode\":\"M_FORBIDDEN\"}");
+        let error = matrix(&server.url)
+            .send("!room:example.org", &Reply::text("Hello"))
+            .unwrap_err();
+        assert!(error.contains("M_FORBIDDEN"));
+    }
+}
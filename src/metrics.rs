@@ -0,0 +1,113 @@
+use std::time::Duration;
+
+use prometheus::{self, CounterVec, Encoder, HistogramVec, TextEncoder};
+
+// Panics
+const PANIC_UNACCEPTABLE_METRIC: &'static str = "Unacceptable metric";
+
+// Intent probabilities are between zero and one, and what matters is where they fall against
+// the confirm and answer thresholds
+const PROBABILITY_BUCKETS: &'static [f64] = &[0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7, 0.8, 0.9, 1.0];
+
+// Everything is registered with the default registry, which is what /metrics gathers
+lazy_static! {
+    static ref CACHE_LOOKUPS: CounterVec = register_counter_vec!(
+        "telemacher_cache_lookups_total",
+        "Cache lookups by cache (geocode, forecast) and result (hit, miss, stale)",
+        &["cache", "result"]
+    ).expect(PANIC_UNACCEPTABLE_METRIC);
+    static ref HTTP_REQUESTS: CounterVec = register_counter_vec!(
+        "telemacher_http_requests_total",
+        "HTTP requests by route, method and status",
+        &["route", "method", "status"]
+    ).expect(PANIC_UNACCEPTABLE_METRIC);
+    static ref HTTP_REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "telemacher_http_request_duration_seconds",
+        "HTTP request latency by route, method and status",
+        &["route", "method", "status"]
+    ).expect(PANIC_UNACCEPTABLE_METRIC);
+    static ref INTENTS: CounterVec = register_counter_vec!(
+        "telemacher_intents_total",
+        "Utterances by the intent they were understood as",
+        &["intent"]
+    ).expect(PANIC_UNACCEPTABLE_METRIC);
+    static ref INTENT_PROBABILITY: HistogramVec = register_histogram_vec!(
+        "telemacher_intent_probability",
+        "How sure the NLU engine was of each intent",
+        &["intent"],
+        PROBABILITY_BUCKETS.to_vec()
+    ).expect(PANIC_UNACCEPTABLE_METRIC);
+    static ref PHRASES: CounterVec = register_counter_vec!(
+        "telemacher_phrases_total",
+        "Lines Harris said by phrase pool (unsure and down are the ones to watch)",
+        &["pool"]
+    ).expect(PANIC_UNACCEPTABLE_METRIC);
+    static ref UPSTREAM_ERRORS: CounterVec = register_counter_vec!(
+        "telemacher_upstream_errors_total",
+        "Failed calls to Google and Dark Sky",
+        &["upstream"]
+    ).expect(PANIC_UNACCEPTABLE_METRIC);
+    static ref UPSTREAM_REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "telemacher_upstream_request_duration_seconds",
+        "Latency of calls to Google and Dark Sky",
+        &["upstream"]
+    ).expect(PANIC_UNACCEPTABLE_METRIC);
+}
+
+pub fn observe_cache_lookup(cache: &str, is_hit: bool) {
+    let result = if is_hit { "hit" } else { "miss" };
+    CACHE_LOOKUPS.with_label_values(&[cache, result]).inc();
+}
+
+pub fn observe_http_request(route: &str, method: &str, status: u32, elapsed: Duration) {
+    let status = status.to_string();
+    let labels = [route, method, status.as_str()];
+    HTTP_REQUESTS.with_label_values(&labels).inc();
+    HTTP_REQUEST_DURATION
+        .with_label_values(&labels)
+        .observe(to_secs(elapsed));
+}
+
+pub fn observe_intent(intent: &str, probability: f32) {
+    INTENTS.with_label_values(&[intent]).inc();
+    INTENT_PROBABILITY
+        .with_label_values(&[intent])
+        .observe(probability as f64);
+}
+
+pub fn observe_phrase(pool: &str) {
+    PHRASES.with_label_values(&[pool]).inc();
+}
+
+/// Counts a stale entry being used because the upstream couldn't be reached, which is on top
+/// of the miss which led to it.
+pub fn observe_stale_cache_use(cache: &str) {
+    CACHE_LOOKUPS.with_label_values(&[cache, "stale"]).inc();
+}
+
+pub fn observe_upstream(upstream: &str, elapsed: Duration, is_error: bool) {
+    UPSTREAM_REQUEST_DURATION
+        .with_label_values(&[upstream])
+        .observe(to_secs(elapsed));
+    if is_error {
+        UPSTREAM_ERRORS.with_label_values(&[upstream]).inc();
+    }
+}
+
+/// Everything, in the Prometheus text format, along with its content type.
+pub fn render() -> (String, String) {
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+
+    // This will always succeed because the text encoder only fails on malformed metrics
+    encoder.encode(&prometheus::gather(), &mut buffer).unwrap();
+
+    (
+        encoder.format_type().to_owned(),
+        String::from_utf8_lossy(&buffer).into_owned(),
+    )
+}
+
+fn to_secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9
+}
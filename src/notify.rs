@@ -0,0 +1,160 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use futures::Future;
+
+use dark_sky::DarkSkyApi;
+use intent::Reply;
+use phrases::{self, Phrasebook, Pool};
+use session::SessionStore;
+use web::reply_to_json;
+use webhook::Webhooks;
+
+// Panics
+const PANIC_POISONED_HUB: &'static str = "Poisoned notification hub";
+
+// Dark Sky alert severities worth interrupting someone for
+const SEVERE_ALERT_SEVERITIES: &'static [&'static str] = &["warning", "watch"];
+
+/// Routes proactive messages to whichever push channels each user currently has open.
+#[derive(Clone, Default)]
+pub struct Hub {
+    listeners: Arc<Mutex<HashMap<u64, Vec<Sender<String>>>>>,
+}
+
+impl Hub {
+    /// Sends the (json) payload to every open channel the user has, returning whether anyone
+    /// was listening. Channels which have gone away are forgotten.
+    pub fn publish(&self, user_id: u64, payload: &str) -> bool {
+        let mut listeners = self.listeners.lock().expect(PANIC_POISONED_HUB);
+        let mut delivered = false;
+        if let Some(senders) = listeners.get_mut(&user_id) {
+            senders.retain(|s| s.send(payload.to_owned()).is_ok());
+            delivered = !senders.is_empty();
+        }
+
+        if !delivered {
+            listeners.remove(&user_id);
+        }
+
+        delivered
+    }
+
+    /// Whether the user has any push channel open, which they may not by the time anything is
+    /// published.
+    pub fn is_listening(&self, user_id: u64) -> bool {
+        let listeners = self.listeners.lock().expect(PANIC_POISONED_HUB);
+        listeners.contains_key(&user_id)
+    }
+
+    pub fn subscribe(&self, user_id: u64) -> Receiver<String> {
+        let (sender, receiver) = channel();
+        let mut listeners = self.listeners.lock().expect(PANIC_POISONED_HUB);
+        listeners.entry(user_id).or_insert_with(Vec::new).push(sender);
+        receiver
+    }
+
+    pub fn user_ids(&self) -> Vec<u64> {
+        let listeners = self.listeners.lock().expect(PANIC_POISONED_HUB);
+        listeners.keys().cloned().collect()
+    }
+}
+
+/// What we've already told a user about, so we only speak up when something changes.
+#[derive(Default)]
+struct Notified {
+    alerts: HashSet<String>,
+    is_rain_coming: bool,
+}
+
+/// Periodically checks the outlook at each listening user's remembered location and pushes
+/// a message when rain is about to start or a new severe alert is issued. With a configured
+/// webhook the users who asked for notifications there are listening too; nobody else is
+/// looked up, as each one costs a Dark Sky call.
+pub fn notify_forever(hub: Hub, sessions: SessionStore, webhooks: Webhooks, interval: Duration) {
+    let dark_sky_api = DarkSkyApi::default();
+    let phrasebook = Phrasebook::default();
+    let mut notified: HashMap<u64, Notified> = HashMap::new();
+
+    loop {
+        thread::sleep(interval);
+
+        // Forget about anyone who stopped listening
+        let mut user_ids = hub.user_ids();
+        if webhooks.is_configured() {
+            for user_id in sessions.user_ids() {
+                if sessions.with_session(user_id, |s| s.notify) && !user_ids.contains(&user_id) {
+                    user_ids.push(user_id);
+                }
+            }
+        }
+        notified.retain(|user_id, _| user_ids.contains(user_id));
+
+        for user_id in user_ids {
+            // Sanity check: We can only help people who have asked about somewhere
+            let location = match sessions.with_session(user_id, |s| s.location.clone()) {
+                Some(location) => location,
+                None => continue,
+            };
+
+            // Sanity check: The weather service might be down; we'll try again next time
+            let outlook = match dark_sky_api.try_get_outlook(location.lat, location.lng).wait() {
+                Ok(Some(outlook)) => outlook,
+                _ => continue,
+            };
+
+            let seen = notified.entry(user_id).or_insert_with(Notified::default);
+            let say = |pool: &'static Pool, args: &[(&str, &str)]| {
+                sessions.with_session(user_id, |s| phrasebook.say(pool, s, args))
+            };
+            let mut texts = vec![];
+
+            // Rain is only news when it wasn't already coming last time we looked
+            match outlook.rain_starts_in {
+                Some(minutes) if !seen.is_rain_coming => {
+                    texts.push(say(
+                        &phrases::RAIN_STARTING,
+                        &[
+                            ("place", location.name.as_str()),
+                            ("minutes", minutes.to_string().as_str()),
+                        ],
+                    ));
+                }
+                _ => (),
+            }
+            seen.is_rain_coming = outlook.rain_starts_in.is_some();
+
+            // Alerts are news the first time we see them; forget the ones which have ended
+            seen.alerts
+                .retain(|title| outlook.alerts.iter().any(|a| &a.title == title));
+            for alert in &outlook.alerts {
+                if !SEVERE_ALERT_SEVERITIES.contains(&alert.severity.as_str())
+                    || !seen.alerts.insert(alert.title.to_owned())
+                {
+                    continue;
+                }
+
+                texts.push(say(
+                    &phrases::SEVERE_ALERT,
+                    &[
+                        ("place", location.name.as_str()),
+                        ("title", alert.title.as_str()),
+                    ],
+                ));
+            }
+
+            for text in texts {
+                let payload = object!{
+                    "type" => "notification",
+                    "user_id" => user_id,
+                    "messages" => reply_to_json(Reply::text(text)),
+                };
+                hub.publish(user_id, &payload.dump());
+                webhooks.post(&payload);
+            }
+        }
+    }
+}
@@ -0,0 +1,519 @@
+use std::cell::RefCell;
+
+use rand::{Rng, SeedableRng, StdRng};
+
+use metrics::observe_phrase;
+use session::Session;
+
+// Panics
+const PANIC_UNACCEPTABLE_RNG: &'static str = "Unacceptable random number generator";
+
+/// A named set of interchangeable lines. Each variant carries a relative weight so
+/// that the signature lines come up more often than the novelty ones. Variants may
+/// contain `{placeholders}` which are filled in by the caller.
+pub struct Pool {
+    pub name: &'static str,
+    pub variants: &'static [(u32, &'static str)],
+}
+
+pub const GREETING: Pool = Pool {
+    name: "greeting",
+    variants: &[
+        (
+            6,
+            "Hello, {name}, this is Harris. I'm in right now, so you can talk to me personally.",
+        ),
+        (3, "Hi {name}, Harris here. Ask me about the weather anywhere you like."),
+        (2, "Good to see you, {name}. Harris K. Telemacher, at your service."),
+        (1, "{name}! We've got sun, earth, and atmosphere. What would you like to know?"),
+    ],
+};
+
+pub const UNSURE: Pool = Pool {
+    name: "unsure",
+    variants: &[
+        (6, "Hmm. That is fascinating. Ask me about the weather where you live."),
+        (3, "I'm not sure I follow. Try asking me whether it will rain somewhere."),
+        (2, "You've lost me there. I really only know about the weather."),
+        (1, "That's beyond my programming. Ask me how windy it is in Chicago."),
+    ],
+};
+
+pub const CONFIRM: Pool = Pool {
+    name: "confirm",
+    variants: &[
+        (3, "Did you want the weather in {place}?"),
+        (1, "I'm not quite sure what you meant. Did you want the weather in {place}?"),
+    ],
+};
+
+pub const DECLINED: Pool = Pool {
+    name: "declined",
+    variants: &[
+        (3, "My mistake. What would you like to know?"),
+        (1, "Never mind then. Ask me about the weather anywhere you like."),
+    ],
+};
+
+pub const DOWN: Pool = Pool {
+    name: "down",
+    variants: &[
+        (
+            6,
+            "Something went terribly wrong deep inside my logic. Put me on the floor and step back.",
+        ),
+        (3, "I can't seem to reach my weather instruments right now. Try again shortly."),
+        (1, "My circuits are a little foggy at the moment. Please ask me again later."),
+    ],
+};
+
+pub const STALE: Pool = Pool {
+    name: "stale",
+    variants: &[
+        (3, "As of {age} ago, {forecast}"),
+        (1, "I can't get a fresh forecast right now, but as of {age} ago, {forecast}"),
+    ],
+};
+
+pub const UNAVAILABLE: Pool = Pool {
+    name: "unavailable",
+    variants: &[
+        (3, "The weather service is unavailable right now. Please try again in a minute."),
+        (2, "My weather service is taking a short break. Ask me again in a little while."),
+        (1, "The forecasters aren't answering at the moment. Give them a minute and try again."),
+    ],
+};
+
+pub const HELLO: Pool = Pool {
+    name: "hello",
+    variants: &[
+        (3, "Hello, {name}. Ask me about the weather anywhere you like."),
+        (2, "Hi there, {name}. What would you like to know about the weather?"),
+        (1, "Why hello, {name}. I'm in right now, so you can talk to me personally."),
+    ],
+};
+
+pub const THANKS: Pool = Pool {
+    name: "thanks",
+    variants: &[
+        (3, "You're welcome, {name}."),
+        (2, "Happy to help."),
+        (1, "Any time. It's what I'm here for."),
+    ],
+};
+
+pub const GOODBYE: Pool = Pool {
+    name: "goodbye",
+    variants: &[
+        (3, "Goodbye, {name}."),
+        (2, "See you later, {name}. Don't forget your umbrella."),
+        (1, "Farewell. Harris K. Telemacher signing off."),
+    ],
+};
+
+pub const HELP: Pool = Pool {
+    name: "help",
+    variants: &[
+        (
+            3,
+            "Ask me about the weather anywhere, now or at a particular time. I can also tell you about {conditions}. Try \"Will it be humid in Paris tomorrow?\"",
+        ),
+        (
+            1,
+            "I'm a weatherman. Ask me something like \"How windy is Chicago?\" I know about {conditions}.",
+        ),
+    ],
+};
+
+pub const LOCAL_TIME: Pool = Pool {
+    name: "local_time",
+    variants: &[
+        (3, "It's {time} in {place}."),
+        (1, "In {place} it's currently {time}."),
+    ],
+};
+
+pub const SAME_TIME: Pool = Pool {
+    name: "same_time",
+    variants: &[
+        (3, "{place} and {other} are on the same time."),
+        (1, "There's no time difference between {place} and {other}."),
+    ],
+};
+
+pub const TIME_AHEAD: Pool = Pool {
+    name: "time_ahead",
+    variants: &[
+        (3, "{place} is {duration} ahead of {other}."),
+        (1, "It's {duration} later in {place} than in {other}."),
+    ],
+};
+
+pub const TIME_BEHIND: Pool = Pool {
+    name: "time_behind",
+    variants: &[
+        (3, "{place} is {duration} behind {other}."),
+        (1, "It's {duration} earlier in {place} than in {other}."),
+    ],
+};
+
+pub const TIME_ZONE: Pool = Pool {
+    name: "time_zone",
+    variants: &[
+        (3, "{place} is in the {zone} time zone, which is {abbreviation} (UTC{offset}) right now."),
+        (1, "They keep {zone} time in {place}; at the moment that's {abbreviation}, UTC{offset}."),
+    ],
+};
+
+pub const NO_TIME_ZONE: Pool = Pool {
+    name: "no_time_zone",
+    variants: &[
+        (3, "I couldn't work out the time zone for {place}."),
+        (1, "I found {place}, but not which time zone it's in. Try a town nearby?"),
+    ],
+};
+
+pub const RAIN_STARTING: Pool = Pool {
+    name: "rain_starting",
+    variants: &[
+        (3, "Heads up: rain should start in {place} in about {minutes} minutes."),
+        (1, "Grab an umbrella. It looks like rain in {place} within {minutes} minutes."),
+    ],
+};
+
+pub const SEVERE_ALERT: Pool = Pool {
+    name: "severe_alert",
+    variants: &[
+        (3, "Weather alert for {place}: {title}."),
+        (1, "Stay safe. There's a {title} in effect for {place}."),
+    ],
+};
+
+pub const SUBSCRIBED: Pool = Pool {
+    name: "subscribed",
+    variants: &[
+        (3, "Okay, I'll send you the forecast for {place} every day at {time}."),
+        (1, "You got it. Expect the {place} forecast daily at {time}."),
+    ],
+};
+
+pub const HAIL_CURRENTLY: Pool = Pool {
+    name: "hail_currently",
+    variants: &[(3, "It is hailing."), (1, "Yes, it's hailing right now.")],
+};
+
+pub const HAIL_MINUTELY: Pool = Pool {
+    name: "hail_minutely",
+    variants: &[(3, "It will hail."), (1, "Hail is on its way.")],
+};
+
+pub const HAIL_HOURLY: Pool = Pool {
+    name: "hail_hourly",
+    variants: &[(3, "It should hail."), (1, "Expect some hail.")],
+};
+
+pub const HAIL_DAILY: Pool = Pool {
+    name: "hail_daily",
+    variants: &[(3, "Hail is expected."), (1, "Hail is in the forecast.")],
+};
+
+pub const NO_HAIL_CURRENTLY: Pool = Pool {
+    name: "no_hail_currently",
+    variants: &[(3, "It is not hailing."), (1, "No, there's no hail right now.")],
+};
+
+pub const NO_HAIL_MINUTELY: Pool = Pool {
+    name: "no_hail_minutely",
+    variants: &[(3, "It will not hail."), (1, "There's no hail on the way.")],
+};
+
+pub const NO_HAIL_HOURLY: Pool = Pool {
+    name: "no_hail_hourly",
+    variants: &[(3, "It should not hail."), (1, "I wouldn't expect any hail.")],
+};
+
+pub const NO_HAIL_DAILY: Pool = Pool {
+    name: "no_hail_daily",
+    variants: &[(3, "Hail is not expected."), (1, "There's no hail in the forecast.")],
+};
+
+pub const HUMIDITY_CURRENTLY: Pool = Pool {
+    name: "humidity_currently",
+    variants: &[
+        (3, "The current humidity is {value}%."),
+        (1, "It's {value}% humidity right now."),
+    ],
+};
+
+pub const HUMIDITY_MINUTELY: Pool = Pool {
+    name: "humidity_minutely",
+    variants: &[
+        (3, "The humidity will be {value}%."),
+        (1, "Humidity is about to be {value}%."),
+    ],
+};
+
+pub const HUMIDITY_HOURLY: Pool = Pool {
+    name: "humidity_hourly",
+    variants: &[
+        (3, "The humidity should be {value}%."),
+        (1, "Expect humidity of around {value}%."),
+    ],
+};
+
+pub const HUMIDITY_DAILY: Pool = Pool {
+    name: "humidity_daily",
+    variants: &[
+        (3, "The estimated humidity is {value}%."),
+        (1, "Humidity should average about {value}%."),
+    ],
+};
+
+pub const RAIN_CURRENTLY: Pool = Pool {
+    name: "rain_currently",
+    variants: &[(3, "It is raining."), (1, "Yes, it's raining right now.")],
+};
+
+pub const RAIN_MINUTELY: Pool = Pool {
+    name: "rain_minutely",
+    variants: &[(3, "It will rain."), (1, "Rain is on its way.")],
+};
+
+pub const RAIN_HOURLY: Pool = Pool {
+    name: "rain_hourly",
+    variants: &[(3, "It should rain."), (1, "You'll want an umbrella.")],
+};
+
+pub const RAIN_DAILY: Pool = Pool {
+    name: "rain_daily",
+    variants: &[(3, "Rain is expected."), (1, "Rain is in the forecast.")],
+};
+
+pub const NO_RAIN_CURRENTLY: Pool = Pool {
+    name: "no_rain_currently",
+    variants: &[(3, "It is not raining."), (1, "No, it's dry right now.")],
+};
+
+pub const NO_RAIN_MINUTELY: Pool = Pool {
+    name: "no_rain_minutely",
+    variants: &[(3, "It will not rain."), (1, "There's no rain on the way.")],
+};
+
+pub const NO_RAIN_HOURLY: Pool = Pool {
+    name: "no_rain_hourly",
+    variants: &[(3, "It should not rain."), (1, "You can leave the umbrella at home.")],
+};
+
+pub const NO_RAIN_DAILY: Pool = Pool {
+    name: "no_rain_daily",
+    variants: &[(3, "Rain is not expected."), (1, "There's no rain in the forecast.")],
+};
+
+pub const SNOW_CURRENTLY: Pool = Pool {
+    name: "snow_currently",
+    variants: &[(3, "It is snowing."), (1, "Yes, it's snowing right now.")],
+};
+
+pub const SNOW_MINUTELY: Pool = Pool {
+    name: "snow_minutely",
+    variants: &[(3, "It will snow."), (1, "Snow is on its way.")],
+};
+
+pub const SNOW_HOURLY: Pool = Pool {
+    name: "snow_hourly",
+    variants: &[(3, "It should snow."), (1, "Expect some snow.")],
+};
+
+pub const SNOW_DAILY: Pool = Pool {
+    name: "snow_daily",
+    variants: &[(3, "Snow is expected."), (1, "Snow is in the forecast.")],
+};
+
+pub const NO_SNOW_CURRENTLY: Pool = Pool {
+    name: "no_snow_currently",
+    variants: &[(3, "It is not snowing."), (1, "No, there's no snow right now.")],
+};
+
+pub const NO_SNOW_MINUTELY: Pool = Pool {
+    name: "no_snow_minutely",
+    variants: &[(3, "It will not snow."), (1, "There's no snow on the way.")],
+};
+
+pub const NO_SNOW_HOURLY: Pool = Pool {
+    name: "no_snow_hourly",
+    variants: &[(3, "It should not snow."), (1, "I wouldn't expect any snow.")],
+};
+
+pub const NO_SNOW_DAILY: Pool = Pool {
+    name: "no_snow_daily",
+    variants: &[(3, "Snow is not expected."), (1, "There's no snow in the forecast.")],
+};
+
+pub const UV_CURRENTLY: Pool = Pool {
+    name: "uv_currently",
+    variants: &[
+        (3, "The current UV index is {value}."),
+        (1, "The UV index is {value} right now."),
+    ],
+};
+
+pub const UV_MINUTELY: Pool = Pool {
+    name: "uv_minutely",
+    variants: &[
+        (3, "The UV index will be {value}."),
+        (1, "The UV index is about to be {value}."),
+    ],
+};
+
+pub const UV_HOURLY: Pool = Pool {
+    name: "uv_hourly",
+    variants: &[
+        (3, "The UV index should be {value}."),
+        (1, "Expect a UV index of around {value}."),
+    ],
+};
+
+pub const UV_DAILY: Pool = Pool {
+    name: "uv_daily",
+    variants: &[
+        (3, "The estimated UV index is {value}."),
+        (1, "The UV index should peak around {value}."),
+    ],
+};
+
+pub const WIND_CURRENTLY: Pool = Pool {
+    name: "wind_currently",
+    variants: &[
+        (3, "The current wind speed is {value}mph."),
+        (1, "The wind is blowing at {value}mph right now."),
+    ],
+};
+
+pub const WIND_MINUTELY: Pool = Pool {
+    name: "wind_minutely",
+    variants: &[
+        (3, "The wind speed will be {value}mph."),
+        (1, "The wind is about to pick up to {value}mph."),
+    ],
+};
+
+pub const WIND_HOURLY: Pool = Pool {
+    name: "wind_hourly",
+    variants: &[
+        (3, "The wind speed should be {value}mph."),
+        (1, "Expect winds of around {value}mph."),
+    ],
+};
+
+pub const WIND_DAILY: Pool = Pool {
+    name: "wind_daily",
+    variants: &[
+        (3, "The estimated wind speed is {value}mph."),
+        (1, "Winds should average about {value}mph."),
+    ],
+};
+
+pub struct Phrasebook {
+    rng: RefCell<StdRng>,
+}
+
+impl Phrasebook {
+    /// Passing a seed makes every pick repeatable, which is what you want for tests.
+    pub fn new(seed: Option<u64>) -> Self {
+        let rng = match seed {
+            Some(seed) => StdRng::from_seed(&[seed as usize][..]),
+            None => StdRng::new().expect(PANIC_UNACCEPTABLE_RNG),
+        };
+
+        Self {
+            rng: RefCell::new(rng),
+        }
+    }
+
+    /// Picks a weighted variant from the pool, avoiding whichever variant this user heard
+    /// last time (if there is any other choice), and fills in the given placeholders.
+    pub fn say(&self, pool: &'static Pool, session: &mut Session, args: &[(&str, &str)]) -> String {
+        observe_phrase(pool.name);
+
+        let last = session.last_variant(pool.name);
+        let total: u32 = pool.variants
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| Some(i) != last || pool.variants.len() == 1)
+            .map(|(_, &(weight, _))| weight)
+            .sum();
+
+        // Walk the remaining variants until we've used up the randomly chosen weight
+        let mut remaining = self.rng.borrow_mut().gen_range(0, total);
+        let mut chosen = 0;
+        for (i, &(weight, _)) in pool.variants.iter().enumerate() {
+            if Some(i) == last && pool.variants.len() > 1 {
+                continue;
+            }
+
+            if remaining < weight {
+                chosen = i;
+                break;
+            }
+
+            remaining -= weight;
+        }
+
+        session.set_last_variant(pool.name, chosen);
+
+        let mut text = pool.variants[chosen].1.to_owned();
+        for &(key, val) in args {
+            text = text.replace(&format!("{{{}}}", key), val);
+        }
+
+        text
+    }
+}
+
+impl Default for Phrasebook {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COIN: Pool = Pool {
+        name: "coin",
+        variants: &[(1, "Heads, {name}."), (1, "Tails, {name}.")],
+    };
+
+    const ONLY: Pool = Pool {
+        name: "only",
+        variants: &[(1, "Always this.")],
+    };
+
+    fn say_many(phrasebook: &Phrasebook, pool: &'static Pool) -> Vec<String> {
+        let mut session = Session::default();
+        (0..20)
+            .map(|_| phrasebook.say(pool, &mut session, &[("name", "Ada")]))
+            .collect()
+    }
+
+    #[test]
+    fn seeded_picks_repeat() {
+        let first = say_many(&Phrasebook::new(Some(7)), &GREETING);
+        let second = say_many(&Phrasebook::new(Some(7)), &GREETING);
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn the_last_variant_is_not_repeated() {
+        let said = say_many(&Phrasebook::new(Some(7)), &COIN);
+        assert!(said.windows(2).all(|pair| pair[0] != pair[1]));
+        assert!(said.iter().all(|s| s == "Heads, Ada." || s == "Tails, Ada."));
+    }
+
+    #[test]
+    fn a_single_variant_is_always_said() {
+        let said = say_many(&Phrasebook::new(Some(7)), &ONLY);
+        assert!(said.iter().all(|s| s == "Always this."));
+    }
+}
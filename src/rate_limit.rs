@@ -0,0 +1,264 @@
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+
+use lru_cache::LruCache;
+
+use cli::{get_rate_limit_global, get_rate_limit_ip, get_rate_limit_user, get_trusted_proxies,
+          get_upstream_budget};
+use logging;
+
+// Panics
+const PANIC_POISONED_BUCKETS: &'static str = "Poisoned buckets";
+
+// Once there are this many buckets the one used longest ago is forgotten, so clients can't grow
+// the map forever; it has most likely filled up again, and a full bucket is the same as none
+const MAX_BUCKETS: usize = 65_536;
+
+const SECS_PER_DAY: i64 = 86_400;
+
+// The upstream budget is spent by every GoogleApi and DarkSkyApi, wherever they were made, and
+// resets at midnight UTC along with the providers' own daily quotas
+static UPSTREAM_CALLS: AtomicUsize = ATOMIC_USIZE_INIT;
+static UPSTREAM_DAY: AtomicUsize = ATOMIC_USIZE_INIT;
+
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// Adds the tokens earned since the last update and returns how many there are now.
+    fn refill(&mut self, now: Instant, capacity: f64) -> f64 {
+        let elapsed = now.duration_since(self.updated);
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        self.tokens = (self.tokens + elapsed * capacity / 60.0).min(capacity);
+        self.updated = now;
+        self.tokens
+    }
+}
+
+/// A token bucket per key, each holding up to a minute's worth of requests and refilling at
+/// the same rate. Without a rate there is no limit.
+#[derive(Clone)]
+struct Limiter<K: Eq + Hash> {
+    buckets: Arc<Mutex<LruCache<K, TokenBucket>>>,
+    per_minute: Option<u32>,
+}
+
+impl<K: Clone + Eq + Hash> Limiter<K> {
+    fn new(per_minute: Option<u32>) -> Self {
+        Self {
+            buckets: Arc::new(Mutex::new(LruCache::new(MAX_BUCKETS))),
+            per_minute: per_minute,
+        }
+    }
+
+    /// Takes a token for the key, or says how long until there will be one.
+    fn try_take(&self, key: K) -> Result<(), Duration> {
+        let capacity = match self.per_minute {
+            Some(per_minute) => per_minute as f64,
+            None => return Ok(()),
+        };
+
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().expect(PANIC_POISONED_BUCKETS);
+        if !buckets.contains_key(&key) {
+            buckets.insert(
+                key.clone(),
+                TokenBucket {
+                    tokens: capacity,
+                    updated: now,
+                },
+            );
+        }
+
+        // This will always succeed because the bucket was added if it was missing
+        let bucket = buckets.get_mut(&key).unwrap();
+        if bucket.refill(now, capacity) >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            let millis = (1.0 - bucket.tokens) * 60_000.0 / capacity;
+            Err(Duration::from_millis(millis.ceil() as u64))
+        }
+    }
+}
+
+/// The request rate limits configured on the command line, shared by every connection.
+#[derive(Clone)]
+pub struct RateLimits {
+    global: Limiter<()>,
+    ip: Limiter<IpAddr>,
+    /// The proxies whose word we take for who the client is
+    trusted_proxies: Arc<Vec<IpAddr>>,
+    user: Limiter<u64>,
+}
+
+impl RateLimits {
+    /// The address of the client on the other end of the connection. Behind one of our
+    /// proxies it's the one the proxy gives: X-Real-IP is set by the proxy outright; otherwise
+    /// X-Forwarded-For is read from the right, past our own proxies, to the address the first
+    /// of them was connected to by. The headers of anyone else are ignored, as anyone can
+    /// send them.
+    pub fn client_ip(
+        &self,
+        peer: IpAddr,
+        real_ip: Option<&str>,
+        forwarded_for: Option<&str>,
+    ) -> IpAddr {
+        if !self.trusted_proxies.contains(&peer) {
+            return peer;
+        }
+
+        if let Some(ip) = real_ip.and_then(|ip| ip.trim().parse().ok()) {
+            return ip;
+        }
+
+        forwarded_for
+            .unwrap_or("")
+            .rsplit(',')
+            .filter_map(|ip| ip.trim().parse().ok())
+            .find(|ip| !self.trusted_proxies.contains(ip))
+            .unwrap_or(peer)
+    }
+
+    /// Takes a token for a request from the client and from everyone, or says how long the
+    /// client should wait.
+    pub fn try_take_request(&self, ip: IpAddr) -> Result<(), Duration> {
+        // The client's own limit goes first so a flood from one client is turned away before
+        // it can use up everyone else's
+        self.ip.try_take(ip)?;
+        self.global.try_take(())
+    }
+
+    pub fn try_take_user(&self, user_id: u64) -> Result<(), Duration> {
+        self.user.try_take(user_id)
+    }
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            global: Limiter::new(get_rate_limit_global()),
+            ip: Limiter::new(get_rate_limit_ip()),
+            trusted_proxies: Arc::new(get_trusted_proxies()),
+            user: Limiter::new(get_rate_limit_user()),
+        }
+    }
+}
+
+/// Spends one of today's calls to a paid upstream API; returns false if they have all been
+/// spent, in which case the call must not be made.
+pub fn try_spend_upstream_call(api: &str) -> bool {
+    let budget = match get_upstream_budget() {
+        Some(budget) => budget as usize,
+        None => return true,
+    };
+
+    let day = (Utc::now().timestamp() / SECS_PER_DAY) as usize;
+    if UPSTREAM_DAY.swap(day, Ordering::SeqCst) != day {
+        UPSTREAM_CALLS.store(0, Ordering::SeqCst);
+    }
+
+    // Only say so the once, rather than for every refused call
+    let calls = UPSTREAM_CALLS.fetch_add(1, Ordering::SeqCst);
+    if calls == budget {
+        logging::warn(format!(
+            "The upstream budget of {} calls is spent ({} was refused); no more until midnight UTC",
+            budget, api
+        ));
+    }
+
+    calls < budget
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rate_limits(trusted_proxies: &[&str]) -> RateLimits {
+        RateLimits {
+            global: Limiter::new(None),
+            ip: Limiter::new(None),
+            trusted_proxies: Arc::new(trusted_proxies.iter().map(|p| p.parse().unwrap()).collect()),
+            user: Limiter::new(None),
+        }
+    }
+
+    #[test]
+    fn bucket_refills_at_the_rate() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket {
+            tokens: 0.0,
+            updated: now,
+        };
+        assert_eq!(bucket.refill(now + Duration::from_secs(30), 60.0), 30.0);
+        assert_eq!(bucket.refill(now + Duration::from_secs(600), 60.0), 60.0);
+    }
+
+    #[test]
+    fn client_ip_behind_proxies() {
+        let rate_limits = rate_limits(&["10.0.0.1", "10.0.0.2"]);
+        let proxy = "10.0.0.1".parse().unwrap();
+        let client: IpAddr = "203.0.113.7".parse().unwrap();
+        assert_eq!(
+            rate_limits.client_ip(proxy, Some("203.0.113.7"), Some("1.2.3.4")),
+            client
+        );
+        assert_eq!(
+            rate_limits.client_ip(proxy, None, Some("1.2.3.4, 203.0.113.7, 10.0.0.2")),
+            client
+        );
+        assert_eq!(rate_limits.client_ip(proxy, None, Some("junk")), proxy);
+        assert_eq!(rate_limits.client_ip(proxy, None, None), proxy);
+    }
+
+    #[test]
+    fn client_ip_without_proxies() {
+        let rate_limits = rate_limits(&[]);
+        let peer: IpAddr = "203.0.113.7".parse().unwrap();
+        assert_eq!(
+            rate_limits.client_ip(peer, Some("1.2.3.4"), Some("1.2.3.4")),
+            peer
+        );
+    }
+
+    #[test]
+    fn buckets_are_capped() {
+        let limiter = Limiter::new(Some(1));
+        for key in 0..MAX_BUCKETS + 10 {
+            assert!(limiter.try_take(key).is_ok());
+        }
+        assert_eq!(limiter.buckets.lock().unwrap().len(), MAX_BUCKETS);
+
+        // The newest are still remembered
+        assert!(limiter.try_take(MAX_BUCKETS + 9).is_err());
+    }
+
+    #[test]
+    fn empty_bucket_says_how_long() {
+        let limiter = Limiter::new(Some(2));
+        assert!(limiter.try_take("a").is_ok());
+        assert!(limiter.try_take("a").is_ok());
+
+        let retry_after = limiter.try_take("a").unwrap_err();
+        assert!(retry_after <= Duration::from_secs(30));
+        assert!(retry_after > Duration::from_secs(29));
+
+        // Each key has its own bucket
+        assert!(limiter.try_take("b").is_ok());
+    }
+
+    #[test]
+    fn no_rate_no_limit() {
+        let limiter = Limiter::new(None);
+        for _ in 0..1_000 {
+            assert!(limiter.try_take(()).is_ok());
+        }
+    }
+}
@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use lru_cache::LruCache;
+
+use snips_nlu_ontology::Slot;
+
+// Panics
+const PANIC_POISONED_SESSION_STORE: &'static str = "Poisoned session store";
+
+const LRU_CACHE_SIZE: usize = 16_384;
+
+/// A place the user asked about, already geocoded.
+#[derive(Clone, PartialEq)]
+pub struct Location {
+    pub lat: f64,
+    pub lng: f64,
+    pub name: String,
+}
+
+/// An intent we weren't sure enough of to act on, which we asked the user to confirm.
+#[derive(Clone, PartialEq)]
+pub struct PendingIntent {
+    pub intent_name: String,
+    pub probability: f32,
+    pub slots: Vec<Slot>,
+}
+
+#[derive(Clone, Default)]
+pub struct Session {
+    /// The last place this user asked about the weather in
+    pub location: Option<Location>,
+    pub name: Option<String>,
+    /// Whether this user wants notifications posted to the webhook
+    pub notify: bool,
+    /// What we asked this user to confirm, which only their next message may answer
+    pub pending: Option<PendingIntent>,
+    last_variants: HashMap<&'static str, usize>,
+}
+
+impl Session {
+    pub fn last_variant(&self, pool: &str) -> Option<usize> {
+        self.last_variants.get(pool).cloned()
+    }
+
+    pub fn set_last_variant(&mut self, pool: &'static str, variant: usize) {
+        self.last_variants.insert(pool, variant);
+    }
+
+    /// Takes on whatever was changed in a copy of the session since it was loaded.
+    fn merge(&mut self, loaded: &Session, changed: Session) {
+        if changed.location != loaded.location {
+            self.location = changed.location;
+        }
+        if changed.name != loaded.name {
+            self.name = changed.name;
+        }
+        if changed.notify != loaded.notify {
+            self.notify = changed.notify;
+        }
+        if changed.pending != loaded.pending {
+            self.pending = changed.pending;
+        }
+        for (pool, variant) in changed.last_variants {
+            if loaded.last_variant(pool) != Some(variant) {
+                self.last_variants.insert(pool, variant);
+            }
+        }
+    }
+}
+
+/// Shared by every connection so that a user is remembered no matter which
+/// worker thread or connection their request arrives on.
+#[derive(Clone)]
+pub struct SessionStore {
+    // Basic caching mechanism to stand-in for redis
+    cache: Arc<Mutex<LruCache<u64, Session>>>,
+}
+
+impl SessionStore {
+    /// Gets a copy of the user's session (or a fresh one) which may later be saved.
+    pub fn load(&self, user_id: u64) -> Session {
+        self.with_session(user_id, |s| s.clone())
+    }
+
+    /// Saves what was changed in a copy of the session since it was loaded, keeping whatever
+    /// else was changed in the meantime by other requests (which may have been answered while
+    /// this one waited on upstream services).
+    pub fn save_changes(&self, user_id: u64, loaded: &Session, changed: Session) {
+        self.with_session(user_id, |s| s.merge(loaded, changed))
+    }
+
+    /// Whether the store can still be used, which it can't once a thread panicked holding it.
+    pub fn try_check(&self) -> Result<(), String> {
+        self.cache
+            .lock()
+            .map(|_| ())
+            .map_err(|_| "The session store is poisoned".to_owned())
+    }
+
+    pub fn user_ids(&self) -> Vec<u64> {
+        let cache = self.cache.lock().expect(PANIC_POISONED_SESSION_STORE);
+        cache.iter().map(|(user_id, _)| *user_id).collect()
+    }
+
+    pub fn with_session<F, R>(&self, user_id: u64, f: F) -> R
+    where
+        F: FnOnce(&mut Session) -> R,
+    {
+        let mut cache = self.cache.lock().expect(PANIC_POISONED_SESSION_STORE);
+        if !cache.contains_key(&user_id) {
+            cache.insert(user_id, Default::default());
+        }
+
+        // This will always succeed because we inserted above
+        f(cache.get_mut(&user_id).unwrap())
+    }
+}
+
+impl Default for SessionStore {
+    fn default() -> Self {
+        Self {
+            cache: Arc::new(Mutex::new(LruCache::new(LRU_CACHE_SIZE))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn location(name: &str) -> Option<Location> {
+        Some(Location {
+            lat: 0.0,
+            lng: 0.0,
+            name: name.to_owned(),
+        })
+    }
+
+    #[test]
+    fn changes_are_merged_with_the_stored_session() {
+        let sessions = SessionStore::default();
+        let loaded = sessions.load(1);
+
+        // Meanwhile another request opts in and is greeted
+        sessions.with_session(1, |s| {
+            s.notify = true;
+            s.set_last_variant("greeting", 2);
+        });
+
+        let mut changed = loaded.clone();
+        changed.location = location("Paris");
+        changed.set_last_variant("forecast", 1);
+        sessions.save_changes(1, &loaded, changed);
+
+        let session = sessions.load(1);
+        assert!(session.notify);
+        assert!(session.location == location("Paris"));
+        assert_eq!(session.last_variant("greeting"), Some(2));
+        assert_eq!(session.last_variant("forecast"), Some(1));
+    }
+
+    #[test]
+    fn unchanged_fields_are_left_alone() {
+        let sessions = SessionStore::default();
+        sessions.with_session(1, |s| s.name = Some("Ada".to_owned()));
+        let loaded = sessions.load(1);
+        sessions.with_session(1, |s| s.name = Some("Grace".to_owned()));
+
+        sessions.save_changes(1, &loaded, loaded.clone());
+        assert_eq!(sessions.load(1).name, Some("Grace".to_owned()));
+    }
+
+    #[test]
+    fn answered_confirmations_are_forgotten() {
+        let sessions = SessionStore::default();
+        sessions.with_session(1, |s| {
+            s.pending = Some(PendingIntent {
+                intent_name: "searchWeatherForecast".to_owned(),
+                probability: 0.4,
+                slots: vec![],
+            })
+        });
+        let loaded = sessions.load(1);
+
+        let mut changed = loaded.clone();
+        changed.pending = None;
+        sessions.save_changes(1, &loaded, changed);
+        assert!(sessions.load(1).pending.is_none());
+    }
+}
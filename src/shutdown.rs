@@ -0,0 +1,305 @@
+use std::io;
+use std::net::{SocketAddr, TcpListener as StdTcpListener};
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::{Future, Stream};
+
+#[cfg(unix)]
+use net2::unix::UnixTcpBuilderExt;
+use net2::TcpBuilder;
+
+use num_cpus;
+
+use signal_hook::iterator::Signals;
+use signal_hook::{SIGINT, SIGTERM};
+
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::{Core, Interval};
+
+use tokio_proto::BindServer;
+
+use tokio_service::Service;
+
+use cli::get_drain_timeout;
+use logging;
+
+// Panics
+const PANIC_UNACCEPTABLE_HTTP_BINDING: &'static str = "Unacceptable http binding";
+const PANIC_UNAVAILABLE_REACTOR: &'static str = "Unavailable reactor";
+
+// How often workers check whether to stop accepting, and how long a draining worker must go
+// without a request before it is done
+const POLL_MILLIS: u64 = 250;
+
+/// Whether we have been asked to stop, and how many requests are still being answered.
+#[derive(Clone)]
+pub struct Shutdown {
+    drain_timeout: Duration,
+    in_flight: Arc<AtomicUsize>,
+    requests: Arc<AtomicUsize>,
+    stopping: Arc<AtomicBool>,
+}
+
+impl Shutdown {
+    /// Listens for SIGTERM and SIGINT (CTRL + C), either of which starts a graceful stop. A
+    /// second one stops us straight away.
+    pub fn listen() -> Self {
+        let shutdown = Self {
+            drain_timeout: get_drain_timeout(),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            requests: Arc::new(AtomicUsize::new(0)),
+            stopping: Arc::new(AtomicBool::new(false)),
+        };
+
+        match Signals::new(&[SIGINT, SIGTERM]) {
+            Ok(signals) => {
+                let drain_timeout = shutdown.drain_timeout;
+                let stopping = shutdown.stopping.clone();
+                thread::spawn(move || {
+                    for _ in signals.forever() {
+                        if stopping.swap(true, Ordering::SeqCst) {
+                            logging::warn("Stopping without waiting for requests");
+                            process::exit(1);
+                        }
+
+                        logging::info(format!(
+                            "Stopping; waiting up to {} seconds for requests to finish",
+                            drain_timeout.as_secs()
+                        ));
+                    }
+                });
+            }
+            Err(e) => logging::warn(format!(
+                "Unable to listen for SIGTERM, stopping won't be graceful: {}",
+                e
+            )),
+        }
+
+        shutdown
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+
+    /// Waits up to the drain timeout for the background work `pending` counts to be done.
+    pub fn wait_for(&self, what: &str, pending: &AtomicUsize) {
+        let deadline = Instant::now() + self.drain_timeout;
+        while pending.load(Ordering::SeqCst) > 0 {
+            if Instant::now() >= deadline {
+                logging::warn(format!(
+                    "Gave up waiting for {} {} to finish",
+                    pending.load(Ordering::SeqCst),
+                    what
+                ));
+                return;
+            }
+
+            thread::sleep(Duration::from_millis(POLL_MILLIS));
+        }
+    }
+}
+
+/// Tells `serve_until_stopped` that a worker is done when dropped, which it also is when the
+/// worker panics.
+struct Finished(Sender<()>);
+
+impl Drop for Finished {
+    fn drop(&mut self) {
+        self.0.send(()).ok();
+    }
+}
+
+/// Counts the requests a service is answering. A request is in flight from the moment `call`
+/// is made until its response is ready, which may be a while after `call` returns.
+struct InFlight<S> {
+    service: S,
+    shutdown: Shutdown,
+}
+
+impl<S: Service> Service for InFlight<S>
+where
+    S::Future: 'static,
+{
+    type Request = S::Request;
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Box<Future<Item = S::Response, Error = S::Error>>;
+
+    fn call(&self, request: Self::Request) -> Self::Future {
+        self.shutdown.requests.fetch_add(1, Ordering::SeqCst);
+        self.shutdown.in_flight.fetch_add(1, Ordering::SeqCst);
+        let in_flight = self.shutdown.in_flight.clone();
+        Box::new(self.service.call(request).then(move |response| {
+            in_flight.fetch_sub(1, Ordering::SeqCst);
+            response
+        }))
+    }
+}
+
+/// Serves the protocol with a thread per core until we are asked to stop, then gives the
+/// requests already received up to the drain timeout to finish before returning. This is
+/// `TcpServer::serve`, but one which returns.
+pub fn serve_until_stopped<Kind, P, S, F>(
+    binding: SocketAddr,
+    proto: P,
+    new_service: F,
+    shutdown: &Shutdown,
+) where
+    Kind: 'static,
+    P: BindServer<
+        Kind,
+        TcpStream,
+        ServiceRequest = S::Request,
+        ServiceResponse = S::Response,
+        ServiceError = S::Error,
+    >
+        + Send
+        + Sync,
+    S: Service + 'static,
+    S::Future: 'static,
+    F: Fn(SocketAddr) -> S + Send + Sync + 'static,
+{
+    // Every worker's listener is bound here so that a binding we can't have stops us straight
+    // away, rather than leaving us running with workers missing
+    let threads = num_cpus::get();
+    let listeners: Vec<_> = (0..threads)
+        .map(|_| try_bind(&binding).expect(PANIC_UNACCEPTABLE_HTTP_BINDING))
+        .collect();
+
+    let proto = Arc::new(proto);
+    let new_service = Arc::new(new_service);
+    let (finished, workers) = channel();
+    for listener in listeners {
+        let finished = Finished(finished.clone());
+        let new_service = new_service.clone();
+        let proto = proto.clone();
+        let shutdown = shutdown.clone();
+        thread::spawn(move || {
+            serve_worker(
+                binding,
+                listener,
+                proto.as_ref(),
+                new_service.as_ref(),
+                &shutdown,
+            );
+            drop(finished);
+        });
+    }
+
+    while !shutdown.is_stopping() {
+        // Sanity check: A worker only finishes early if it died, and we won't limp on without it
+        if workers.try_recv().is_ok() {
+            logging::error("A web server worker stopped unexpectedly");
+            process::exit(1);
+        }
+
+        thread::sleep(Duration::from_millis(POLL_MILLIS));
+    }
+
+    // A worker whose requests are still waiting (on Dark Sky, say) is abandoned at the deadline
+    let deadline = Instant::now() + shutdown.drain_timeout;
+    for _ in 0..threads {
+        let now = Instant::now();
+        if now >= deadline || workers.recv_timeout(deadline - now).is_err() {
+            logging::warn(format!(
+                "Gave up waiting for {} requests to finish",
+                shutdown.in_flight()
+            ));
+            return;
+        }
+    }
+}
+
+fn serve_worker<Kind, P, S, F>(
+    binding: SocketAddr,
+    listener: StdTcpListener,
+    proto: &P,
+    new_service: &F,
+    shutdown: &Shutdown,
+) where
+    Kind: 'static,
+    P: BindServer<
+        Kind,
+        TcpStream,
+        ServiceRequest = S::Request,
+        ServiceResponse = S::Response,
+        ServiceError = S::Error,
+    >,
+    S: Service + 'static,
+    S::Future: 'static,
+    F: Fn(SocketAddr) -> S,
+{
+    let mut core = Core::new().expect(PANIC_UNAVAILABLE_REACTOR);
+    let handle = core.handle();
+
+    // Accept connections until we are asked to stop; the listener is closed once this is done
+    {
+        let listener = TcpListener::from_listener(listener, &binding, &handle)
+            .expect(PANIC_UNAVAILABLE_REACTOR);
+        let accepting = listener.incoming().for_each(|(socket, peer)| {
+            let service = InFlight {
+                service: new_service(peer),
+                shutdown: shutdown.clone(),
+            };
+            proto.bind_server(&handle, socket, service);
+            Ok(())
+        });
+        let stopped = Interval::new(Duration::from_millis(POLL_MILLIS), &handle)
+            .expect(PANIC_UNAVAILABLE_REACTOR)
+            .take_while(|_| Ok(!shutdown.is_stopping()))
+            .for_each(|_| Ok(()));
+        if let Err((e, _)) = core.run(accepting.select(stopped)) {
+            logging::error(format!("Unable to accept connections: {}", e));
+        }
+    }
+
+    // The connections we already have may still be sending requests or waiting on responses;
+    // keep serving them until things go quiet or we run out of time
+    let deadline = Instant::now() + shutdown.drain_timeout;
+    let quiet = Duration::from_millis(POLL_MILLIS);
+    let mut requests = shutdown.requests.load(Ordering::SeqCst);
+    let mut quiet_since = Instant::now();
+    while Instant::now() < deadline && quiet_since.elapsed() < quiet {
+        core.turn(Some(quiet));
+
+        let latest = shutdown.requests.load(Ordering::SeqCst);
+        if latest != requests || shutdown.in_flight() > 0 {
+            requests = latest;
+            quiet_since = Instant::now();
+        }
+    }
+}
+
+/// Binds a listener which shares the port with the other workers' (like `TcpServer` does).
+/// Each worker hands its own to its reactor.
+fn try_bind(binding: &SocketAddr) -> io::Result<StdTcpListener> {
+    let builder = match *binding {
+        SocketAddr::V4(_) => TcpBuilder::new_v4()?,
+        SocketAddr::V6(_) => TcpBuilder::new_v6()?,
+    };
+    builder.reuse_address(true)?;
+    try_reuse_port(&builder)?;
+    builder.bind(binding)?;
+
+    builder.listen(1024)
+}
+
+#[cfg(unix)]
+fn try_reuse_port(builder: &TcpBuilder) -> io::Result<()> {
+    builder.reuse_port(true).map(|_| ())
+}
+
+#[cfg(not(unix))]
+fn try_reuse_port(_builder: &TcpBuilder) -> io::Result<()> {
+    Ok(())
+}
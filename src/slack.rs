@@ -0,0 +1,483 @@
+use std::str;
+use std::sync::Mutex;
+
+use chrono::Utc;
+
+use json::{self, JsonValue};
+
+use lru_cache::LruCache;
+
+use reqwest::header::{ContentType, Headers};
+use reqwest::Client;
+
+use tokio_minihttp::Request;
+
+use auth::constant_time_eq;
+use channel::{to_user_id, Channel, Inbound};
+use cli::{get_slack_bot_token, get_slack_signing_secret};
+use intent::Reply;
+use web::{try_get_header, try_get_mime_type, try_get_urlencoded_fields, ApiError,
+          MIME_TYPE_APPLICATION_JSON, MIME_TYPE_APPLICATION_X_WWW_FORM_URLENCODED};
+use webhook::hmac_sha256_hex;
+
+// Panics
+const PANIC_POISONED_SEEN_EVENTS: &'static str = "Poisoned seen events";
+
+// Headers
+const HEADER_AUTHORIZATION: &'static str = "Authorization";
+const HEADER_SLACK_REQUEST_TIMESTAMP: &'static str = "X-Slack-Request-Timestamp";
+const HEADER_SLACK_SIGNATURE: &'static str = "X-Slack-Signature";
+
+// Slash command fields
+const FORM_DATA_RESPONSE_URL: &'static str = "response_url";
+const FORM_DATA_TEAM_ID: &'static str = "team_id";
+const FORM_DATA_TEXT: &'static str = "text";
+const FORM_DATA_USER_ID: &'static str = "user_id";
+
+// Requests signed longer ago than this might be replays
+const MAX_REQUEST_AGE_SECS: i64 = 300;
+
+// How many recent event ids are remembered; Slack gives up retrying within the hour, and we
+// don't expect this many events in that time
+const MAX_SEEN_EVENTS: usize = 4_096;
+
+const API_URL: &'static str = "https://slack.com/api";
+const SIGNATURE_VERSION: &'static str = "v0";
+
+/// What Slack wants from us.
+pub enum SlackRequest {
+    /// The Events API checking the request URL; the challenge is echoed back
+    Challenge(String),
+    /// A slash command, which is acknowledged straight away and answered at its response_url
+    Command {
+        response_url: String,
+        text: String,
+        user_id: u64,
+    },
+    /// An Events API callback, whose messages are answered over the channel
+    Events(JsonValue),
+    /// Anything we don't answer, such as events we already have
+    Ignored,
+}
+
+lazy_static! {
+    // Slack delivers an event again if it doesn't hear from us quickly enough, and the retry
+    // may arrive on any connection
+    static ref SEEN_EVENTS: Mutex<LruCache<String, ()>> =
+        Mutex::new(LruCache::new(MAX_SEEN_EVENTS));
+}
+
+/// A Slack app. Slash commands and the Events API both post to /slack; direct messages and
+/// mentions are answered with chat.postMessage, in the thread they were sent in if any.
+pub struct SlackChannel {
+    api_url: String,
+    bot_token: Option<String>,
+    client: Client,
+    signing_secret: String,
+}
+
+impl SlackChannel {
+    pub fn try_new() -> Option<Self> {
+        get_slack_signing_secret().map(|signing_secret| Self {
+            api_url: API_URL.to_owned(),
+            bot_token: get_slack_bot_token(),
+            client: Client::new(),
+            signing_secret: signing_secret,
+        })
+    }
+
+    pub fn try_parse(&self, request: &Request) -> Result<SlackRequest, ApiError> {
+        // Sanity check: It must really be from Slack
+        self.verify(request)?;
+
+        // The Events API posts json; slash commands are form posts
+        match try_get_mime_type(request) {
+            Some(ref m) if m == MIME_TYPE_APPLICATION_JSON => try_parse_event(request.body()),
+            Some(ref m) if m == MIME_TYPE_APPLICATION_X_WWW_FORM_URLENCODED => {
+                let fields = try_get_urlencoded_fields(request)?;
+                let team_id = fields.get(FORM_DATA_TEAM_ID).unwrap_or("");
+                let user_id = fields.require(FORM_DATA_USER_ID)?;
+                Ok(SlackRequest::Command {
+                    response_url: fields.require(FORM_DATA_RESPONSE_URL)?.to_owned(),
+                    text: fields.get(FORM_DATA_TEXT).unwrap_or("").to_owned(),
+                    user_id: to_user_id(&["slack", team_id, user_id]),
+                })
+            }
+            _ => Err(ApiError::unsupported_content_type()),
+        }
+    }
+
+    /// Answers a slash command at the response_url Slack gave with it; these take no token.
+    pub fn respond(&self, response_url: &str, reply: Reply) -> Result<(), String> {
+        let mut headers = Headers::new();
+        headers.set(ContentType::json());
+        let response = self.client
+            .post(response_url)
+            .headers(headers)
+            .body(reply_to_slack(reply).dump())
+            .send()
+            .map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("Slack responded {}", response.status()));
+        }
+
+        Ok(())
+    }
+
+    fn verify(&self, request: &Request) -> Result<(), ApiError> {
+        let timestamp = try_get_header(request, HEADER_SLACK_REQUEST_TIMESTAMP);
+        let signature = try_get_header(request, HEADER_SLACK_SIGNATURE);
+        verify_signature(
+            &self.signing_secret,
+            timestamp.as_ref().map(|t| t.as_str()),
+            signature.as_ref().map(|s| s.as_str()),
+            request.body(),
+            Utc::now().timestamp(),
+        )
+    }
+}
+
+impl Channel for SlackChannel {
+    fn name(&self) -> &'static str {
+        "slack"
+    }
+
+    fn accepts(&self, request: &Request) -> bool {
+        self.verify(request).is_ok()
+    }
+
+    fn parse(&self, updates: &JsonValue) -> Vec<Inbound> {
+        try_parse_message(updates).into_iter().collect()
+    }
+
+    fn send(&self, conversation: &str, reply: &Reply) -> Result<(), String> {
+        // Sanity check: We can only post with a bot token
+        let bot_token = self.bot_token
+            .as_ref()
+            .ok_or_else(|| "There is no bot token to answer Slack with".to_owned())?;
+
+        // Threaded conversations are "{channel}:{thread_ts}"
+        let mut parts = conversation.splitn(2, ':');
+        let body = object!{
+            "channel" => parts.next(),
+            "thread_ts" => parts.next(),
+            "text" => reply.text.as_str(),
+        };
+
+        let mut headers = Headers::new();
+        headers.set(ContentType::json());
+        headers.set_raw(HEADER_AUTHORIZATION, format!("Bearer {}", bot_token));
+        let mut response = self.client
+            .post(format!("{}/chat.postMessage", &self.api_url).as_str())
+            .headers(headers)
+            .body(body.dump())
+            .send()
+            .map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("Slack responded {}", response.status()));
+        }
+
+        // Sanity check: Slack refuses with a 200 and says why in the body
+        let text = response.text().map_err(|e| e.to_string())?;
+        let answer = json::parse(&text).map_err(|e| e.to_string())?;
+        if answer["ok"].as_bool() != Some(true) {
+            return Err(format!(
+                "Slack refused: {}",
+                answer["error"].as_str().unwrap_or("unknown")
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Forgets we had the event, so Slack's next delivery of it is answered; for when we were
+/// unable to answer this one.
+pub fn forget_delivery(event_id: Option<&str>) {
+    if let Some(event_id) = event_id {
+        let mut seen = SEEN_EVENTS.lock().expect(PANIC_POISONED_SEEN_EVENTS);
+        seen.remove(event_id);
+    }
+}
+
+/// Produces a Slack message; slash command answers are shown to the whole channel.
+fn reply_to_slack(reply: Reply) -> JsonValue {
+    object!{
+        "response_type" => "in_channel",
+        "text" => reply.text,
+    }
+}
+
+/// Whether this is the first we've heard of the event, remembering it if so.
+fn is_first_delivery(event_id: Option<&str>) -> bool {
+    // Sanity check: Events always have an id, but we can't tell one without from another
+    let event_id = match event_id {
+        Some(event_id) => event_id,
+        None => return true,
+    };
+
+    let mut seen = SEEN_EVENTS.lock().expect(PANIC_POISONED_SEEN_EVENTS);
+    if seen.contains_key(event_id) {
+        return false;
+    }
+
+    seen.insert(event_id.to_owned(), ());
+    true
+}
+
+/// Drops the "<@U0123ABCD>" mentions of us (or anyone) from the message text.
+fn strip_mentions(text: &str) -> String {
+    let mut stripped = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("<@") {
+        stripped.push_str(&rest[..start]);
+        rest = match rest[start..].find('>') {
+            Some(end) => &rest[start + end + 1..],
+            None => "",
+        };
+    }
+    stripped.push_str(rest);
+
+    stripped.trim().to_owned()
+}
+
+fn try_parse_event(body: &[u8]) -> Result<SlackRequest, ApiError> {
+    // Sanity check: Must be a utf8 json object
+    let body =
+        str::from_utf8(body).map_err(|_| ApiError::malformed_body("The body must be utf8"))?;
+    let body = json::parse(body).map_err(|e| ApiError::malformed_body(&e.to_string()))?;
+
+    match body["type"].as_str() {
+        Some("url_verification") => Ok(SlackRequest::Challenge(
+            body["challenge"].as_str().unwrap_or("").to_owned(),
+        )),
+        Some("event_callback") if is_first_delivery(body["event_id"].as_str()) => {
+            Ok(SlackRequest::Events(body))
+        }
+        _ => Ok(SlackRequest::Ignored),
+    }
+}
+
+fn try_parse_message(callback: &JsonValue) -> Option<Inbound> {
+    let event = &callback["event"];
+
+    // Sanity check: We don't answer bots, edits or deletions, which all arrive with a bot_id or
+    // subtype; this also stops Harris talking to himself
+    if !event["bot_id"].is_null() || !event["subtype"].is_null() {
+        return None;
+    }
+
+    // Channel messages reach us as mentions; only direct messages are read in full
+    let is_for_us = match event["type"].as_str() {
+        Some("app_mention") => true,
+        Some("message") => event["channel_type"].as_str() == Some("im"),
+        _ => false,
+    };
+    if !is_for_us {
+        return None;
+    }
+
+    let user = event["user"].as_str()?;
+    let channel = event["channel"].as_str()?;
+    let text = event["text"].as_str()?;
+    Some(Inbound {
+        conversation: match event["thread_ts"].as_str() {
+            Some(thread_ts) => format!("{}:{}", channel, thread_ts),
+            None => channel.to_owned(),
+        },
+        text: strip_mentions(text),
+        user_id: to_user_id(&["slack", callback["team_id"].as_str().unwrap_or(""), user]),
+    })
+}
+
+/// Checks the v0 signature: the HMAC-SHA256 of "v0:{timestamp}:{body}" using our signing
+/// secret. See https://api.slack.com/docs/verifying-requests-from-slack
+fn verify_signature(
+    signing_secret: &str,
+    timestamp: Option<&str>,
+    signature: Option<&str>,
+    body: &[u8],
+    now: i64,
+) -> Result<(), ApiError> {
+    // Sanity check: Must be signed, recently
+    let timestamp = timestamp.and_then(|t| t.trim().parse::<i64>().ok());
+    if timestamp.is_none() || signature.is_none() {
+        return Err(ApiError::invalid_signature("The request is not signed"));
+    }
+
+    let timestamp = timestamp.unwrap();
+    if (now - timestamp).abs() > MAX_REQUEST_AGE_SECS {
+        return Err(ApiError::invalid_signature("The request is too old"));
+    }
+
+    let mut message = format!("{}:{}:", SIGNATURE_VERSION, timestamp).into_bytes();
+    message.extend_from_slice(body);
+    let expected = format!(
+        "{}={}",
+        SIGNATURE_VERSION,
+        hmac_sha256_hex(signing_secret, &message)
+    );
+    if !constant_time_eq(expected.as_bytes(), signature.unwrap().trim().as_bytes()) {
+        return Err(ApiError::invalid_signature("The request signature does not match"));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use fake_server::FakeServer;
+
+    use super::*;
+
+    const BODY: &'static [u8] = b"{\"type\":\"event_callback\"}";
+
+    fn callback(event: JsonValue) -> JsonValue {
+        object!{
+            "type" => "event_callback",
+            "team_id" => "T1",
+            "event" => event,
+        }
+    }
+
+    fn sign(timestamp: i64, body: &[u8]) -> String {
+        let mut message = format!("v0:{}:", timestamp).into_bytes();
+        message.extend_from_slice(body);
+        format!("v0={}", hmac_sha256_hex("secret", &message))
+    }
+
+    fn verify(timestamp: Option<&str>, signature: Option<&str>, now: i64) -> bool {
+        verify_signature("secret", timestamp, signature, BODY, now).is_ok()
+    }
+
+    fn slack(api_url: &str, bot_token: Option<&str>) -> SlackChannel {
+        SlackChannel {
+            api_url: api_url.to_owned(),
+            bot_token: bot_token.map(|t| t.to_owned()),
+            client: Client::new(),
+            signing_secret: "secret".to_owned(),
+        }
+    }
+
+    #[test]
+    fn signed_requests_are_verified() {
+        let signature = sign(1_000, BODY);
+        assert!(verify(Some("1000"), Some(signature.as_str()), 1_000));
+        assert!(verify(Some("1000"), Some(signature.as_str()), 1_300));
+        assert!(!verify(Some("1000"), Some(signature.as_str()), 1_301));
+        assert!(!verify(Some("1001"), Some(signature.as_str()), 1_001));
+        assert!(!verify(Some("1000"), Some("v0=00"), 1_000));
+        assert!(!verify(None, Some(signature.as_str()), 1_000));
+        assert!(!verify(Some("1000"), None, 1_000));
+    }
+
+    #[test]
+    fn challenge_is_echoed() {
+        let body = b"{\"type\":\"url_verification\",\"challenge\":\"abc\"}";
+        match try_parse_event(body) {
+            Ok(SlackRequest::Challenge(challenge)) => assert_eq!(challenge, "abc"),
+            _ => panic!("Expected a challenge"),
+        }
+    }
+
+    #[test]
+    fn events_are_answered_once() {
+        let body = b"{\"type\":\"event_callback\",\"event_id\":\"Ev-once\"}";
+        match try_parse_event(body) {
+            Ok(SlackRequest::Events(_)) => (),
+            _ => panic!("Expected the events"),
+        }
+        match try_parse_event(body) {
+            Ok(SlackRequest::Ignored) => (),
+            _ => panic!("Expected the retry to be ignored"),
+        }
+    }
+
+    #[test]
+    fn forgotten_events_are_answered_again() {
+        let body = b"{\"type\":\"event_callback\",\"event_id\":\"Ev-forgotten\"}";
+        assert!(is_first_delivery(Some("Ev-forgotten")));
+        forget_delivery(Some("Ev-forgotten"));
+        match try_parse_event(body) {
+            Ok(SlackRequest::Events(_)) => (),
+            _ => panic!("Expected the retry to be answered"),
+        }
+    }
+
+    #[test]
+    fn mentions_are_answered_in_their_thread() {
+        let inbound = slack("unused", None).parse(&callback(object!{
+            "type" => "app_mention",
+            "channel" => "C1",
+            "text" => "<@U0HARRIS> Is it raining?",
+            "thread_ts" => "1.2",
+            "user" => "U1",
+        }));
+        assert_eq!(inbound.len(), 1);
+        assert_eq!(inbound[0].conversation, "C1:1.2");
+        assert_eq!(inbound[0].text, "Is it raining?");
+        assert_eq!(inbound[0].user_id, to_user_id(&["slack", "T1", "U1"]));
+    }
+
+    #[test]
+    fn only_direct_messages_from_people_are_answered() {
+        let message = |channel_type, bot_id: Option<&str>| {
+            callback(object!{
+                "type" => "message",
+                "bot_id" => bot_id,
+                "channel" => "D1",
+                "channel_type" => channel_type,
+                "text" => "Hi",
+                "user" => "U1",
+            })
+        };
+        let slack = slack("unused", None);
+        assert_eq!(slack.parse(&message("im", None))[0].conversation, "D1");
+        assert!(slack.parse(&message("channel", None)).is_empty());
+        assert!(slack.parse(&message("im", Some("B1"))).is_empty());
+    }
+
+    #[test]
+    fn replies_are_posted_to_the_thread() {
+        let server = FakeServer::start("200 OK", "{\"ok\":true}");
+        let slack = slack(&server.url, Some("xoxb-1"));
+        assert!(slack.send("C1:1.2", &Reply::text("Hello")).is_ok());
+
+        let request = server.next_request();
+        assert_eq!(request.path, "/chat.postMessage");
+        assert_eq!(request.header("Authorization"), Some("Bearer xoxb-1"));
+        let body = json::parse(&request.body).unwrap();
+        assert_eq!(body["channel"].as_str(), Some("C1"));
+        assert_eq!(body["thread_ts"].as_str(), Some("1.2"));
+        assert_eq!(body["text"].as_str(), Some("Hello"));
+    }
+
+    #[test]
+    fn refused_replies_fail() {
+        let server = FakeServer::start("200 OK", "{\"ok\":false,\"error\":\"not_in_channel\"}");
+        let error = slack(&server.url, Some("xoxb-1"))
+            .send("C1", &Reply::text("Hello"))
+            .unwrap_err();
+        assert!(error.contains("not_in_channel"));
+        assert!(slack("unused", None).send("C1", &Reply::text("Hello")).is_err());
+    }
+
+    #[test]
+    fn commands_are_answered_at_the_response_url() {
+        let server = FakeServer::start("200 OK", "ok");
+        let response_url = format!("{}/commands/1", &server.url);
+        assert!(slack("unused", None).respond(&response_url, Reply::text("Hello")).is_ok());
+
+        let request = server.next_request();
+        assert_eq!(request.path, "/commands/1");
+        let body = json::parse(&request.body).unwrap();
+        assert_eq!(body["response_type"].as_str(), Some("in_channel"));
+        assert_eq!(body["text"].as_str(), Some("Hello"));
+    }
+
+    #[test]
+    fn mentions_are_stripped() {
+        assert_eq!(strip_mentions("<@U1> hi <@U2>"), "hi");
+        assert_eq!(strip_mentions("hi <@U1"), "hi");
+    }
+}
@@ -0,0 +1,38 @@
+use snips_nlu_ontology::Slot;
+
+use intent::{Context, IntentHandler, Reply, ReplyFuture};
+use phrases::{self, Pool};
+use weather::CONDITION_KEYWORDS;
+
+/// Lists what Harris can be asked about, including the condition keywords he understands.
+pub struct HelpHandler;
+
+impl IntentHandler for HelpHandler {
+    fn respond(&self, ctx: &Context, _slots: &[Slot]) -> ReplyFuture {
+        let conditions = CONDITION_KEYWORDS.join(", ");
+        Reply::text(ctx.say(&phrases::HELP, &[("conditions", conditions.as_str())])).ready()
+    }
+}
+
+/// Answers pleasantries (hello, thanks, goodbye) with a line from the given pool. The user's
+/// name is available to the pool if they joined with one.
+pub struct SmallTalkHandler {
+    pool: &'static Pool,
+}
+
+impl SmallTalkHandler {
+    pub fn new(pool: &'static Pool) -> Self {
+        Self { pool: pool }
+    }
+}
+
+impl IntentHandler for SmallTalkHandler {
+    fn respond(&self, ctx: &Context, _slots: &[Slot]) -> ReplyFuture {
+        let name = ctx.session
+            .borrow()
+            .name
+            .clone()
+            .unwrap_or_else(|| "friend".to_owned());
+        Reply::text(ctx.say(self.pool, &[("name", name.as_str())])).ready()
+    }
+}
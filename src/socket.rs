@@ -0,0 +1,617 @@
+use std::cell::Cell;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, TcpListener, TcpStream};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::sync::oneshot::{channel, Sender};
+use futures::{Future, Stream};
+
+use num_cpus;
+
+use tokio_core::reactor::Core;
+
+use tungstenite::{accept, Message as Frame, WebSocket};
+
+use urlencoding::decode;
+
+use auth::{try_get_bearer_token, Authenticator, Principal};
+use cli::get_ws_max_connections;
+use cors::{CorsPolicy, HEADER_ORIGIN};
+use harris::{Event, Harris};
+use intent::Reply;
+use logging::{self, to_millis, Level, RequestLog};
+use notify::Hub;
+use rate_limit::RateLimits;
+use session::SessionStore;
+use subscription::SubscriptionStore;
+use web::{authorize_fields, authorize_user_id, reply_to_json, to_retry_after_secs, try_parse_id,
+          ApiError, Fields, FORM_DATA_USER_ID};
+
+// Panics
+const PANIC_POISONED_RESPONDERS: &'static str = "Poisoned responders";
+const PANIC_UNACCEPTABLE_WS_BINDING: &'static str = "Unacceptable websocket binding";
+const PANIC_UNAVAILABLE_REACTOR: &'static str = "Unavailable reactor";
+
+// Headers
+const HEADER_AUTHORIZATION: &'static str = "Authorization";
+const HEADER_X_API_KEY: &'static str = "X-Api-Key";
+const HEADER_X_FORWARDED_FOR: &'static str = "X-Forwarded-For";
+const HEADER_X_REAL_IP: &'static str = "X-Real-IP";
+
+// Browsers can't set headers on a websocket or an EventSource, so they may put the credentials
+// in the query string instead
+const QUERY_ACCESS_TOKEN: &'static str = "access_token";
+
+// Routes
+const ROUTE_EVENTS: &'static str = "/events/";
+
+// What answered frames are logged as, as they have no route of their own
+const ROUTE_LABEL_WEBSOCKET: &'static str = "websocket";
+
+// How long an event stream may sit idle before we send a comment to keep it open
+const EVENTS_KEEP_ALIVE_SECS: u64 = 15;
+
+// The request line and headers must arrive within this many bytes and this long
+const MAX_HEAD_BYTES: usize = 8_192;
+const HEAD_TIMEOUT_SECS: u64 = 10;
+
+// How long to wait for more of the head after peeking at a piece of it
+const HEAD_PEEK_INTERVAL_MILLIS: u64 = 10;
+
+// A websocket message may take no more than this to arrive, frames and all; chat messages are
+// a few hundred bytes
+const MAX_MESSAGE_BYTES: usize = 65_536;
+
+// An event to answer, the log of the frame it came in and where the reply (and log) go
+type Question = (Event, RequestLog, Sender<(Reply, RequestLog)>);
+
+/// Counts an open connection until it is dropped, which it also is when the connection's thread
+/// panics.
+struct Connection(Arc<AtomicUsize>);
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A websocket's stream, which refuses to read on once a message has taken too many bytes.
+/// tungstenite 0.5 has no size limits of its own, so it would hold a message however big it
+/// was in memory.
+struct Limited<S> {
+    /// Bytes read since the last whole message, which the connection resets
+    read: Rc<Cell<usize>>,
+    stream: S,
+}
+
+impl<S: Read> Read for Limited<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Sanity check: Nobody gets to fill our memory with one endless message
+        if self.read.get() > MAX_MESSAGE_BYTES {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Message too big"));
+        }
+
+        let len = self.stream.read(buf)?;
+        self.read.set(self.read.get() + len);
+        Ok(len)
+    }
+}
+
+impl<S: Write> Write for Limited<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// The threads which answer websocket messages. Harris can't be shared between threads and
+/// loading his nlu engine is slow, so rather than one per connection there is one per
+/// responder; each answers many messages at once on an event loop of its own, as most of the
+/// time is spent waiting on upstream services.
+struct Responders {
+    next: AtomicUsize,
+    senders: Vec<Mutex<UnboundedSender<Question>>>,
+}
+
+impl Responders {
+    fn start(sessions: SessionStore, subscriptions: SubscriptionStore) -> Self {
+        let senders = (0..num_cpus::get())
+            .map(|_| {
+                let (sender, receiver) = unbounded();
+                let sessions = sessions.clone();
+                let subscriptions = subscriptions.clone();
+                thread::spawn(move || respond_forever(receiver, sessions, subscriptions));
+                Mutex::new(sender)
+            })
+            .collect();
+
+        Self {
+            next: AtomicUsize::new(0),
+            senders: senders,
+        }
+    }
+
+    /// Asks the responders in turn, waiting for the reply; `None` if the responder is gone.
+    fn ask(&self, event: Event, log: RequestLog) -> Option<(Reply, RequestLog)> {
+        let (answer, answered) = channel();
+        let next = self.next.fetch_add(1, Ordering::Relaxed) % self.senders.len();
+        {
+            let sender = self.senders[next].lock().expect(PANIC_POISONED_RESPONDERS);
+            sender.unbounded_send((event, log, answer)).ok()?;
+        }
+
+        answered.wait().ok()
+    }
+}
+
+/// The request line and headers, peeked at without taking them off the stream so that the
+/// websocket handshake can still read them.
+struct Head {
+    headers: Vec<(String, String)>,
+    path: String,
+    query: String,
+}
+
+impl Head {
+    fn try_peek(stream: &TcpStream) -> Option<Self> {
+        // Sanity check: Nobody gets to hold a thread open by never finishing their request
+        stream
+            .set_read_timeout(Some(Duration::from_secs(HEAD_TIMEOUT_SECS)))
+            .ok()?;
+
+        // The head may arrive in pieces, so keep peeking until we have all of it
+        let mut buf = vec![0; MAX_HEAD_BYTES];
+        let max_peeks = HEAD_TIMEOUT_SECS * 1_000 / HEAD_PEEK_INTERVAL_MILLIS;
+        for _ in 0..max_peeks {
+            let len = stream.peek(&mut buf).ok()?;
+            let head = String::from_utf8_lossy(&buf[..len]).into_owned();
+            if let Some(end) = head.find("\r\n\r\n") {
+                stream.set_read_timeout(None).ok()?;
+                return Some(Self::parse(&head[..end]));
+            }
+
+            // Sanity check: The client went away, or sent more head than we'll read
+            if len == 0 || len == buf.len() {
+                return None;
+            }
+
+            thread::sleep(Duration::from_millis(HEAD_PEEK_INTERVAL_MILLIS));
+        }
+
+        None
+    }
+
+    fn parse(head: &str) -> Self {
+        let mut lines = head.split("\r\n");
+        let target = lines
+            .next()
+            .and_then(|l| l.split_whitespace().nth(1))
+            .unwrap_or("");
+        let mut target = target.splitn(2, '?');
+        let path = target.next().unwrap_or("").to_owned();
+        let query = target.next().unwrap_or("").to_owned();
+
+        let headers = lines
+            .filter_map(|line| {
+                let mut parts = line.splitn(2, ':');
+                match (parts.next(), parts.next()) {
+                    (Some(key), Some(val)) => Some((key.trim().to_owned(), val.trim().to_owned())),
+                    _ => None,
+                }
+            })
+            .collect();
+
+        Self {
+            headers: headers,
+            path: path,
+            query: query,
+        }
+    }
+
+    fn try_get_credentials(&self) -> Option<String> {
+        if let Some(api_key) = self.try_get_header(HEADER_X_API_KEY) {
+            return Some(api_key.to_owned());
+        }
+
+        if let Some(token) = self.try_get_header(HEADER_AUTHORIZATION)
+            .and_then(try_get_bearer_token)
+        {
+            return Some(token);
+        }
+
+        self.try_get_query(QUERY_ACCESS_TOKEN)
+    }
+
+    fn try_get_header(&self, key: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|&&(ref k, _)| k.eq_ignore_ascii_case(key))
+            .map(|&(_, ref v)| v.as_str())
+    }
+
+    fn try_get_query(&self, key: &str) -> Option<String> {
+        self.query
+            .split('&')
+            .filter_map(|pair| {
+                let mut parts = pair.splitn(2, '=');
+                match (parts.next(), parts.next()) {
+                    (Some(k), Some(v)) if k == key => decode(&v.replace('+', " ")).ok(),
+                    _ => None,
+                }
+            })
+            .next()
+    }
+}
+
+/// Serves the long-lived connections: chat over websockets, and server-sent events carrying
+/// proactive messages at /events/{user_id}.
+pub fn serve_forever(
+    ws_binding: &str,
+    sessions: SessionStore,
+    subscriptions: SubscriptionStore,
+    hub: Hub,
+    cors: CorsPolicy,
+    rate_limits: RateLimits,
+) {
+    let auth = Arc::new(Authenticator::default());
+    let connections = Arc::new(AtomicUsize::new(0));
+    let max_connections = get_ws_max_connections();
+    let responders = Arc::new(Responders::start(sessions, subscriptions));
+    let listener = TcpListener::bind(ws_binding).expect(PANIC_UNACCEPTABLE_WS_BINDING);
+    for stream in listener.incoming() {
+        if let Ok(mut stream) = stream {
+            // Sanity check: Every connection holds a thread, so only so many are taken at once
+            if connections.load(Ordering::SeqCst) >= max_connections {
+                let e = ApiError::unavailable("Too many connections; try again later");
+                write_error(&mut stream, &e);
+                continue;
+            }
+
+            connections.fetch_add(1, Ordering::SeqCst);
+            let connection = Connection(connections.clone());
+            let auth = auth.clone();
+            let cors = cors.clone();
+            let hub = hub.clone();
+            let rate_limits = rate_limits.clone();
+            let responders = responders.clone();
+            thread::spawn(move || {
+                let _connection = connection;
+
+                // Peek at the head to decide which kind of connection this is, and whether to
+                // take it at all, before the handshake reads it
+                let head = match Head::try_peek(&stream) {
+                    Some(head) => head,
+                    None => return,
+                };
+
+                // Sanity check: Connecting counts as a request, as far as the limits go
+                let ip = match stream.peer_addr() {
+                    Ok(peer) => rate_limits.client_ip(
+                        peer.ip(),
+                        head.try_get_header(HEADER_X_REAL_IP),
+                        head.try_get_header(HEADER_X_FORWARDED_FOR),
+                    ),
+                    Err(_) => return,
+                };
+                if let Err(retry_after) = rate_limits.try_take_request(ip) {
+                    let e = ApiError::rate_limited(to_retry_after_secs(retry_after));
+                    write_error(&mut stream, &e);
+                    return;
+                }
+
+                // Sanity check: With authentication on, both kinds of connection need it
+                let principal = match try_authenticate(&auth, &head) {
+                    Ok(principal) => principal,
+                    Err(e) => {
+                        write_error(&mut stream, &e);
+                        return;
+                    }
+                };
+
+                if head.path.starts_with(ROUTE_EVENTS) {
+                    serve_events(stream, &head, hub, &cors, principal);
+                    return;
+                }
+
+                // Sanity check: Browsers don't stop other sites opening websockets to us, so we
+                // must; they always say where the page came from
+                if let Some(origin) = head.try_get_header(HEADER_ORIGIN) {
+                    if !cors.allows_origin(origin) {
+                        let message = format!("Origin {} is not allowed", origin);
+                        write_error(&mut stream, &ApiError::cors_rejected(&message));
+                        return;
+                    }
+                }
+
+                let read = Rc::new(Cell::new(0));
+                let stream = Limited {
+                    read: read.clone(),
+                    stream: stream,
+                };
+                if let Ok(socket) = accept(stream) {
+                    serve_connection(socket, &read, &responders, principal, rate_limits, ip);
+                }
+            });
+        }
+    }
+}
+
+/// Answers messages as they come, until the server is gone.
+fn respond_forever(
+    receiver: UnboundedReceiver<Question>,
+    sessions: SessionStore,
+    subscriptions: SubscriptionStore,
+) {
+    let mut core = Core::new().expect(PANIC_UNAVAILABLE_REACTOR);
+    let handle = core.handle();
+    let harris = Harris::new(sessions, subscriptions);
+    let responding = receiver.for_each(|(event, mut log, answer)| {
+        // The connection may have gone while we thought about it
+        let reply = log.enter(|| harris.respond(&event));
+        handle.spawn(log.around(reply).map(move |answered| {
+            answer.send(answered).ok();
+        }));
+
+        Ok(())
+    });
+
+    // This only ends once the server is gone
+    core.run(responding).ok();
+}
+
+fn serve_connection(
+    mut socket: WebSocket<Limited<TcpStream>>,
+    read: &Cell<usize>,
+    responders: &Responders,
+    principal: Option<Principal>,
+    rate_limits: RateLimits,
+    ip: IpAddr,
+) {
+    let mut user_id = None;
+
+    loop {
+        let message = socket.read_message();
+
+        // Whatever arrived, the next message is counted from nothing
+        read.set(0);
+        let text = match message {
+            Ok(Frame::Text(text)) => text,
+            Ok(Frame::Close(_)) | Err(_) => return,
+            Ok(_) => continue,
+        };
+        let started = Instant::now();
+
+        // Remember who is on the other end so later frames don't have to say
+        let mut fields = match Fields::try_from_json(text.as_bytes()) {
+            Ok(fields) => fields,
+            Err(e) => {
+                if socket.write_message(Frame::Text(e.to_json().dump())).is_err() {
+                    return;
+                }
+
+                continue;
+            }
+        };
+        let frame_user_id = fields.get(FORM_DATA_USER_ID).map(|u| u.to_owned());
+        match frame_user_id {
+            Some(u) => user_id = Some(u),
+            None => if let Some(ref u) = user_id {
+                fields.set(FORM_DATA_USER_ID, u.as_str());
+            },
+        }
+
+        // Sanity check: Frames may only speak for whoever the connection authenticated as
+        if let Err(e) = authorize_fields(principal.as_ref(), &mut fields) {
+            if socket.write_message(Frame::Text(e.to_json().dump())).is_err() {
+                return;
+            }
+
+            continue;
+        }
+
+        // Sanity check: Each frame counts against the limits just as a request would
+        let limited = rate_limits.try_take_request(ip).and_then(|_| {
+            match fields.try_get_user_id() {
+                Ok(user_id) => rate_limits.try_take_user(user_id),
+                Err(_) => Ok(()),
+            }
+        });
+        if let Err(retry_after) = limited {
+            let e = ApiError::rate_limited(to_retry_after_secs(retry_after));
+            if socket.write_message(Frame::Text(e.to_json().dump())).is_err() {
+                return;
+            }
+
+            continue;
+        }
+
+        let event = match fields.try_into_event() {
+            Ok(event) => event,
+            Err(e) => {
+                if socket.write_message(Frame::Text(e.to_json().dump())).is_err() {
+                    return;
+                }
+
+                continue;
+            }
+        };
+
+        // Messages may take a while as we geocode and look up forecasts, so let the client know
+        if let Event::Message(_) = event {
+            let typing = object!{
+                "type" => "typing",
+            };
+            if socket.write_message(Frame::Text(typing.dump())).is_err() {
+                return;
+            }
+        }
+
+        let log = RequestLog::begin(logging::request_id(None));
+        let (reply, log) = match responders.ask(event, log) {
+            Some(answered) => answered,
+            None => return,
+        };
+        let messages = object!{
+            "messages" => reply_to_json(reply),
+        };
+        let is_sent = socket.write_message(Frame::Text(messages.dump())).is_ok();
+        log.end(Level::Info, vec![
+            ("route", ROUTE_LABEL_WEBSOCKET.into()),
+            ("duration_ms", to_millis(started.elapsed()).into()),
+        ]);
+        if !is_sent {
+            return;
+        }
+    }
+}
+
+fn serve_events(
+    stream: TcpStream,
+    head: &Head,
+    hub: Hub,
+    cors: &CorsPolicy,
+    principal: Option<Principal>,
+) {
+    // We already have the headers but they must still be read past
+    let mut reader = BufReader::new(stream);
+    loop {
+        let mut line = String::new();
+        match reader.read_line(&mut line) {
+            Ok(len) if len > 0 && !line.trim().is_empty() => (),
+            Ok(len) if len > 0 => break,
+            _ => return,
+        }
+    }
+    let mut stream = reader.into_inner();
+
+    // Sanity check: The stream may only be read by the user it belongs to
+    let user_id = head.path[ROUTE_EVENTS.len()..].trim_matches('/');
+    let user_id = match authorize_event_stream(principal.as_ref(), user_id) {
+        Ok(user_id) => user_id,
+        Err(e) => {
+            write_error(&mut stream, &e);
+            return;
+        }
+    };
+
+    let mut headers = "HTTP/1.1 200 OK\r\n\
+                       Content-Type: text/event-stream\r\n\
+                       Cache-Control: no-cache\r\n\
+                       Connection: keep-alive\r\n"
+        .to_owned();
+    for (key, val) in cors.headers(head.try_get_header(HEADER_ORIGIN)) {
+        headers.push_str(&format!("{}: {}\r\n", key, val));
+    }
+    headers.push_str("\r\n");
+    if stream.write_all(headers.as_bytes()).is_err() {
+        return;
+    }
+
+    // Relay whatever the hub sends us until the client goes away
+    let receiver = hub.subscribe(user_id);
+    loop {
+        let event = match receiver.recv_timeout(Duration::from_secs(EVENTS_KEEP_ALIVE_SECS)) {
+            Ok(payload) => format!("event: message\ndata: {}\n\n", payload),
+            Err(RecvTimeoutError::Timeout) => ": keep-alive\n\n".to_owned(),
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+
+        if stream.write_all(event.as_bytes()).is_err() || stream.flush().is_err() {
+            return;
+        }
+    }
+}
+
+/// Finds whose event stream this is, which must be the authenticated subject's (if there is
+/// one) whatever the subject mode; overriding would hand them a stream they didn't ask for.
+fn authorize_event_stream(principal: Option<&Principal>, user_id: &str) -> Result<u64, ApiError> {
+    let requested = try_parse_id(FORM_DATA_USER_ID, user_id)?;
+    let user_id = authorize_user_id(principal, user_id)?;
+    if user_id != requested {
+        return Err(ApiError::forbidden(
+            FORM_DATA_USER_ID,
+            "The event stream does not belong to the authenticated subject",
+        ));
+    }
+
+    Ok(user_id)
+}
+
+/// Authenticates the connection, if authentication is on.
+fn try_authenticate(auth: &Authenticator, head: &Head) -> Result<Option<Principal>, ApiError> {
+    if !auth.is_enabled() {
+        return Ok(None);
+    }
+
+    let credentials = head.try_get_credentials();
+    auth.authenticate(credentials.as_ref().map(|c| c.as_str()))
+        .map(Some)
+        .map_err(|e| ApiError::unauthorized(&e))
+}
+
+fn write_error(stream: &mut TcpStream, e: &ApiError) {
+    let (status_code_numeric, status_code_alpha) = e.status_code();
+    let body = e.to_json().dump();
+    let _ = write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+        status_code_numeric,
+        status_code_alpha,
+        body.len(),
+        body
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn credentials_from_header() {
+        let head = Head::parse("GET / HTTP/1.1\r\nX-Api-Key: key\r\nAuthorization: Bearer token");
+        assert_eq!(head.try_get_credentials(), Some("key".to_owned()));
+
+        let head = Head::parse("GET / HTTP/1.1\r\nauthorization: Bearer token");
+        assert_eq!(head.try_get_credentials(), Some("token".to_owned()));
+    }
+
+    #[test]
+    fn credentials_from_query() {
+        let head = Head::parse("GET /events/42?x=1&access_token=a%2Bb HTTP/1.1\r\nHost: h");
+        assert_eq!(head.path, "/events/42");
+        assert_eq!(head.try_get_credentials(), Some("a+b".to_owned()));
+
+        let head = Head::parse("GET /events/42 HTTP/1.1\r\nHost: h");
+        assert_eq!(head.try_get_credentials(), None);
+    }
+
+    #[test]
+    fn messages_are_limited_until_reset() {
+        let read = Rc::new(Cell::new(0));
+        let mut stream = Limited {
+            read: read.clone(),
+            stream: io::Cursor::new(vec![0; MAX_MESSAGE_BYTES * 3]),
+        };
+        let mut buf = vec![0; MAX_MESSAGE_BYTES + 1];
+        assert_eq!(stream.read(&mut buf).unwrap(), MAX_MESSAGE_BYTES + 1);
+        assert!(stream.read(&mut buf).is_err());
+
+        read.set(0);
+        assert!(stream.read(&mut buf).is_ok());
+    }
+
+    #[test]
+    fn headers_are_case_insensitive() {
+        let head = Head::parse("GET / HTTP/1.1\r\norigin: https://example.com");
+        assert_eq!(head.try_get_header(HEADER_ORIGIN), Some("https://example.com"));
+    }
+}
@@ -0,0 +1,588 @@
+use std::cell::RefCell;
+use std::fs::{rename, File};
+use std::io::{Read, Write};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use chrono::{DateTime, FixedOffset, LocalResult, TimeZone, Timelike, Utc};
+
+use chrono_tz::Tz;
+
+use futures::Future;
+
+use json::{self, JsonValue};
+
+use snips_nlu_ontology::{Grain, Slot, SlotValue};
+
+use dark_sky::DarkSkyApi;
+use google::GoogleApi;
+use intent::{Context, IntentHandler, Reply, ReplyFuture};
+use local_time::try_get_time_zone_at;
+use logging;
+use notify::Hub;
+use phrases::{self, Phrasebook};
+use session::SessionStore;
+use upstream::Pending;
+use weather::WeatherHandler;
+use web::reply_to_json;
+use webhook::Webhooks;
+
+// Panics
+const PANIC_POISONED_SUBSCRIPTION_FILE: &'static str = "Poisoned subscription file";
+const PANIC_POISONED_SUBSCRIPTION_STORE: &'static str = "Poisoned subscription store";
+const PANIC_UNACCEPTABLE_SUBSCRIPTIONS_FILE: &'static str = "Unacceptable subscriptions file";
+
+// Subscriptions without a time are sent at 7am local time
+pub const DEFAULT_HOUR: u32 = 7;
+pub const DEFAULT_MINUTE: u32 = 0;
+
+// A subscription which was due longer ago than this (say, while we were down) is skipped
+const MAX_LATENESS_SECS: i64 = 3_600;
+
+// A time skipped when the clocks go forward is moved to the end of the gap, which is never
+// longer than this
+const MAX_GAP_MINUTES: u32 = 180;
+
+// How often the scheduler wakes up to look for due subscriptions
+const SCHEDULER_INTERVAL_SECS: u64 = 30;
+
+/// A daily forecast for a place, sent at a local time of day.
+#[derive(Clone)]
+pub struct Subscription {
+    pub condition: Option<String>,
+    pub hour: u32,
+    pub id: u64,
+    /// When this was last sent, as a unix timestamp
+    pub last_sent: Option<i64>,
+    pub lat: f64,
+    pub lng: f64,
+    pub minute: u32,
+    pub place: String,
+    pub time_zone: String,
+    pub user_id: u64,
+}
+
+impl Subscription {
+    pub fn to_json(&self) -> JsonValue {
+        object!{
+            "id" => self.id,
+            "user_id" => self.user_id,
+            "place" => self.place.as_str(),
+            "lat" => self.lat,
+            "lng" => self.lng,
+            "time" => format!("{:02}:{:02}", self.hour, self.minute),
+            "time_zone" => self.time_zone.as_str(),
+            "condition" => self.condition.clone(),
+            "last_sent" => self.last_sent,
+        }
+    }
+
+    fn try_from_json(value: &JsonValue) -> Option<Self> {
+        // Sanity check: The required fields must be present
+        let id = value["id"].as_u64();
+        let user_id = value["user_id"].as_u64();
+        let place = value["place"].as_str();
+        let lat = value["lat"].as_f64();
+        let lng = value["lng"].as_f64();
+        let time_zone = value["time_zone"].as_str();
+        let time: Vec<u32> = value["time"]
+            .as_str()
+            .unwrap_or("")
+            .split(':')
+            .filter_map(|t| t.parse().ok())
+            .collect();
+        if id.is_none() || user_id.is_none() || place.is_none() || lat.is_none()
+            || lng.is_none() || time_zone.is_none() || time.len() != 2
+        {
+            return None;
+        }
+
+        Some(Self {
+            condition: value["condition"].as_str().map(|c| c.to_owned()),
+            hour: time[0],
+            id: id.unwrap(),
+            last_sent: value["last_sent"].as_i64(),
+            lat: lat.unwrap(),
+            lng: lng.unwrap(),
+            minute: time[1],
+            place: place.unwrap().to_owned(),
+            time_zone: time_zone.unwrap().to_owned(),
+            user_id: user_id.unwrap(),
+        })
+    }
+
+    /// The time this is due today (in its own time zone), if it is due and hasn't been sent. A
+    /// time which is skipped when the clocks go forward is due as soon as the clocks have gone
+    /// forward, and a time which happens twice when they go back is due the first time.
+    fn try_get_due(&self, now: DateTime<Utc>) -> Option<DateTime<Tz>> {
+        let tz: Tz = match self.time_zone.parse() {
+            Ok(tz) => tz,
+            Err(_) => return None,
+        };
+
+        let date = now.with_timezone(&tz).date().naive_local();
+        let minutes = self.hour * 60 + self.minute;
+        let due = (minutes..minutes + MAX_GAP_MINUTES)
+            .filter_map(|m| date.and_hms_opt(m / 60, m % 60, 0))
+            .filter_map(|local| match tz.from_local_datetime(&local) {
+                LocalResult::Single(due) | LocalResult::Ambiguous(due, _) => Some(due),
+                LocalResult::None => None,
+            })
+            .next();
+        match due {
+            Some(due)
+                if due.timestamp() <= now.timestamp()
+                    && now.timestamp() - due.timestamp() < MAX_LATENESS_SECS
+                    && self.last_sent.map(|l| l < due.timestamp()).unwrap_or(true) =>
+            {
+                Some(due)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The subscriptions as they were after a change, to be written to disk once the store is
+/// free for others again.
+struct Snapshot {
+    /// Changes are numbered in the order they were made, so an older one is never written
+    /// over a newer one
+    change: usize,
+    next_id: u64,
+    subscriptions: Vec<Subscription>,
+}
+
+/// Keeps every subscription in memory and writes them all back to a json file whenever they
+/// change, which is plenty for the number of subscriptions we expect.
+#[derive(Clone)]
+pub struct SubscriptionStore {
+    changes: Arc<AtomicUsize>,
+    path: Arc<String>,
+    /// The last change written to disk; held while writing, so only one write goes at once
+    saved: Arc<Mutex<usize>>,
+    state: Arc<Mutex<(u64, Vec<Subscription>)>>,
+}
+
+impl SubscriptionStore {
+    pub fn open(path: &str) -> Self {
+        let mut next_id = 1;
+        let mut subscriptions = vec![];
+
+        // A missing file is fine; it just means nobody has subscribed yet
+        let mut contents = String::new();
+        if let Ok(mut file) = File::open(path) {
+            file.read_to_string(&mut contents)
+                .expect(PANIC_UNACCEPTABLE_SUBSCRIPTIONS_FILE);
+            let stored = json::parse(&contents).expect(PANIC_UNACCEPTABLE_SUBSCRIPTIONS_FILE);
+            next_id = stored["next_id"].as_u64().unwrap_or(next_id);
+            subscriptions = stored["subscriptions"]
+                .members()
+                .filter_map(Subscription::try_from_json)
+                .collect();
+        }
+
+        Self {
+            changes: Arc::new(AtomicUsize::new(0)),
+            path: Arc::new(path.to_owned()),
+            saved: Arc::new(Mutex::new(0)),
+            state: Arc::new(Mutex::new((next_id, subscriptions))),
+        }
+    }
+
+    /// Writes the subscriptions to disk, as they stand.
+    pub fn flush(&self) {
+        let snapshot = {
+            let state = self.state.lock().expect(PANIC_POISONED_SUBSCRIPTION_STORE);
+            self.snapshot(&state)
+        };
+        self.save(snapshot);
+    }
+
+    pub fn list(&self, user_id: u64) -> Vec<Subscription> {
+        let state = self.state.lock().expect(PANIC_POISONED_SUBSCRIPTION_STORE);
+        state
+            .1
+            .iter()
+            .filter(|s| s.user_id == user_id)
+            .cloned()
+            .collect()
+    }
+
+    pub fn remove(&self, user_id: u64, id: u64) -> bool {
+        let snapshot = {
+            let mut state = self.state.lock().expect(PANIC_POISONED_SUBSCRIPTION_STORE);
+            let len = state.1.len();
+            state.1.retain(|s| s.user_id != user_id || s.id != id);
+            if state.1.len() == len {
+                return false;
+            }
+
+            self.snapshot(&state)
+        };
+        self.save(snapshot);
+
+        true
+    }
+
+    /// Geocodes the place and finds its time zone before storing the subscription.
+    pub fn subscribe(
+        &self,
+        google_api: &GoogleApi,
+        user_id: u64,
+        place: &str,
+        hour: u32,
+        minute: u32,
+        condition: Option<String>,
+    ) -> Pending<Subscription> {
+        let found = google_api.try_get_place(place);
+        let place = place.to_owned();
+        let store = self.clone();
+        Box::new(found.map(move |found| {
+            // Sanity check: We must be able to locate the place
+            let (lat, lng) = found.map(|f| (f.lat, f.lng))?;
+
+            // Sanity check: We must be able to tell what time it is there
+            let tz = try_get_time_zone_at(lat, lng)?;
+
+            Some(store.add(Subscription {
+                condition: condition,
+                hour: hour,
+                id: 0,
+                last_sent: None,
+                lat: lat,
+                lng: lng,
+                minute: minute,
+                place: place,
+                time_zone: tz.name().to_owned(),
+                user_id: user_id,
+            }))
+        }))
+    }
+
+    /// Whether the store can still be used, which it can't once a thread panicked holding it.
+    pub fn try_check(&self) -> Result<(), String> {
+        self.state
+            .lock()
+            .map(|_| ())
+            .map_err(|_| "The subscription store is poisoned".to_owned())
+    }
+
+    /// Stores the subscription under the next id.
+    fn add(&self, mut subscription: Subscription) -> Subscription {
+        let snapshot = {
+            let mut state = self.state.lock().expect(PANIC_POISONED_SUBSCRIPTION_STORE);
+            subscription.id = state.0;
+            state.0 += 1;
+            state.1.push(subscription.clone());
+            self.snapshot(&state)
+        };
+        self.save(snapshot);
+
+        subscription
+    }
+
+    /// Finds the subscriptions which are due, along with when they were due. They aren't
+    /// marked as sent until they have been, so those which couldn't be are found again next time
+    /// around (until they are too late).
+    fn find_due(&self, now: DateTime<Utc>) -> Vec<(i64, Subscription)> {
+        let state = self.state.lock().expect(PANIC_POISONED_SUBSCRIPTION_STORE);
+        state
+            .1
+            .iter()
+            .filter_map(|s| s.try_get_due(now).map(|due| (due.timestamp(), s.clone())))
+            .collect()
+    }
+
+    /// Remembers that the subscription was sent for the time it was due.
+    fn mark_sent(&self, id: u64, due: i64) {
+        let snapshot = {
+            let mut state = self.state.lock().expect(PANIC_POISONED_SUBSCRIPTION_STORE);
+            let mut is_marked = false;
+            for subscription in state.1.iter_mut().filter(|s| s.id == id) {
+                subscription.last_sent = Some(due);
+                is_marked = true;
+            }
+
+            // Sanity check: It may have been removed while it was being sent
+            if !is_marked {
+                return;
+            }
+
+            self.snapshot(&state)
+        };
+        self.save(snapshot);
+    }
+
+    /// Writes the snapshot to disk, unless a later change has been written already. Nobody
+    /// waits on the store meanwhile; only other writes wait.
+    fn save(&self, snapshot: Snapshot) {
+        let mut saved = self.saved.lock().expect(PANIC_POISONED_SUBSCRIPTION_FILE);
+        if snapshot.change <= *saved {
+            return;
+        }
+
+        let mut subscriptions = JsonValue::new_array();
+        for subscription in &snapshot.subscriptions {
+            // This will always succeed because subscriptions is an array
+            subscriptions.push(subscription.to_json()).unwrap();
+        }
+
+        // Write to the side and then swap the file in so a crash never leaves half a file
+        let tmp_path = format!("{}.tmp", &self.path);
+        let contents = object!{
+            "next_id" => snapshot.next_id,
+            "subscriptions" => subscriptions,
+        }.pretty(4);
+        let written = File::create(&tmp_path)
+            .and_then(|mut file| file.write_all(contents.as_bytes()))
+            .and_then(|_| rename(&tmp_path, self.path.as_str()));
+        match written {
+            Ok(_) => *saved = snapshot.change,
+            Err(e) => logging::error(format!("Unable to save subscriptions: {}", e)),
+        }
+    }
+
+    /// Copies the subscriptions as they are after a change; only while holding the store.
+    fn snapshot(&self, state: &(u64, Vec<Subscription>)) -> Snapshot {
+        Snapshot {
+            change: self.changes.fetch_add(1, Ordering::SeqCst) + 1,
+            next_id: state.0,
+            subscriptions: state.1.clone(),
+        }
+    }
+}
+
+/// Lets users subscribe by asking, e.g. "Send me the weather in Chicago every morning at 7".
+pub struct SubscribeHandler {
+    store: SubscriptionStore,
+}
+
+impl SubscribeHandler {
+    pub fn new(store: SubscriptionStore) -> Self {
+        Self { store: store }
+    }
+}
+
+impl IntentHandler for SubscribeHandler {
+    fn accepts(&self, slots: &[Slot]) -> bool {
+        try_get_slot(slots, "subscription_locality").is_some()
+    }
+
+    fn respond(&self, ctx: &Context, slots: &[Slot]) -> ReplyFuture {
+        // This will always succeed because accepts() checked it
+        let place = try_get_slot(slots, "subscription_locality").unwrap();
+        let (hour, minute) = try_get_time(slots).unwrap_or((DEFAULT_HOUR, DEFAULT_MINUTE));
+
+        let subscription = self.store
+            .subscribe(&ctx.google_api, ctx.user_id, &place, hour, minute, None);
+        let ctx = ctx.clone();
+        Box::new(subscription.map(move |subscription| {
+            // Sanity check: We may have been unable to locate the place
+            if let None = subscription {
+                return Reply::text(ctx.say_down());
+            }
+
+            let time = format!("{:02}:{:02}", hour, minute);
+            Reply::text(ctx.say(
+                &phrases::SUBSCRIBED,
+                &[("place", place.as_str()), ("time", time.as_str())],
+            ))
+        }))
+    }
+}
+
+/// Sends each subscription's forecast to the user's push channel and the configured webhook
+/// when it comes due.
+pub fn deliver_forever(
+    store: SubscriptionStore,
+    sessions: SessionStore,
+    hub: Hub,
+    webhooks: Webhooks,
+) {
+    let dark_sky_api = Rc::new(DarkSkyApi::default());
+    let google_api = Rc::new(GoogleApi::default());
+    let phrasebook = Rc::new(Phrasebook::default());
+
+    loop {
+        thread::sleep(Duration::from_secs(SCHEDULER_INTERVAL_SECS));
+
+        for (due, subscription) in store.find_due(Utc::now()) {
+            // Sanity check: Nobody would hear it yet; they may be listening next time around
+            if !webhooks.is_configured() && !hub.is_listening(subscription.user_id) {
+                continue;
+            }
+
+            // Generate the message exactly as if the user had asked for today's forecast
+            let loaded = sessions.load(subscription.user_id);
+            let ctx = Context {
+                dark_sky_api: dark_sky_api.clone(),
+                google_api: google_api.clone(),
+                phrasebook: phrasebook.clone(),
+                session: Rc::new(RefCell::new(loaded.clone())),
+                user_id: subscription.user_id,
+            };
+
+            // This will always succeed because replies don't fail
+            let reply = WeatherHandler
+                .forecast(
+                    &ctx,
+                    subscription.place.to_owned(),
+                    subscription.condition.clone(),
+                    None,
+                    Some(Grain::Day),
+                )
+                .wait()
+                .unwrap();
+            sessions.save_changes(subscription.user_id, &loaded, ctx.session.borrow().clone());
+
+            // Sanity check: A forecast always comes with its cards; without them we were unable
+            // to get one, and it is tried again next time around
+            if reply.cards.is_empty() {
+                logging::warn(format!(
+                    "Unable to get the forecast for subscription {}",
+                    subscription.id
+                ));
+                continue;
+            }
+
+            let payload = object!{
+                "type" => "subscription",
+                "subscription_id" => subscription.id,
+                "user_id" => subscription.user_id,
+                "messages" => reply_to_json(reply),
+            };
+            let is_published = hub.publish(subscription.user_id, &payload.dump());
+            let is_posted = webhooks.post(&payload);
+            if is_published || is_posted {
+                store.mark_sent(subscription.id, due);
+            }
+        }
+    }
+}
+
+fn try_get_slot(slots: &[Slot], slot_name: &str) -> Option<String> {
+    for slot in slots {
+        if &slot.slot_name == slot_name {
+            if let &SlotValue::Custom(ref v) = &slot.value {
+                return Some(v.value.to_owned());
+            }
+        }
+    }
+
+    None
+}
+
+/// Finds the time of day they asked for; an interval such as "in the morning" uses its start.
+fn try_get_time(slots: &[Slot]) -> Option<(u32, u32)> {
+    for slot in slots {
+        if &slot.slot_name != "subscription_time" {
+            continue;
+        }
+
+        let value = match &slot.value {
+            &SlotValue::InstantTime(ref v) => Some(v.value.to_owned()),
+            &SlotValue::TimeInterval(ref v) => v.from.clone(),
+            _ => None,
+        };
+
+        if let Some(d) = value.and_then(|v| {
+            DateTime::<FixedOffset>::parse_from_str(&v, "%Y-%m-%d %H:%M:%S %:z").ok()
+        }) {
+            return Some((d.hour(), d.minute()));
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use super::*;
+
+    fn subscription(hour: u32, minute: u32, time_zone: &str) -> Subscription {
+        Subscription {
+            condition: None,
+            hour: hour,
+            id: 1,
+            last_sent: None,
+            lat: 0.0,
+            lng: 0.0,
+            minute: minute,
+            place: "Somewhere".to_owned(),
+            time_zone: time_zone.to_owned(),
+            user_id: 1,
+        }
+    }
+
+    fn utc(y: i32, mo: u32, d: u32, h: u32, mi: u32) -> DateTime<Utc> {
+        Utc.ymd(y, mo, d).and_hms(h, mi, 0)
+    }
+
+    #[test]
+    fn due_within_the_hour() {
+        let subscription = subscription(7, 0, "UTC");
+        assert!(subscription.try_get_due(utc(2018, 6, 1, 6, 59)).is_none());
+        assert_eq!(
+            subscription.try_get_due(utc(2018, 6, 1, 7, 30)),
+            Some(Tz::UTC.ymd(2018, 6, 1).and_hms(7, 0, 0))
+        );
+        assert!(subscription.try_get_due(utc(2018, 6, 1, 8, 0)).is_none());
+    }
+
+    #[test]
+    fn skipped_time_is_due_once_the_clocks_go_forward() {
+        // New York went from 2:00 to 3:00 EST (7:00 UTC) on the 11th of March 2018
+        let subscription = subscription(2, 30, "America/New_York");
+        let due = subscription.try_get_due(utc(2018, 3, 11, 7, 10)).unwrap();
+        assert_eq!(due.timestamp(), utc(2018, 3, 11, 7, 0).timestamp());
+    }
+
+    #[test]
+    fn repeated_time_is_due_the_first_time() {
+        // New York went from 2:00 EDT back to 1:00 (6:00 UTC) on the 4th of November 2018
+        let subscription = subscription(1, 30, "America/New_York");
+        let due = subscription.try_get_due(utc(2018, 11, 4, 5, 40)).unwrap();
+        assert_eq!(due.timestamp(), utc(2018, 11, 4, 5, 30).timestamp());
+    }
+
+    #[test]
+    fn due_until_marked_sent() {
+        let path = env::temp_dir().join(format!("telemacher-subscriptions-{}.json", process::id()));
+        let store = SubscriptionStore::open(path.to_str().unwrap());
+        let id = store.add(subscription(7, 0, "UTC")).id;
+        let now = utc(2018, 6, 1, 7, 10);
+
+        let due = store.find_due(now);
+        assert_eq!(due.len(), 1);
+        assert_eq!(store.find_due(now).len(), 1);
+
+        store.mark_sent(id, due[0].0);
+        assert!(store.find_due(now).is_empty());
+        assert_eq!(store.list(1)[0].last_sent, Some(due[0].0));
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn changes_are_saved() {
+        let path = env::temp_dir().join(format!("telemacher-saved-{}.json", process::id()));
+        let path = path.to_str().unwrap();
+        let store = SubscriptionStore::open(path);
+        let id = store.add(subscription(7, 0, "UTC")).id;
+        store.add(subscription(8, 0, "UTC"));
+        assert!(store.remove(1, id));
+
+        let reopened = SubscriptionStore::open(path);
+        let subscriptions = reopened.list(1);
+        assert_eq!(subscriptions.len(), 1);
+        assert_eq!(subscriptions[0].hour, 8);
+        assert_eq!(reopened.add(subscription(9, 0, "UTC")).id, id + 2);
+        fs::remove_file(path).ok();
+    }
+}
@@ -0,0 +1,190 @@
+use json::JsonValue;
+
+use reqwest::header::ContentType;
+use reqwest::Client;
+
+use tokio_minihttp::Request;
+
+use auth::constant_time_eq;
+use channel::{to_user_id, Channel, Inbound};
+use cli::{get_telegram_api_url, get_telegram_secret, get_telegram_token};
+use intent::Reply;
+use web::try_get_header;
+
+// Headers
+const HEADER_TELEGRAM_SECRET_TOKEN: &'static str = "X-Telegram-Bot-Api-Secret-Token";
+
+/// The Telegram Bot API, which posts updates to /channels/telegram once the webhook has been
+/// set with https://core.telegram.org/bots/api#setwebhook (including our secret_token)
+pub struct TelegramChannel {
+    api_url: String,
+    client: Client,
+    secret: String,
+    token: String,
+}
+
+impl TelegramChannel {
+    pub fn try_new() -> Option<Self> {
+        // Sanity check: The command line won't take a token without a secret, as anyone could
+        // post updates otherwise
+        let token = get_telegram_token();
+        let secret = get_telegram_secret();
+        if token.is_none() || secret.is_none() {
+            return None;
+        }
+
+        Some(Self {
+            api_url: get_telegram_api_url().trim_right_matches('/').to_owned(),
+            client: Client::new(),
+            secret: secret.unwrap(),
+            token: token.unwrap(),
+        })
+    }
+
+    /// Whether the update came with the secret_token we gave setWebhook.
+    fn is_from_telegram(&self, secret_token: Option<&str>) -> bool {
+        secret_token.map_or(false, |t| constant_time_eq(t.as_bytes(), self.secret.as_bytes()))
+    }
+
+    fn try_parse_update(update: &JsonValue) -> Option<Inbound> {
+        // Sanity check: We only answer people, and only text
+        let message = &update["message"];
+        if message["from"]["is_bot"].as_bool().unwrap_or(false) {
+            return None;
+        }
+
+        let from_id = message["from"]["id"].as_i64();
+        let chat_id = message["chat"]["id"].as_i64();
+        let text = message["text"].as_str();
+        if from_id.is_none() || chat_id.is_none() || text.is_none() {
+            return None;
+        }
+
+        Some(Inbound {
+            conversation: chat_id.unwrap().to_string(),
+            text: text.unwrap().to_owned(),
+            user_id: to_user_id(&["telegram", &from_id.unwrap().to_string()]),
+        })
+    }
+}
+
+impl Channel for TelegramChannel {
+    fn name(&self) -> &'static str {
+        "telegram"
+    }
+
+    fn accepts(&self, request: &Request) -> bool {
+        let secret_token = try_get_header(request, HEADER_TELEGRAM_SECRET_TOKEN);
+        self.is_from_telegram(secret_token.as_ref().map(|t| t.as_str()))
+    }
+
+    fn parse(&self, updates: &JsonValue) -> Vec<Inbound> {
+        // The webhook posts a single update; getUpdates returns a list of them
+        if updates["result"].is_array() {
+            updates["result"]
+                .members()
+                .filter_map(Self::try_parse_update)
+                .collect()
+        } else {
+            Self::try_parse_update(updates).into_iter().collect()
+        }
+    }
+
+    fn send(&self, conversation: &str, reply: &Reply) -> Result<(), String> {
+        let body = object!{
+            "chat_id" => conversation,
+            "text" => reply.text.as_str(),
+        };
+        let response = self.client
+            .post(format!("{}/bot{}/sendMessage", &self.api_url, &self.token).as_str())
+            .header(ContentType::json())
+            .body(body.dump())
+            .send()
+            .map_err(|e| e.to_string())?;
+
+        // Sanity check: Telegram tells us why it refused
+        if !response.status().is_success() {
+            return Err(format!("Telegram responded {}", response.status()));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use json;
+
+    use fake_server::FakeServer;
+
+    use super::*;
+
+    fn telegram(api_url: &str) -> TelegramChannel {
+        TelegramChannel {
+            api_url: api_url.to_owned(),
+            client: Client::new(),
+            secret: "secret".to_owned(),
+            token: "123:abc".to_owned(),
+        }
+    }
+
+    fn update(is_bot: bool, text: &str) -> JsonValue {
+        object!{
+            "update_id" => 1,
+            "message" => object!{
+                "chat" => object!{ "id" => 10 },
+                "from" => object!{ "id" => 20, "is_bot" => is_bot },
+                "text" => text,
+            },
+        }
+    }
+
+    #[test]
+    fn only_the_secret_is_accepted() {
+        let telegram = telegram("unused");
+        assert!(telegram.is_from_telegram(Some("secret")));
+        assert!(!telegram.is_from_telegram(Some("secreT")));
+        assert!(!telegram.is_from_telegram(Some("")));
+        assert!(!telegram.is_from_telegram(None));
+    }
+
+    #[test]
+    fn updates_from_people_are_parsed() {
+        let inbound = telegram("unused").parse(&update(false, "Hi"));
+        assert_eq!(inbound.len(), 1);
+        assert_eq!(inbound[0].conversation, "10");
+        assert_eq!(inbound[0].text, "Hi");
+        assert_eq!(inbound[0].user_id, to_user_id(&["telegram", "20"]));
+
+        let updates = object!{
+            "ok" => true,
+            "result" => array![update(false, "Hi"), update(true, "Beep"), update(false, "Bye")],
+        };
+        assert_eq!(telegram("unused").parse(&updates).len(), 2);
+    }
+
+    #[test]
+    fn updates_from_bots_are_ignored() {
+        assert!(telegram("unused").parse(&update(true, "Beep")).is_empty());
+    }
+
+    #[test]
+    fn replies_are_sent_to_the_chat() {
+        let server = FakeServer::start("200 OK", "{\"ok\":true}");
+        let reply = Reply::text("Hello");
+        assert!(telegram(&server.url).send("10", &reply).is_ok());
+
+        let request = server.next_request();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/bot123:abc/sendMessage");
+        let body = json::parse(&request.body).unwrap();
+        assert_eq!(body["chat_id"].as_str(), Some("10"));
+        assert_eq!(body["text"].as_str(), Some("Hello"));
+    }
+
+    #[test]
+    fn refused_replies_fail() {
+        let server = FakeServer::start("403 Forbidden", "{\"ok\":false}");
+        assert!(telegram(&server.url).send("10", &Reply::text("Hello")).is_err());
+    }
+}
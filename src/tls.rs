@@ -0,0 +1,127 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::sync::{Arc, RwLock};
+use std::thread;
+
+use futures::Future;
+
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use rustls::{NoClientAuth, ServerConfig, ServerSession};
+
+use signal_hook::iterator::Signals;
+use signal_hook::SIGHUP;
+
+use tokio_core::net::TcpStream;
+
+use tokio_minihttp::{Http, Request, Response};
+
+use tokio_proto::pipeline::ServerProto;
+
+use tokio_rustls::{TlsAcceptor, TlsStream};
+
+use logging;
+
+// Panics
+const PANIC_UNACCEPTABLE_TLS_CERT_OR_KEY: &'static str = "Unacceptable tls cert or key";
+
+/// HTTP spoken over TLS. Every connection is accepted with the certificate current at the
+/// time, which is reloaded from disk on SIGHUP so renewals don't need a restart.
+#[derive(Clone)]
+pub struct TlsHttp {
+    acceptor: Arc<RwLock<TlsAcceptor>>,
+}
+
+impl TlsHttp {
+    /// Loads the certificate chain and private key (PEM), which must be good since we can't
+    /// serve without them.
+    pub fn new(cert_file: &str, key_file: &str) -> Self {
+        let acceptor = try_load_acceptor(cert_file, key_file).unwrap_or_else(|e| {
+            logging::error(e);
+            panic!(PANIC_UNACCEPTABLE_TLS_CERT_OR_KEY);
+        });
+
+        Self {
+            acceptor: Arc::new(RwLock::new(acceptor)),
+        }
+    }
+
+    /// Reloads the certificate chain and private key whenever we are sent SIGHUP. If the new
+    /// ones won't do we carry on with the old ones.
+    pub fn reload_on_sighup(&self, cert_file: String, key_file: String) {
+        let signals = match Signals::new(&[SIGHUP]) {
+            Ok(signals) => signals,
+            Err(e) => {
+                logging::warn(format!(
+                    "Unable to listen for SIGHUP, certificates won't be reloaded: {}",
+                    e
+                ));
+                return;
+            }
+        };
+
+        let acceptor = self.acceptor.clone();
+        thread::spawn(move || {
+            for _ in signals.forever() {
+                match try_load_acceptor(&cert_file, &key_file) {
+                    Ok(reloaded) => {
+                        *acceptor.write().unwrap() = reloaded;
+                        logging::info(format!("Reloaded tls certificate {}", &cert_file));
+                    }
+                    Err(e) => logging::error(format!("Unable to reload tls certificate: {}", e)),
+                }
+            }
+        });
+    }
+}
+
+impl ServerProto<TcpStream> for TlsHttp {
+    type Request = Request;
+    type Response = Response;
+    type Transport = <Http as ServerProto<TlsStream<TcpStream, ServerSession>>>::Transport;
+    type BindTransport = Box<Future<Item = Self::Transport, Error = io::Error>>;
+
+    fn bind_transport(&self, io: TcpStream) -> Self::BindTransport {
+        // Connections already mid-handshake keep the acceptor they started with
+        let acceptor = self.acceptor.read().unwrap().clone();
+        Box::new(acceptor.accept(io).and_then(|tls| Http.bind_transport(tls)))
+    }
+}
+
+fn try_load_acceptor(cert_file: &str, key_file: &str) -> Result<TlsAcceptor, String> {
+    let open = |path: &str| {
+        File::open(path)
+            .map(BufReader::new)
+            .map_err(|e| format!("Unable to open {}: {}", path, e))
+    };
+
+    // Sanity check: Need at least the one certificate
+    let cert_chain = certs(&mut open(cert_file)?)
+        .map_err(|_| format!("Unable to read certificates from {}", cert_file))?;
+    if cert_chain.is_empty() {
+        return Err(format!("No certificates found in {}", cert_file));
+    }
+
+    // Keys may be PKCS#8 ("BEGIN PRIVATE KEY") or PKCS#1 ("BEGIN RSA PRIVATE KEY")
+    let mut keys = pkcs8_private_keys(&mut open(key_file)?)
+        .map_err(|_| format!("Unable to read private key from {}", key_file))?;
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut open(key_file)?)
+            .map_err(|_| format!("Unable to read private key from {}", key_file))?;
+    }
+
+    // Sanity check: Need exactly the one key
+    if keys.len() != 1 {
+        return Err(format!(
+            "Expected one private key in {}, found {}",
+            key_file,
+            keys.len()
+        ));
+    }
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config
+        .set_single_cert(cert_chain, keys.remove(0))
+        .map_err(|e| format!("Unable to use {} with {}: {}", cert_file, key_file, e))?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
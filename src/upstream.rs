@@ -0,0 +1,358 @@
+use std::collections::HashMap;
+use std::mem;
+use std::str;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::future::{self, loop_fn, Either, Loop};
+use futures::sync::mpsc::{unbounded, UnboundedSender};
+use futures::sync::oneshot::{channel, Sender};
+use futures::{Future, Stream};
+
+use json::{self, JsonValue};
+
+use rand::{self, Rng};
+
+use reqwest::unstable::async::{Client, Decoder};
+use reqwest::{self, StatusCode};
+
+use tokio_core::reactor::{Core, Handle, Timeout};
+
+use cli::{get_upstream_breaker_cooldown, get_upstream_breaker_failures,
+          get_upstream_connect_timeout, get_upstream_deadline, get_upstream_read_timeout,
+          get_upstream_retries};
+use logging::{self, record_upstream};
+use metrics::observe_upstream;
+use rate_limit::try_spend_upstream_call;
+
+// Panics
+const PANIC_POISONED_BREAKERS: &'static str = "Poisoned breakers";
+const PANIC_POISONED_UPSTREAM: &'static str = "Poisoned upstream";
+const PANIC_UNAVAILABLE_REACTOR: &'static str = "Unavailable reactor";
+
+// The first retry waits up to this long; each one after waits up to twice as long as the last,
+// until the waits stop growing (at about a minute)
+const MAX_RETRY_DOUBLINGS: u32 = 8;
+const RETRY_BASE_MILLIS: u64 = 250;
+
+/// Something from an upstream API which may not have arrived yet; `None` if it never will.
+pub type Pending<T> = Box<Future<Item = Option<T>, Error = ()>>;
+
+/// Stops calling an upstream which keeps failing, so users are told straight away rather than
+/// after every timeout and retry. Once the cooldown is over a single call is let through to
+/// see whether it has recovered.
+#[derive(Default)]
+struct Breaker {
+    failures: u32,
+    opened_at: Option<Instant>,
+}
+
+/// Looks inside json which arrived with a success, for upstreams which answer 200 even when
+/// they refuse.
+pub type Check = fn(&JsonValue) -> Result<(), Failure>;
+
+// A url to get, how to tell its json is good and where to send it (or why there is none)
+struct Call {
+    answer: Sender<Result<JsonValue, String>>,
+    check: Check,
+    upstream: &'static str,
+    url: String,
+}
+
+struct Config {
+    breaker_cooldown: Duration,
+    breaker_failures: u32,
+    connect_timeout: Duration,
+    deadline: Duration,
+    read_timeout: Duration,
+    retries: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            breaker_cooldown: get_upstream_breaker_cooldown(),
+            breaker_failures: get_upstream_breaker_failures(),
+            connect_timeout: get_upstream_connect_timeout(),
+            deadline: get_upstream_deadline(),
+            read_timeout: get_upstream_read_timeout(),
+            retries: get_upstream_retries(),
+        }
+    }
+}
+
+/// Why a call failed, and whether it may work if tried again.
+pub struct Failure {
+    pub is_transient: bool,
+    pub message: String,
+}
+
+impl Failure {
+    pub fn permanent<M: Into<String>>(message: M) -> Self {
+        Self {
+            is_transient: false,
+            message: message.into(),
+        }
+    }
+
+    pub fn transient<M: Into<String>>(message: M) -> Self {
+        Self {
+            is_transient: true,
+            message: message.into(),
+        }
+    }
+}
+
+impl<'a> From<&'a reqwest::Error> for Failure {
+    /// Describes the error without its url, which carries our API key.
+    fn from(e: &reqwest::Error) -> Self {
+        match (e.status(), e.get_ref()) {
+            (Some(status), _) => Self::from(status),
+            (None, Some(inner)) => Self::transient(inner.to_string()),
+            (None, None) => Self::transient("Request failed"),
+        }
+    }
+}
+
+impl From<StatusCode> for Failure {
+    /// Only the upstream being overloaded or broken is worth trying again; anything else is
+    /// down to what we asked for.
+    fn from(status: StatusCode) -> Self {
+        let message = format!("Responded {}", status);
+        if status.is_server_error() || status == StatusCode::TooManyRequests {
+            Self::transient(message)
+        } else {
+            Self::permanent(message)
+        }
+    }
+}
+
+lazy_static! {
+    static ref BREAKERS: Mutex<HashMap<&'static str, Breaker>> = Default::default();
+
+    // Every upstream call is made from one reactor thread of its own, so no matter which
+    // thread asks (a web worker's event loop included) nothing blocks while Google and Dark Sky
+    // answer
+    static ref CALLS: Mutex<UnboundedSender<Call>> = Mutex::new(start());
+
+    static ref CONFIG: Config = Config::default();
+}
+
+/// Whether any upstream has failed so often lately that we've stopped calling it.
+pub fn is_any_unavailable() -> bool {
+    let breakers = BREAKERS.lock().expect(PANIC_POISONED_BREAKERS);
+    breakers.values().any(|b| match b.opened_at {
+        Some(opened_at) => opened_at.elapsed() < CONFIG.breaker_cooldown,
+        None => false,
+    })
+}
+
+/// Takes any json as good.
+pub fn accept_any(_: &JsonValue) -> Result<(), Failure> {
+    Ok(())
+}
+
+/// Gets json from one of the paid upstream APIs (Google or Dark Sky), recording how long it
+/// took and whether it worked. Anything but good json which passes the check counts as an error.
+pub fn try_get_json(upstream: &'static str, url: String, check: Check) -> Pending<JsonValue> {
+    // Sanity check: A failing upstream isn't called (or paid for) until it has had time to
+    // recover
+    if !try_pass(upstream) {
+        record_upstream(upstream, Duration::from_secs(0), Some("Unavailable"));
+        return Box::new(future::ok(None));
+    }
+
+    // Sanity check: Every call costs us
+    if !try_spend_upstream_call(upstream) {
+        return Box::new(future::ok(None));
+    }
+
+    let started = Instant::now();
+    let (answer, answered) = channel();
+
+    // Should the reactor be gone the answer is dropped, which is reported below
+    CALLS
+        .lock()
+        .expect(PANIC_POISONED_UPSTREAM)
+        .unbounded_send(Call {
+            answer: answer,
+            check: check,
+            upstream: upstream,
+            url: url,
+        })
+        .ok();
+
+    Box::new(answered.then(move |response| {
+        let response = response.unwrap_or_else(|_| Err("The upstream reactor stopped".to_owned()));
+        let elapsed = started.elapsed();
+        observe_upstream(upstream, elapsed, response.is_err());
+        record_upstream(
+            upstream,
+            elapsed,
+            response.as_ref().err().map(|e| e.as_str()),
+        );
+        record_breaker(upstream, response.is_ok());
+
+        if let Err(ref e) = response {
+            logging::warn(format!("Unable to get {} data: {}", upstream, e));
+        }
+
+        Ok(response.ok())
+    }))
+}
+
+/// Gets the json once, within the timeouts.
+fn get(
+    client: &Client,
+    handle: &Handle,
+    url: &str,
+    check: Check,
+) -> Box<Future<Item = JsonValue, Error = Failure>> {
+    let response = client.get(url).send().map_err(|e| Failure::from(&e));
+    let response = within(response, CONFIG.connect_timeout, handle, "Timed out connecting");
+
+    let handle = handle.clone();
+    let body = response.and_then(move |mut response| {
+        // Sanity check: Only a success carries the json we want
+        let status = response.status();
+        if !status.is_success() {
+            return Either::A(future::err(Failure::from(status)));
+        }
+
+        let body = mem::replace(response.body_mut(), Decoder::empty())
+            .concat2()
+            .map_err(|e| Failure::from(&e));
+        Either::B(within(body, CONFIG.read_timeout, &handle, "Timed out reading"))
+    });
+
+    Box::new(body.and_then(move |body| {
+        str::from_utf8(&body)
+            .map_err(|e| Failure::permanent(e.to_string()))
+            .and_then(|text| json::parse(text).map_err(|e| Failure::permanent(e.to_string())))
+            .and_then(|json| check(&json).map(|_| json))
+    }))
+}
+
+/// Counts the result against the upstream's breaker, opening it after too many failures in a
+/// row.
+fn record_breaker(upstream: &'static str, is_ok: bool) {
+    let mut breakers = BREAKERS.lock().expect(PANIC_POISONED_BREAKERS);
+    let breaker = breakers.entry(upstream).or_insert_with(Default::default);
+    if is_ok {
+        if breaker.opened_at.is_some() {
+            logging::info(format!("Calling {} again, as it has recovered", upstream));
+        }
+
+        breaker.failures = 0;
+        breaker.opened_at = None;
+        return;
+    }
+
+    breaker.failures += 1;
+    if breaker.failures >= CONFIG.breaker_failures {
+        if breaker.opened_at.is_none() {
+            logging::warn(format!(
+                "Not calling {} for {}s after {} failures in a row",
+                upstream,
+                CONFIG.breaker_cooldown.as_secs(),
+                breaker.failures
+            ));
+        }
+
+        breaker.opened_at = Some(Instant::now());
+    }
+}
+
+/// Starts the reactor thread which makes the calls.
+fn start() -> UnboundedSender<Call> {
+    let (calls, received) = unbounded::<Call>();
+    thread::spawn(move || {
+        let mut core = Core::new().expect(PANIC_UNAVAILABLE_REACTOR);
+        let handle = core.handle();
+        let client = Client::new(&handle);
+        let calling = received.for_each(|call| {
+            let Call {
+                answer,
+                check,
+                upstream,
+                url,
+            } = call;
+            let client = client.clone();
+            let retry_handle = handle.clone();
+
+            // Failures which may pass are tried again after a random (jittered) wait, so that
+            // the calls which failed together don't all retry together
+            let response = loop_fn(0, move |attempt| {
+                let handle = retry_handle.clone();
+                get(&client, &handle, &url, check).then(move |response| match response {
+                    Err(ref e)
+                        if e.is_transient && attempt < CONFIG.retries
+                            && try_spend_upstream_call(upstream) =>
+                    {
+                        let max_wait = RETRY_BASE_MILLIS << attempt.min(MAX_RETRY_DOUBLINGS);
+                        let wait = rand::thread_rng().gen_range(0, max_wait + 1);
+                        let waited = Timeout::new(Duration::from_millis(wait), &handle)
+                            .expect(PANIC_UNAVAILABLE_REACTOR)
+                            .then(move |_| Ok(Loop::Continue(attempt + 1)));
+
+                        Either::A(waited)
+                    }
+                    response => Either::B(future::ok::<_, ()>(Loop::Break(response))),
+                })
+            });
+
+            // Sanity check: However many tries it takes, whoever asked mustn't be kept waiting
+            // past the deadline
+            let deadline = Timeout::new(CONFIG.deadline, &handle).expect(PANIC_UNAVAILABLE_REACTOR);
+            let response = response.select2(deadline).then(|result| match result {
+                Ok(Either::A((response, _))) => response,
+                _ => Err(Failure::transient("Timed out before the deadline")),
+            });
+            handle.spawn(response.then(move |response| {
+                answer.send(response.map_err(|e| e.message)).ok();
+                Ok(())
+            }));
+
+            Ok(())
+        });
+
+        // This only ends once every sender is gone, which is never as the sender is static
+        core.run(calling).ok();
+    });
+
+    calls
+}
+
+/// Lets the call through unless the upstream's breaker is open. Once the cooldown is over the
+/// breaker is opened again for another cooldown, so only the one call is let through to try.
+fn try_pass(upstream: &'static str) -> bool {
+    let mut breakers = BREAKERS.lock().expect(PANIC_POISONED_BREAKERS);
+    let breaker = breakers.entry(upstream).or_insert_with(Default::default);
+    match breaker.opened_at {
+        Some(opened_at) if opened_at.elapsed() < CONFIG.breaker_cooldown => false,
+        Some(_) => {
+            breaker.opened_at = Some(Instant::now());
+            true
+        }
+        None => true,
+    }
+}
+
+/// Fails the work if it takes too long.
+fn within<F>(
+    work: F,
+    timeout: Duration,
+    handle: &Handle,
+    message: &'static str,
+) -> Box<Future<Item = F::Item, Error = Failure>>
+where
+    F: Future<Error = Failure> + 'static,
+{
+    let timeout = Timeout::new(timeout, handle).expect(PANIC_UNAVAILABLE_REACTOR);
+    Box::new(work.select2(timeout).then(move |result| match result {
+        Ok(Either::A((item, _))) => Ok(item),
+        Err(Either::A((failure, _))) => Err(failure),
+        Ok(Either::B(_)) | Err(Either::B(_)) => Err(Failure::transient(message)),
+    }))
+}
@@ -0,0 +1,376 @@
+use std::time::Duration;
+
+use chrono::{DateTime, FixedOffset};
+
+use futures::future::Either;
+use futures::Future;
+
+use snips_nlu_ontology::{Grain, Slot, SlotValue};
+
+use dark_sky::Forecast;
+use intent::{Card, Context, ForecastCard, IntentHandler, PlaceCard, Reply, ReplyFuture};
+use phrases;
+use session::Location;
+
+/// The conditions Harris can narrow a forecast down to, as documented in the README.
+pub const CONDITION_KEYWORDS: &'static [&'static str] =
+    &["hail", "humid", "rain", "snow", "UV", "wind"];
+
+// What they may be asking about in particular (these items must be in the training set)
+enum SpecificForecast {
+    Snow,
+    Wind,
+    Hail,
+    Humidity,
+    Precipitation,
+    Uv,
+}
+
+// Which of the four dark sky predictions a reply is about
+#[derive(Clone, Copy)]
+enum Tense {
+    Currently,
+    Minutely,
+    Hourly,
+    Daily,
+}
+
+impl Tense {
+    fn name(&self) -> &'static str {
+        match *self {
+            Tense::Currently => "currently",
+            Tense::Minutely => "minutely",
+            Tense::Hourly => "hourly",
+            Tense::Daily => "daily",
+        }
+    }
+}
+
+/// Answers both of the weather forecast intents; a condition slot narrows the answer down.
+pub struct WeatherHandler;
+
+impl IntentHandler for WeatherHandler {
+    fn accepts(&self, slots: &[Slot]) -> bool {
+        // Only a located question can be answered
+        try_get_locality(slots).is_some()
+    }
+
+    fn confirm(&self, ctx: &Context, slots: &[Slot]) -> Option<Reply> {
+        try_get_locality(slots)
+            .map(|locality| Reply::text(ctx.say(&phrases::CONFIRM, &[("place", locality.as_str())])))
+    }
+
+    fn respond(&self, ctx: &Context, slots: &[Slot]) -> ReplyFuture {
+        // Pick out the values from slots; could be simpler but this form allows for the use of all
+        // value formats offered by the library and a way to handle each type according to our needs
+        let mut forecast_condition_name = None;
+        let mut forecast_start_datetime = None;
+        for slot in slots {
+            match slot {
+                s if &s.slot_name == "forecast_condition_name" => {
+                    if let &SlotValue::Custom(ref v) = &s.value {
+                        forecast_condition_name = Some(v.value.to_owned());
+                    }
+                }
+                s if &s.slot_name == "forecast_start_datetime" => {
+                    if let &SlotValue::InstantTime(ref v) = &s.value {
+                        if let Ok(d) = DateTime::<FixedOffset>::parse_from_str(
+                            &v.value,
+                            "%Y-%m-%d %H:%M:%S %:z",
+                        ) {
+                            forecast_start_datetime = Some((d, v.grain));
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        // Sanity check: We should have found a location
+        // A real service might guess using a commercial product such as https://www.maxmind.com
+        let forecast_locality = try_get_locality(slots);
+        if let None = forecast_locality {
+            return Reply::text(ctx.say(&phrases::UNSURE, &[])).ready();
+        }
+
+        let mut dt = None;
+        let mut grain = None;
+        if let Some((d, g)) = forecast_start_datetime {
+            dt = Some(d.clone());
+            grain = Some(g);
+        }
+
+        self.forecast(
+            ctx,
+            forecast_locality.unwrap(),
+            forecast_condition_name,
+            dt,
+            grain,
+        )
+    }
+}
+
+impl WeatherHandler {
+    /// Answers a question about the weather in a place, optionally narrowed down to a condition
+    /// and a date/time of the given granularity.
+    pub fn forecast(
+        &self,
+        ctx: &Context,
+        forecast_locality: String,
+        forecast_condition_name: Option<String>,
+        dt: Option<DateTime<FixedOffset>>,
+        grain: Option<Grain>,
+    ) -> ReplyFuture {
+        // See if we can further answer their specific question (these items must be in the training set)
+        let desired_forecast = match &forecast_condition_name {
+            &Some(ref v)
+                if v == "blizzard" || v == "snow" || v == "snowfall" || v == "snowing"
+                    || v == "snowstorm" || v == "snowy" =>
+            {
+                Some(SpecificForecast::Snow)
+            }
+            &Some(ref v) if v == "wind" || v == "windy" => Some(SpecificForecast::Wind),
+            &Some(ref v) if v == "hail" || v == "hailing" => Some(SpecificForecast::Hail),
+            &Some(ref v) if v == "humid" => Some(SpecificForecast::Humidity),
+            &Some(ref v)
+                if v == "storm" || v == "stormy" || v == "rain" || v == "rainfall"
+                    || v == "rainy" =>
+            {
+                Some(SpecificForecast::Precipitation)
+            }
+            &Some(ref v)
+                if v == "cloud" || v == "cloudi" || v == "overcast" || v == "depress" || v == "fog"
+                    || v == "foggy" || v == "sun" || v == "sunni" || v == "hot"
+                    || v == "be sunni" =>
+            {
+                Some(SpecificForecast::Uv)
+            }
+            _ => None,
+        };
+
+        // At this point we know they're asking about weather. We also have:
+        // forecast_locality: String
+        // dt: Option<DateTime<FixedOffset>>
+        // grain: Option<Grain>
+        // desired_forecast: Option<DesiredForecast>
+
+        // Step 1: Process locality string into lat/lng
+        let place = ctx.google_api.try_get_place(&forecast_locality);
+        let ctx = ctx.clone();
+        Box::new(place.and_then(move |place| {
+            // Sanity check: We may have been unable to do that
+            let place = match place {
+                Some(place) => place,
+                None => return Either::A(Reply::text(ctx.say_down()).ready()),
+            };
+
+            // Remember where they asked about so we can keep them posted
+            ctx.session.borrow_mut().location = Some(Location {
+                lat: place.lat,
+                lng: place.lng,
+                name: forecast_locality.to_owned(),
+            });
+
+            // Step 2: Go check the weather
+            let forecast = ctx.dark_sky_api.try_get_forecast(place.lat, place.lng, dt);
+            Either::B(forecast.map(move |forecast| {
+                // Sanity check: We may have been unable to do that
+                if let None = forecast {
+                    return Reply::text(ctx.say_down());
+                }
+
+                // The card names the place Google found, so users can tell if it's the wrong one
+                let place = PlaceCard {
+                    lat: place.lat,
+                    lng: place.lng,
+                    name: place.name,
+                };
+                Self::describe(
+                    &ctx,
+                    &forecast.unwrap(),
+                    place,
+                    forecast_condition_name,
+                    desired_forecast,
+                    grain,
+                )
+            }))
+        }))
+    }
+
+    /// Words the prediction which answers their question, along with the cards.
+    fn describe(
+        ctx: &Context,
+        forecast: &Forecast,
+        place: PlaceCard,
+        forecast_condition_name: Option<String>,
+        desired_forecast: Option<SpecificForecast>,
+        grain: Option<Grain>,
+    ) -> Reply {
+        // Step 3: Pick the prediction which matches the granularity they asked about
+        let (prediction, tense) = match grain {
+            None | Some(Grain::Second) => (&forecast.currently, Tense::Currently),
+            Some(Grain::Minute) => (&forecast.minutely, Tense::Minutely),
+            Some(Grain::Hour) => (&forecast.hourly, Tense::Hourly),
+            Some(Grain::Year) | Some(Grain::Quarter) | Some(Grain::Month) | Some(Grain::Week)
+            | Some(Grain::Day) => (&forecast.daily, Tense::Daily),
+        };
+
+        // Step 4: Pick the correct combination of desired forecast and granularity so we can respond
+        let text = match desired_forecast {
+            None => prediction.summary.to_owned(),
+            Some(SpecificForecast::Hail) => ctx.say(
+                match (tense, prediction.is_haily) {
+                    (Tense::Currently, true) => &phrases::HAIL_CURRENTLY,
+                    (Tense::Currently, false) => &phrases::NO_HAIL_CURRENTLY,
+                    (Tense::Minutely, true) => &phrases::HAIL_MINUTELY,
+                    (Tense::Minutely, false) => &phrases::NO_HAIL_MINUTELY,
+                    (Tense::Hourly, true) => &phrases::HAIL_HOURLY,
+                    (Tense::Hourly, false) => &phrases::NO_HAIL_HOURLY,
+                    (Tense::Daily, true) => &phrases::HAIL_DAILY,
+                    (Tense::Daily, false) => &phrases::NO_HAIL_DAILY,
+                },
+                &[],
+            ),
+            Some(SpecificForecast::Humidity) => ctx.say(
+                match tense {
+                    Tense::Currently => &phrases::HUMIDITY_CURRENTLY,
+                    Tense::Minutely => &phrases::HUMIDITY_MINUTELY,
+                    Tense::Hourly => &phrases::HUMIDITY_HOURLY,
+                    Tense::Daily => &phrases::HUMIDITY_DAILY,
+                },
+                &[("value", format!("{:.0}", 100. * prediction.humidity).as_str())],
+            ),
+            Some(SpecificForecast::Precipitation) => ctx.say(
+                match (tense, prediction.is_rainy) {
+                    (Tense::Currently, true) => &phrases::RAIN_CURRENTLY,
+                    (Tense::Currently, false) => &phrases::NO_RAIN_CURRENTLY,
+                    (Tense::Minutely, true) => &phrases::RAIN_MINUTELY,
+                    (Tense::Minutely, false) => &phrases::NO_RAIN_MINUTELY,
+                    (Tense::Hourly, true) => &phrases::RAIN_HOURLY,
+                    (Tense::Hourly, false) => &phrases::NO_RAIN_HOURLY,
+                    (Tense::Daily, true) => &phrases::RAIN_DAILY,
+                    (Tense::Daily, false) => &phrases::NO_RAIN_DAILY,
+                },
+                &[],
+            ),
+            Some(SpecificForecast::Snow) => ctx.say(
+                match (tense, prediction.is_snowy) {
+                    (Tense::Currently, true) => &phrases::SNOW_CURRENTLY,
+                    (Tense::Currently, false) => &phrases::NO_SNOW_CURRENTLY,
+                    (Tense::Minutely, true) => &phrases::SNOW_MINUTELY,
+                    (Tense::Minutely, false) => &phrases::NO_SNOW_MINUTELY,
+                    (Tense::Hourly, true) => &phrases::SNOW_HOURLY,
+                    (Tense::Hourly, false) => &phrases::NO_SNOW_HOURLY,
+                    (Tense::Daily, true) => &phrases::SNOW_DAILY,
+                    (Tense::Daily, false) => &phrases::NO_SNOW_DAILY,
+                },
+                &[],
+            ),
+            Some(SpecificForecast::Uv) => ctx.say(
+                match tense {
+                    Tense::Currently => &phrases::UV_CURRENTLY,
+                    Tense::Minutely => &phrases::UV_MINUTELY,
+                    Tense::Hourly => &phrases::UV_HOURLY,
+                    Tense::Daily => &phrases::UV_DAILY,
+                },
+                &[("value", prediction.uv_index.to_string().as_str())],
+            ),
+            Some(SpecificForecast::Wind) => ctx.say(
+                match tense {
+                    Tense::Currently => &phrases::WIND_CURRENTLY,
+                    Tense::Minutely => &phrases::WIND_MINUTELY,
+                    Tense::Hourly => &phrases::WIND_HOURLY,
+                    Tense::Daily => &phrases::WIND_DAILY,
+                },
+                &[("value", format!("{:.1}", prediction.wind_speed).as_str())],
+            ),
+        };
+
+        // An old forecast must say how old it is
+        let text = match forecast.stale_age {
+            Some(age) => ctx.say(
+                &phrases::STALE,
+                &[
+                    ("age", to_age(age).as_str()),
+                    ("forecast", to_clause(&text).as_str()),
+                ],
+            ),
+            None => text,
+        };
+
+        // Step 5: Include what we found so clients can show more than the sentence
+        Reply::text(text)
+            .with_card(Card::Place(place))
+            .with_card(Card::Forecast(ForecastCard {
+                condition: forecast_condition_name,
+                prediction: prediction.clone(),
+                tense: tense.name(),
+            }))
+    }
+}
+
+/// Words how long ago something was, to the minute (or hour, once it's been a while).
+fn to_age(age: Duration) -> String {
+    let minutes = age.as_secs() / 60;
+    match minutes {
+        0 | 1 => "a minute".to_owned(),
+        2...119 => format!("{} minutes", minutes),
+        _ => format!("{} hours", minutes / 60),
+    }
+}
+
+/// Makes a sentence fit on the end of another, keeping the capital of "I".
+fn to_clause(sentence: &str) -> String {
+    let mut chars = sentence.chars();
+    match chars.next() {
+        Some('I') if sentence[1..].starts_with(|c: char| c == ' ' || c == '\'') => {
+            sentence.to_owned()
+        }
+        Some(c) => c.to_lowercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/// Finds the place being asked about. We have four location values so we preference locality,
+/// poi, region, then country.
+fn try_get_locality(slots: &[Slot]) -> Option<String> {
+    for slot_name in &[
+        "forecast_locality",
+        "forecast_geographical_poi",
+        "forecast_region",
+        "forecast_country",
+    ] {
+        for slot in slots {
+            if &slot.slot_name == slot_name {
+                if let &SlotValue::Custom(ref v) = &slot.value {
+                    return Some(v.value.to_owned());
+                }
+            }
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ages_are_rounded_down() {
+        assert_eq!(to_age(Duration::from_secs(0)), "a minute");
+        assert_eq!(to_age(Duration::from_secs(119)), "a minute");
+        assert_eq!(to_age(Duration::from_secs(40 * 60 + 59)), "40 minutes");
+        assert_eq!(to_age(Duration::from_secs(119 * 60)), "119 minutes");
+        assert_eq!(to_age(Duration::from_secs(150 * 60)), "2 hours");
+    }
+
+    #[test]
+    fn clauses_keep_the_capital_of_i() {
+        assert_eq!(to_clause("It is raining."), "it is raining.");
+        assert_eq!(to_clause("Ice is forming."), "ice is forming.");
+        assert_eq!(to_clause("I wouldn't expect any snow."), "I wouldn't expect any snow.");
+        assert_eq!(to_clause("I'm not sure."), "I'm not sure.");
+        assert_eq!(to_clause(""), "");
+    }
+}
@@ -1,70 +1,665 @@
-use std::io::{BufReader, Error, Read};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::io::{BufReader, Error, ErrorKind, Read};
+use std::net::{IpAddr, SocketAddr};
 use std::str;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use futures::future;
+use futures::{future, Async, Future, Poll};
 
-use multipart::server::Multipart;
+use json::{self, JsonValue};
 
-use num_cpus;
+use multipart::server::Multipart;
 
 use tokio_minihttp::{Http, Request, Response};
-use tokio_proto::TcpServer;
 use tokio_service::Service;
 
+use urlencoding::decode;
+
+use auth::{try_get_bearer_token, Authenticator, Principal};
+use channel::{webhook_channels, Channel};
+use cli::{get_tls_cert_file, get_tls_key_file};
+use cors::{CorsPolicy, HEADER_ACCESS_CONTROL_REQUEST_HEADERS,
+           HEADER_ACCESS_CONTROL_REQUEST_METHOD, HEADER_ORIGIN};
+use google::GoogleApi;
 use harris::{Event, Harris, Join, Message};
+use health::Health;
+use intent::{Card, IntentSummary, Reply};
+use logging::{self, to_millis, Level, RequestLog};
+use metrics::{observe_http_request, render};
+use rate_limit::RateLimits;
+use session::SessionStore;
+use shutdown::{serve_until_stopped, Shutdown};
+use slack::{forget_delivery, SlackChannel, SlackRequest};
+use subscription::{SubscriptionStore, DEFAULT_HOUR, DEFAULT_MINUTE};
+use tls::TlsHttp;
+use webhook::Deferred;
 
 // Panics
 const PANIC_UNACCEPTABLE_HTTP_BINDING: &'static str = "Unacceptable http binding";
+const PANIC_UNACCEPTABLE_TLS_CERT_OR_KEY: &'static str = "Unacceptable tls cert or key";
 
 // Headers
-const HEADER_ACCESS_CONTROL_ALLOW_ORIGIN: &'static str = "Access-Control-Allow-Origin";
-const HEADER_ACCESS_CONTROL_ALLOW_ORIGIN_STAR: &'static str = "*";
+const HEADER_ALLOW: &'static str = "Allow";
+const HEADER_AUTHORIZATION: &'static str = "Authorization";
 const HEADER_BOUNDARY: &'static str = "boundary";
 const HEADER_CONTENT_TYPE: &'static str = "Content-Type";
-const HEADER_ORIGIN: &'static str = "Origin";
+const HEADER_RETRY_AFTER: &'static str = "Retry-After";
+const HEADER_WWW_AUTHENTICATE: &'static str = "WWW-Authenticate";
+const HEADER_X_API_KEY: &'static str = "X-Api-Key";
+const HEADER_X_FORWARDED_FOR: &'static str = "X-Forwarded-For";
+const HEADER_X_REAL_IP: &'static str = "X-Real-IP";
+const HEADER_X_REQUEST_ID: &'static str = "X-Request-Id";
+
+// Error codes
+const ERROR_CODE_CORS_REJECTED: &'static str = "cors_rejected";
+const ERROR_CODE_FORBIDDEN: &'static str = "forbidden";
+const ERROR_CODE_INVALID_FIELD: &'static str = "invalid_field";
+const ERROR_CODE_INVALID_SIGNATURE: &'static str = "invalid_signature";
+const ERROR_CODE_MALFORMED_BODY: &'static str = "malformed_body";
+const ERROR_CODE_METHOD_NOT_ALLOWED: &'static str = "method_not_allowed";
+const ERROR_CODE_MISSING_FIELD: &'static str = "missing_field";
+const ERROR_CODE_NOT_FOUND: &'static str = "not_found";
+const ERROR_CODE_RATE_LIMITED: &'static str = "rate_limited";
+const ERROR_CODE_UNAUTHORIZED: &'static str = "unauthorized";
+const ERROR_CODE_UNAVAILABLE: &'static str = "unavailable";
+const ERROR_CODE_UNKNOWN_ACTION: &'static str = "unknown_action";
+const ERROR_CODE_UNKNOWN_SUBSCRIPTION: &'static str = "unknown_subscription";
+const ERROR_CODE_UNSUPPORTED_CONTENT_TYPE: &'static str = "unsupported_content_type";
 
 // Methods
+const METHOD_DELETE: &'static str = "DELETE";
+const METHOD_GET: &'static str = "GET";
+const METHOD_OPTIONS: &'static str = "OPTIONS";
 const METHOD_POST: &'static str = "POST";
+const METHOD_PUT: &'static str = "PUT";
 
 // Mime types
-const MIME_TYPE_APPLICATION_JSON: &'static str = "application/json";
+pub const MIME_TYPE_APPLICATION_JSON: &'static str = "application/json";
+pub const MIME_TYPE_APPLICATION_X_WWW_FORM_URLENCODED: &'static str =
+    "application/x-www-form-urlencoded";
 const MIME_TYPE_MULTIPART_FORM_DATA: &'static str = "multipart/form-data";
 
 // Status codes
+const STATUS_CODE_ACCEPTED_ALPHA: &'static str = "accepted";
+const STATUS_CODE_ACCEPTED_NUMERIC: u32 = 202;
 const STATUS_CODE_BAD_REQUEST_ALPHA: &'static str = "bad request";
 const STATUS_CODE_BAD_REQUEST_NUMERIC: u32 = 400;
+const STATUS_CODE_CREATED_ALPHA: &'static str = "created";
+const STATUS_CODE_CREATED_NUMERIC: u32 = 201;
+const STATUS_CODE_FORBIDDEN_ALPHA: &'static str = "forbidden";
+const STATUS_CODE_FORBIDDEN_NUMERIC: u32 = 403;
+const STATUS_CODE_METHOD_NOT_ALLOWED_ALPHA: &'static str = "method not allowed";
+const STATUS_CODE_METHOD_NOT_ALLOWED_NUMERIC: u32 = 405;
+const STATUS_CODE_NO_CONTENT_ALPHA: &'static str = "no content";
+const STATUS_CODE_NO_CONTENT_NUMERIC: u32 = 204;
 const STATUS_CODE_NOT_FOUND_ALPHA: &'static str = "not found";
 const STATUS_CODE_NOT_FOUND_NUMERIC: u32 = 404;
+const STATUS_CODE_OK_ALPHA: &'static str = "ok";
+const STATUS_CODE_OK_NUMERIC: u32 = 200;
+const STATUS_CODE_SERVICE_UNAVAILABLE_ALPHA: &'static str = "service unavailable";
+const STATUS_CODE_SERVICE_UNAVAILABLE_NUMERIC: u32 = 503;
+const STATUS_CODE_TOO_MANY_REQUESTS_ALPHA: &'static str = "too many requests";
+const STATUS_CODE_TOO_MANY_REQUESTS_NUMERIC: u32 = 429;
+const STATUS_CODE_UNAUTHORIZED_ALPHA: &'static str = "unauthorized";
+const STATUS_CODE_UNAUTHORIZED_NUMERIC: u32 = 401;
+const STATUS_CODE_UNSUPPORTED_MEDIA_TYPE_ALPHA: &'static str = "unsupported media type";
+const STATUS_CODE_UNSUPPORTED_MEDIA_TYPE_NUMERIC: u32 = 415;
 
 // Routes
+const ROUTE_CHANNELS: &'static str = "/channels/";
 const ROUTE_CHAT_MESSAGES: &'static str = "/chat/messages";
+const ROUTE_HEALTHZ: &'static str = "/healthz";
+const ROUTE_METRICS: &'static str = "/metrics";
+const ROUTE_NOTIFICATIONS: &'static str = "/notifications/";
+const ROUTE_READYZ: &'static str = "/readyz";
+const ROUTE_SLACK: &'static str = "/slack";
+const ROUTE_SUBSCRIPTIONS: &'static str = "/subscriptions";
+const ROUTE_VERSION: &'static str = "/version";
 
 // Form data fields
 const FORM_DATA_ACTION: &'static str = "action";
+const FORM_DATA_ASYNC: &'static str = "async";
+const FORM_DATA_CONDITION: &'static str = "condition";
+const FORM_DATA_ID: &'static str = "id";
 const FORM_DATA_NAME: &'static str = "name";
+const FORM_DATA_PLACE: &'static str = "place";
 const FORM_DATA_TEXT: &'static str = "text";
-const FORM_DATA_USER_ID: &'static str = "user_id";
+const FORM_DATA_TIME: &'static str = "time";
+pub const FORM_DATA_USER_ID: &'static str = "user_id";
 
 // Actions
 const ACTION_JOIN: &'static str = "join";
 const ACTION_MESSAGE: &'static str = "message";
 
-fn bad_request() -> Response {
-    let mut response = Response::new();
-    response.status_code(
-        STATUS_CODE_BAD_REQUEST_NUMERIC,
-        STATUS_CODE_BAD_REQUEST_ALPHA,
-    );
+// A response won't tell us its status code once it's set, so the last one set on this thread
+// is remembered for the metrics; requests take turns on a worker, so each one's status code is
+// put back before it is worked on (see Answering)
+thread_local!(static LAST_STATUS_CODE: Cell<u32> = Cell::new(STATUS_CODE_OK_NUMERIC));
+
+/// A response which may still be waiting on upstream services.
+type ResponseFuture = Box<Future<Item = Response, Error = ()>>;
+
+/// Everything that can go wrong with a request, as reported back to the client. The code is
+/// stable and meant for machines; the message is meant for people.
+pub struct ApiError {
+    code: &'static str,
+    field: Option<&'static str>,
+    message: String,
+    status_code: (u32, &'static str),
+}
+
+impl ApiError {
+    pub fn cors_rejected(message: &str) -> Self {
+        Self {
+            code: ERROR_CODE_CORS_REJECTED,
+            field: None,
+            message: message.to_owned(),
+            status_code: (STATUS_CODE_FORBIDDEN_NUMERIC, STATUS_CODE_FORBIDDEN_ALPHA),
+        }
+    }
+
+    pub fn forbidden(field: &'static str, message: &str) -> Self {
+        Self {
+            code: ERROR_CODE_FORBIDDEN,
+            field: Some(field),
+            message: message.to_owned(),
+            status_code: (STATUS_CODE_FORBIDDEN_NUMERIC, STATUS_CODE_FORBIDDEN_ALPHA),
+        }
+    }
+
+    pub fn invalid_field(field: &'static str, message: &str) -> Self {
+        Self {
+            code: ERROR_CODE_INVALID_FIELD,
+            field: Some(field),
+            message: message.to_owned(),
+            status_code: (STATUS_CODE_BAD_REQUEST_NUMERIC, STATUS_CODE_BAD_REQUEST_ALPHA),
+        }
+    }
+
+    pub fn invalid_signature(message: &str) -> Self {
+        Self {
+            code: ERROR_CODE_INVALID_SIGNATURE,
+            field: None,
+            message: message.to_owned(),
+            status_code: (
+                STATUS_CODE_UNAUTHORIZED_NUMERIC,
+                STATUS_CODE_UNAUTHORIZED_ALPHA,
+            ),
+        }
+    }
+
+    pub fn malformed_body(message: &str) -> Self {
+        Self {
+            code: ERROR_CODE_MALFORMED_BODY,
+            field: None,
+            message: message.to_owned(),
+            status_code: (STATUS_CODE_BAD_REQUEST_NUMERIC, STATUS_CODE_BAD_REQUEST_ALPHA),
+        }
+    }
+
+    fn method_not_allowed(method: &str) -> Self {
+        Self {
+            code: ERROR_CODE_METHOD_NOT_ALLOWED,
+            field: None,
+            message: format!("Method {} is not allowed on this route", method),
+            status_code: (
+                STATUS_CODE_METHOD_NOT_ALLOWED_NUMERIC,
+                STATUS_CODE_METHOD_NOT_ALLOWED_ALPHA,
+            ),
+        }
+    }
+
+    fn missing_field(field: &'static str) -> Self {
+        Self {
+            code: ERROR_CODE_MISSING_FIELD,
+            field: Some(field),
+            message: format!("The {} field is required", field),
+            status_code: (STATUS_CODE_BAD_REQUEST_NUMERIC, STATUS_CODE_BAD_REQUEST_ALPHA),
+        }
+    }
+
+    pub fn not_found(path: &str) -> Self {
+        Self {
+            code: ERROR_CODE_NOT_FOUND,
+            field: None,
+            message: format!("No route matches {}", path),
+            status_code: (STATUS_CODE_NOT_FOUND_NUMERIC, STATUS_CODE_NOT_FOUND_ALPHA),
+        }
+    }
+
+    pub fn rate_limited(retry_after_secs: u64) -> Self {
+        Self {
+            code: ERROR_CODE_RATE_LIMITED,
+            field: None,
+            message: format!(
+                "Too many requests; try again in {} seconds",
+                retry_after_secs
+            ),
+            status_code: (
+                STATUS_CODE_TOO_MANY_REQUESTS_NUMERIC,
+                STATUS_CODE_TOO_MANY_REQUESTS_ALPHA,
+            ),
+        }
+    }
+
+    pub fn unauthorized(message: &str) -> Self {
+        Self {
+            code: ERROR_CODE_UNAUTHORIZED,
+            field: None,
+            message: message.to_owned(),
+            status_code: (
+                STATUS_CODE_UNAUTHORIZED_NUMERIC,
+                STATUS_CODE_UNAUTHORIZED_ALPHA,
+            ),
+        }
+    }
+
+    pub fn unavailable(message: &str) -> Self {
+        Self {
+            code: ERROR_CODE_UNAVAILABLE,
+            field: None,
+            message: message.to_owned(),
+            status_code: (
+                STATUS_CODE_SERVICE_UNAVAILABLE_NUMERIC,
+                STATUS_CODE_SERVICE_UNAVAILABLE_ALPHA,
+            ),
+        }
+    }
+
+    fn unknown_action(action: &str) -> Self {
+        Self {
+            code: ERROR_CODE_UNKNOWN_ACTION,
+            field: Some(FORM_DATA_ACTION),
+            message: format!(
+                "Unknown action {}; expected {} or {}",
+                action, ACTION_JOIN, ACTION_MESSAGE
+            ),
+            status_code: (STATUS_CODE_BAD_REQUEST_NUMERIC, STATUS_CODE_BAD_REQUEST_ALPHA),
+        }
+    }
+
+    fn unknown_subscription(id: u64) -> Self {
+        Self {
+            code: ERROR_CODE_UNKNOWN_SUBSCRIPTION,
+            field: Some(FORM_DATA_ID),
+            message: format!("No subscription {} exists for this user", id),
+            status_code: (STATUS_CODE_NOT_FOUND_NUMERIC, STATUS_CODE_NOT_FOUND_ALPHA),
+        }
+    }
+
+    pub fn unsupported_content_type() -> Self {
+        Self {
+            code: ERROR_CODE_UNSUPPORTED_CONTENT_TYPE,
+            field: None,
+            message: format!(
+                "Content-Type must be {}, {} or {}",
+                MIME_TYPE_APPLICATION_JSON,
+                MIME_TYPE_APPLICATION_X_WWW_FORM_URLENCODED,
+                MIME_TYPE_MULTIPART_FORM_DATA
+            ),
+            status_code: (
+                STATUS_CODE_UNSUPPORTED_MEDIA_TYPE_NUMERIC,
+                STATUS_CODE_UNSUPPORTED_MEDIA_TYPE_ALPHA,
+            ),
+        }
+    }
+
+    fn into_response(self) -> Response {
+        let mut response = new_response(self.status_code);
+        response.header(HEADER_CONTENT_TYPE, MIME_TYPE_APPLICATION_JSON);
+        response.body(&self.to_json().dump());
+        response
+    }
+
+    pub fn status_code(&self) -> (u32, &'static str) {
+        self.status_code
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        object!{
+            "error" => object!{
+                "code" => self.code,
+                "message" => self.message.as_str(),
+                "field" => self.field,
+            },
+        }
+    }
+}
+
+/// The fields a request body carries, however the client chose to encode them.
+#[derive(Default)]
+pub struct Fields {
+    values: HashMap<String, String>,
+}
+
+impl Fields {
+    pub fn try_from_json(body: &[u8]) -> Result<Self, ApiError> {
+        // Sanity check: Must be a utf8 json object
+        let body =
+            str::from_utf8(body).map_err(|_| ApiError::malformed_body("The body must be utf8"))?;
+        let body = json::parse(body).map_err(|e| ApiError::malformed_body(&e.to_string()))?;
+        if !body.is_object() {
+            return Err(ApiError::malformed_body("The body must be a json object"));
+        }
+
+        // Fields may be strings or numbers (user_id especially), anything else is ignored
+        let mut fields = Self::default();
+        for (key, val) in body.entries() {
+            if let Some(v) = val.as_str() {
+                fields.set(key, v);
+            } else if val.is_number() {
+                fields.set(key, val.dump());
+            }
+        }
+
+        Ok(fields)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(|v| v.as_str())
+    }
+
+    pub fn require(&self, key: &'static str) -> Result<&str, ApiError> {
+        self.get(key).ok_or_else(|| ApiError::missing_field(key))
+    }
+
+    pub fn set<K: AsRef<str>, V: Into<String>>(&mut self, key: K, val: V) {
+        self.values
+            .insert(key.as_ref().trim().to_lowercase(), val.into());
+    }
+
+    pub fn try_get_user_id(&self) -> Result<u64, ApiError> {
+        // Sanity check: user_id should be numeric
+        self.require(FORM_DATA_USER_ID)?
+            .trim()
+            .parse()
+            .map_err(|_| {
+                ApiError::invalid_field(FORM_DATA_USER_ID, "The user_id field must be numeric")
+            })
+    }
+
+    pub fn try_into_event(self) -> Result<Event, ApiError> {
+        // Sanity check: We should have action and user_id
+        let action = self.require(FORM_DATA_ACTION)?.trim().to_lowercase();
+        let user_id = self.try_get_user_id()?;
+
+        // Parse the correct message type
+        match action.as_ref() {
+            ACTION_JOIN => {
+                // Sanity check: We should have name
+                let name = self.require(FORM_DATA_NAME)?;
+
+                Ok(Event::Join(Join::new(user_id, name)))
+            }
+            ACTION_MESSAGE => {
+                // Sanity check: We should have text
+                let text = self.require(FORM_DATA_TEXT)?;
+
+                Ok(Event::Message(Message::new(user_id, text)))
+            }
+            action => Err(ApiError::unknown_action(action)),
+        }
+    }
+}
+
+fn json_response(status_code: (u32, &'static str), body: JsonValue) -> Response {
+    let mut response = new_response(status_code);
+    response.header(HEADER_CONTENT_TYPE, MIME_TYPE_APPLICATION_JSON);
+    response.body(&body.dump());
     response
 }
 
-fn not_found() -> Response {
+/// A response with the status code set, which is remembered for the metrics.
+fn new_response(status_code: (u32, &'static str)) -> Response {
+    let (status_code_numeric, status_code_alpha) = status_code;
+    LAST_STATUS_CODE.with(|s| s.set(status_code_numeric));
+
     let mut response = Response::new();
-    response.status_code(STATUS_CODE_NOT_FOUND_NUMERIC, STATUS_CODE_NOT_FOUND_ALPHA);
+    response.status_code(status_code_numeric, status_code_alpha);
+    response
+}
+
+fn ready(response: Response) -> ResponseFuture {
+    Box::new(future::ok(response))
+}
+
+fn method_not_allowed(method: &str, allow: &str) -> Response {
+    let mut response = ApiError::method_not_allowed(method).into_response();
+    response.header(HEADER_ALLOW, allow);
+    response
+}
+
+/// Applies the authenticated subject, if any, to the user_id the fields carry.
+pub fn authorize_fields(
+    principal: Option<&Principal>,
+    fields: &mut Fields,
+) -> Result<(), ApiError> {
+    let principal = match principal {
+        Some(principal) => principal,
+        None => return Ok(()),
+    };
+
+    let requested = match fields.get(FORM_DATA_USER_ID) {
+        Some(_) => Some(fields.try_get_user_id()?),
+        None => None,
+    };
+    let user_id = principal
+        .resolve_user_id(requested)
+        .map_err(|e| ApiError::forbidden(FORM_DATA_USER_ID, &e))?;
+    if let Some(user_id) = user_id {
+        fields.set(FORM_DATA_USER_ID, user_id.to_string());
+    }
+
+    Ok(())
+}
+
+/// Applies the authenticated subject, if any, to a user_id from the path.
+pub fn authorize_user_id(principal: Option<&Principal>, user_id: &str) -> Result<u64, ApiError> {
+    let user_id = try_parse_id(FORM_DATA_USER_ID, user_id)?;
+    match principal {
+        Some(principal) => principal
+            .resolve_user_id(Some(user_id))
+            .map(|u| u.unwrap_or(user_id))
+            .map_err(|e| ApiError::forbidden(FORM_DATA_USER_ID, &e)),
+        None => Ok(user_id),
+    }
+}
+
+/// Rounds up to whole seconds for Retry-After, never zero as that would invite an immediate
+/// retry.
+pub fn to_retry_after_secs(retry_after: Duration) -> u64 {
+    let mut retry_after_secs = retry_after.as_secs();
+    if retry_after.subsec_nanos() > 0 || retry_after_secs == 0 {
+        retry_after_secs += 1;
+    }
+
+    retry_after_secs
+}
+
+fn too_many_requests(retry_after: Duration) -> Response {
+    let retry_after_secs = to_retry_after_secs(retry_after);
+    let mut response = ApiError::rate_limited(retry_after_secs).into_response();
+    response.header(HEADER_RETRY_AFTER, &retry_after_secs.to_string());
     response
 }
 
-fn try_get_header(request: &Request, key: &str) -> Option<String> {
+/// The method for the metrics; anything we don't serve is lumped together.
+fn method_label(method: &str) -> &'static str {
+    match method {
+        METHOD_DELETE => METHOD_DELETE,
+        METHOD_GET => METHOD_GET,
+        METHOD_OPTIONS => METHOD_OPTIONS,
+        METHOD_POST => METHOD_POST,
+        METHOD_PUT => METHOD_PUT,
+        _ => "OTHER",
+    }
+}
+
+/// The route for the metrics, without any ids in it so there isn't a series per user.
+fn route_label(path: &str) -> &'static str {
+    match path {
+        ROUTE_CHAT_MESSAGES => ROUTE_CHAT_MESSAGES,
+        ROUTE_HEALTHZ => ROUTE_HEALTHZ,
+        ROUTE_METRICS => ROUTE_METRICS,
+        ROUTE_READYZ => ROUTE_READYZ,
+        ROUTE_SLACK => ROUTE_SLACK,
+        ROUTE_SUBSCRIPTIONS => ROUTE_SUBSCRIPTIONS,
+        ROUTE_VERSION => ROUTE_VERSION,
+        path if path.starts_with(ROUTE_CHANNELS) => "/channels/{name}",
+        path if path.starts_with(ROUTE_NOTIFICATIONS) => "/notifications/{user_id}",
+        path if path.starts_with(&format!("{}/", ROUTE_SUBSCRIPTIONS)) => {
+            match path[ROUTE_SUBSCRIPTIONS.len()..]
+                .split('/')
+                .filter(|i| !i.is_empty())
+                .count()
+            {
+                1 => "/subscriptions/{user_id}",
+                2 => "/subscriptions/{user_id}/{id}",
+                _ => "other",
+            }
+        }
+        _ => "other",
+    }
+}
+
+fn card_to_json(card: Card) -> JsonValue {
+    match card {
+        Card::Forecast(f) => object!{
+            "type" => "forecast",
+            "tense" => f.tense,
+            "condition" => f.condition,
+            "summary" => f.prediction.summary,
+            "icon" => f.prediction.icon,
+            "humidity" => f.prediction.humidity,
+            "is_haily" => f.prediction.is_haily,
+            "is_rainy" => f.prediction.is_rainy,
+            "is_snowy" => f.prediction.is_snowy,
+            "uv_index" => f.prediction.uv_index,
+            "wind_speed" => f.prediction.wind_speed,
+        },
+        Card::Place(p) => object!{
+            "type" => "place",
+            "name" => p.name,
+            "lat" => p.lat,
+            "lng" => p.lng,
+        },
+    }
+}
+
+fn intent_to_json(intent: IntentSummary) -> JsonValue {
+    let mut slots = JsonValue::new_array();
+    for slot in intent.slots {
+        // This will always succeed because slots is an array
+        slots
+            .push(object!{
+                "name" => slot.name,
+                "entity" => slot.entity,
+                "raw_value" => slot.raw_value,
+                "value" => slot.value,
+            })
+            .unwrap();
+    }
+
+    object!{
+        "type" => "intent",
+        "name" => intent.name,
+        "probability" => intent.probability,
+        "slots" => slots,
+    }
+}
+
+/// Produces the messages array: the text message always comes first and is followed by the
+/// cards and what the nlu engine understood, for clients which can render them.
+pub fn reply_to_json(reply: Reply) -> JsonValue {
+    let mut messages = array![object!{
+        "type" => "text",
+        "text" => reply.text,
+    }];
+
+    // These will always succeed because messages is an array
+    for card in reply.cards {
+        messages.push(card_to_json(card)).unwrap();
+    }
+
+    if let Some(intent) = reply.intent {
+        messages.push(intent_to_json(intent)).unwrap();
+    }
+
+    messages
+}
+
+/// The credentials from either the X-Api-Key header or an Authorization bearer token.
+fn try_get_credentials(request: &Request) -> Option<String> {
+    if let Some(api_key) = try_get_header(request, HEADER_X_API_KEY) {
+        return Some(api_key);
+    }
+
+    try_get_header(request, HEADER_AUTHORIZATION).and_then(|a| try_get_bearer_token(&a))
+}
+
+fn try_get_fields(request: &Request) -> Result<Fields, ApiError> {
+    match try_get_mime_type(request) {
+        Some(ref m) if m == MIME_TYPE_APPLICATION_JSON => {
+            Fields::try_from_json(request.body())
+        }
+        Some(ref m) if m == MIME_TYPE_APPLICATION_X_WWW_FORM_URLENCODED => {
+            try_get_urlencoded_fields(request)
+        }
+        Some(ref m) if m == MIME_TYPE_MULTIPART_FORM_DATA => try_get_multipart_fields(request),
+        _ => Err(ApiError::unsupported_content_type()),
+    }
+}
+
+fn try_get_multipart_fields(request: &Request) -> Result<Fields, ApiError> {
+    // Sanity check: Must have multipart data
+    let mut multipart = try_get_multipart(request)?;
+
+    // Parse out the fields from all requests
+    let mut fields = Fields::default();
+    let iter = multipart.foreach_entry(|e| {
+        let key = e.headers.name.to_string();
+        if let Some(val) = try_parse_utf8(e.data) {
+            fields.set(&key, val);
+        }
+    });
+
+    // Sanity check: The body must have been well formed
+    if let Err(e) = iter {
+        return Err(ApiError::malformed_body(&e.to_string()));
+    }
+
+    Ok(fields)
+}
+
+pub fn try_get_urlencoded_fields(request: &Request) -> Result<Fields, ApiError> {
+    // Sanity check: Must be utf8
+    let body = str::from_utf8(request.body())
+        .map_err(|_| ApiError::malformed_body("The body must be utf8"))?;
+
+    // This is the body we're parsing: action=message&user_id=1&text=Is+it+raining%3F
+    let mut fields = Fields::default();
+    for pair in body.split('&').filter(|p| !p.is_empty()) {
+        let mut parts = pair.splitn(2, '=');
+        let key = decode(&parts.next().unwrap_or("").replace('+', " "));
+        let val = decode(&parts.next().unwrap_or("").replace('+', " "));
+
+        // Sanity check: Both halves must be valid percent-encoding
+        if key.is_err() || val.is_err() {
+            return Err(ApiError::malformed_body(&format!(
+                "Invalid percent-encoding in {}",
+                pair
+            )));
+        }
+
+        fields.set(&key.unwrap(), val.unwrap());
+    }
+
+    Ok(fields)
+}
+
+pub fn try_get_header(request: &Request, key: &str) -> Option<String> {
     let key = key.to_lowercase();
     for (header_key, header_val) in request.headers() {
         if key == header_key.to_lowercase().trim() {
@@ -77,13 +672,19 @@ fn try_get_header(request: &Request, key: &str) -> Option<String> {
     None
 }
 
-fn try_get_multipart(request: &Request) -> Option<Multipart<&[u8]>> {
+/// Returns the lowercase mime type from the Content-Type header, without any parameters.
+pub fn try_get_mime_type(request: &Request) -> Option<String> {
+    try_get_header(request, HEADER_CONTENT_TYPE)
+        .and_then(|c| c.split(';').next().map(|m| m.trim().to_lowercase()))
+}
+
+fn try_get_multipart(request: &Request) -> Result<Multipart<&[u8]>, ApiError> {
     // Get any required headers
     let content_type = try_get_header(request, HEADER_CONTENT_TYPE);
 
     // Sanity check: Must have Content-Type
     if let None = content_type {
-        return None;
+        return Err(ApiError::unsupported_content_type());
     }
 
     // This will always succeed because it's not-none
@@ -91,10 +692,15 @@ fn try_get_multipart(request: &Request) -> Option<Multipart<&[u8]>> {
     let content_type_parts: Vec<&str> = content_type.split(';').collect();
 
     // Sanity check: Content-Type must be form data
-    if content_type_parts.len() < 2
-        || content_type_parts[0].to_lowercase().trim() != MIME_TYPE_MULTIPART_FORM_DATA
-    {
-        return None;
+    if content_type_parts[0].to_lowercase().trim() != MIME_TYPE_MULTIPART_FORM_DATA {
+        return Err(ApiError::unsupported_content_type());
+    }
+
+    // Sanity check: Content-Type must have parameters
+    if content_type_parts.len() < 2 {
+        return Err(ApiError::malformed_body(
+            "Content-Type is missing the multipart boundary",
+        ));
     }
 
     // This is the header we're parsing to find the boundary:
@@ -103,12 +709,38 @@ fn try_get_multipart(request: &Request) -> Option<Multipart<&[u8]>> {
 
     // Sanity check: Content-Type must have boundary
     if boundary_parts.len() != 2 || boundary_parts[0].to_lowercase().trim() != HEADER_BOUNDARY {
-        return None;
+        return Err(ApiError::malformed_body(
+            "Content-Type has a malformed multipart boundary",
+        ));
     }
 
     // This will always succeed because we checked above
     let boundary = boundary_parts[1].trim();
-    Some(Multipart::with_body(request.body(), boundary))
+    Ok(Multipart::with_body(request.body(), boundary))
+}
+
+pub fn try_parse_id(field: &'static str, id: &str) -> Result<u64, ApiError> {
+    id.trim()
+        .parse()
+        .map_err(|_| ApiError::invalid_field(field, &format!("The {} must be numeric", field)))
+}
+
+/// Parses a 24 hour "HH:MM" time of day.
+fn try_parse_time(time: &str) -> Result<(u32, u32), ApiError> {
+    let parts: Vec<u32> = time.trim()
+        .split(':')
+        .map(|t| t.parse().unwrap_or(u32::max_value()))
+        .collect();
+
+    // Sanity check: Must be exactly an hour and a minute
+    if parts.len() != 2 || parts[0] > 23 || parts[1] > 59 {
+        return Err(ApiError::invalid_field(
+            FORM_DATA_TIME,
+            "The time field must be a 24 hour HH:MM time",
+        ));
+    }
+
+    Ok((parts[0], parts[1]))
 }
 
 fn try_parse_utf8<R: Read>(data: R) -> Option<String> {
@@ -123,106 +755,525 @@ fn try_parse_utf8<R: Read>(data: R) -> Option<String> {
 }
 
 pub struct Router {
+    auth: Authenticator,
+    channels: Vec<Arc<Channel + Sync>>,
+    cors: CorsPolicy,
+    deferred: Deferred,
+    google_api: GoogleApi,
     harris: Harris,
+    health: Health,
+    /// The address on the other end of the connection, which may be a proxy
+    peer: IpAddr,
+    rate_limits: RateLimits,
+    sessions: SessionStore,
+    slack: Option<Arc<SlackChannel>>,
+    subscriptions: SubscriptionStore,
 }
 
 impl Router {
-    pub fn serve_forever(http_binding: &str) {
+    /// Serves until we are asked to stop and the requests in flight have been answered.
+    pub fn serve_until_stopped(
+        http_binding: &str,
+        sessions: SessionStore,
+        subscriptions: SubscriptionStore,
+        deferred: Deferred,
+        cors: CorsPolicy,
+        rate_limits: RateLimits,
+        shutdown: &Shutdown,
+    ) {
         // Parse input string into the tokio address type
         let http_binding = http_binding.parse().expect(PANIC_UNACCEPTABLE_HTTP_BINDING);
 
+        // Every connection shares the same user sessions, subscriptions, rate limits, health
+        // and chat networks (whose clients are made once, here)
         // The new webserver will use a thread per core
-        let mut server = TcpServer::new(Http, http_binding);
-        server.threads(num_cpus::get());
-        server.serve(|| Ok(Router::default()));
-    }
-
-    fn chat_messages(&self, request: &Request) -> Response {
-        // Sanity check: Must have multipart data
-        let multipart = try_get_multipart(request);
-        if let None = multipart {
-            return bad_request();
-        }
-
-        // Parse out the fields from all requests
-        let mut action = None;
-        let mut user_id = None;
-        let mut name = None;
-        let mut text = None;
-        let mut multipart = multipart.unwrap();
-        let iter =
-            multipart.foreach_entry(|e| match e.headers.name.trim().to_lowercase().as_ref() {
-                FORM_DATA_ACTION => action = try_parse_utf8(e.data),
-                FORM_DATA_NAME => name = try_parse_utf8(e.data),
-                FORM_DATA_TEXT => text = try_parse_utf8(e.data),
-                FORM_DATA_USER_ID => user_id = try_parse_utf8(e.data),
-                _ => (),
-            });
+        let channels = webhook_channels();
+        let health = Health::default();
+        let slack = SlackChannel::try_new().map(Arc::new);
+        let new_router = move |peer: SocketAddr| Router {
+            auth: Default::default(),
+            channels: channels.clone(),
+            cors: cors.clone(),
+            deferred: deferred.clone(),
+            google_api: Default::default(),
+            harris: Harris::new(sessions.clone(), subscriptions.clone()),
+            health: health.clone(),
+            peer: peer.ip(),
+            rate_limits: rate_limits.clone(),
+            sessions: sessions.clone(),
+            slack: slack.clone(),
+            subscriptions: subscriptions.clone(),
+        };
 
-        // Sanity check: We should have action and user_id
-        if iter.is_err() || action.is_none() || user_id.is_none() {
-            return bad_request();
+        // Serve https when given a certificate, otherwise plain http
+        match (get_tls_cert_file(), get_tls_key_file()) {
+            (Some(cert_file), Some(key_file)) => {
+                let tls_http = TlsHttp::new(&cert_file, &key_file);
+                tls_http.reload_on_sighup(cert_file, key_file);
+                serve_until_stopped(http_binding, tls_http, new_router, shutdown);
+            }
+            (None, None) => serve_until_stopped(http_binding, Http, new_router, shutdown),
+            _ => panic!(PANIC_UNACCEPTABLE_TLS_CERT_OR_KEY),
+        }
+    }
+
+    fn channel(&self, request: &Request, name: &str) -> Result<ResponseFuture, ApiError> {
+        // Sanity check: Must be a configured channel, and the request must be from it
+        let channel = self.channels
+            .iter()
+            .find(|c| c.name() == name)
+            .ok_or_else(|| ApiError::not_found(request.path()))?;
+        if !channel.accepts(request) {
+            return Err(ApiError::invalid_signature(
+                "The request is not from this channel",
+            ));
         }
 
-        // Sanity check: user_id should be numeric
-        let user_id: Result<u64, _> = user_id.unwrap().trim().parse();
-        if let Err(_) = user_id {
-            return bad_request();
+        // Sanity check: Updates are always json
+        let updates = str::from_utf8(request.body())
+            .map_err(|_| ApiError::malformed_body("The body must be utf8"))?;
+        let updates = json::parse(updates).map_err(|e| ApiError::malformed_body(&e.to_string()))?;
+
+        self.answer_channel(channel.clone(), updates)
+    }
+
+    /// Acknowledges the updates straight away, as networks give up on us (and try again) if
+    /// we are slow; the messages are answered in the background and the replies go back over
+    /// the channel.
+    fn answer_channel(
+        &self,
+        channel: Arc<Channel + Sync>,
+        updates: JsonValue,
+    ) -> Result<ResponseFuture, ApiError> {
+        if !self.deferred.defer_updates(channel, updates) {
+            return Err(ApiError::unavailable("Unable to answer right now"));
         }
 
-        // Parse the correct message type
-        let msg = match action.unwrap().trim().to_lowercase().as_ref() {
-            ACTION_JOIN => {
-                // Sanity check: We should have name
-                if name.is_none() {
-                    return bad_request();
+        Ok(ready(json_response(
+            (STATUS_CODE_OK_NUMERIC, STATUS_CODE_OK_ALPHA),
+            JsonValue::new_object(),
+        )))
+    }
+
+    fn chat_messages(
+        &self,
+        request: &Request,
+        principal: Option<&Principal>,
+    ) -> Result<ResponseFuture, ApiError> {
+        // Sanity check: Must have a body we understand, for a user we may speak for
+        let mut fields = try_get_fields(request)?;
+        authorize_fields(principal, &mut fields)?;
+        if let Some(response) = self.limit_user(&fields) {
+            return Ok(ready(response));
+        }
+
+        let is_async = match fields.get(FORM_DATA_ASYNC) {
+            Some(a) => a.trim() == "1" || a.trim().to_lowercase() == "true",
+            None => false,
+        };
+        let msg = fields.try_into_event()?;
+
+        // Async requests are answered later by posting to the webhook
+        if is_async {
+            let request_id = self.deferred.defer(msg).ok_or_else(|| {
+                ApiError::invalid_field(FORM_DATA_ASYNC, "No webhook is configured for replies")
+            })?;
+
+            return Ok(ready(json_response(
+                (STATUS_CODE_ACCEPTED_NUMERIC, STATUS_CODE_ACCEPTED_ALPHA),
+                object!{
+                    "request_id" => request_id,
+                },
+            )));
+        }
+
+        // Process the chat logic and produce a one-liner response plus any structured data
+        let reply = self.harris.respond(&msg);
+
+        // Respond to the client using json; the text message always comes first
+        Ok(Box::new(reply.map(|reply| {
+            json_response(
+                (STATUS_CODE_OK_NUMERIC, STATUS_CODE_OK_ALPHA),
+                object!{
+                    "messages" => reply_to_json(reply),
+                },
+            )
+        })))
+    }
+
+    fn create_subscription(
+        &self,
+        request: &Request,
+        principal: Option<&Principal>,
+    ) -> Result<ResponseFuture, ApiError> {
+        // Sanity check: We should have user_id and place; the time defaults to the morning
+        let mut fields = try_get_fields(request)?;
+        authorize_fields(principal, &mut fields)?;
+        if let Some(response) = self.limit_user(&fields) {
+            return Ok(ready(response));
+        }
+
+        let user_id = fields.try_get_user_id()?;
+        let place = fields.require(FORM_DATA_PLACE)?;
+        let (hour, minute) = match fields.get(FORM_DATA_TIME) {
+            Some(time) => try_parse_time(time)?,
+            None => (DEFAULT_HOUR, DEFAULT_MINUTE),
+        };
+
+        let subscription = self.subscriptions.subscribe(
+            &self.google_api,
+            user_id,
+            place,
+            hour,
+            minute,
+            fields.get(FORM_DATA_CONDITION).map(|c| c.to_owned()),
+        );
+
+        Ok(Box::new(subscription.map(|subscription| match subscription {
+            Some(subscription) => json_response(
+                (STATUS_CODE_CREATED_NUMERIC, STATUS_CODE_CREATED_ALPHA),
+                object!{
+                    "subscription" => subscription.to_json(),
+                },
+            ),
+            None => ApiError::invalid_field(FORM_DATA_PLACE, "The place could not be located")
+                .into_response(),
+        })))
+    }
+
+    fn delete_subscription(
+        &self,
+        principal: Option<&Principal>,
+        user_id: &str,
+        id: &str,
+    ) -> Result<Response, ApiError> {
+        let user_id = authorize_user_id(principal, user_id)?;
+        let id = try_parse_id(FORM_DATA_ID, id)?;
+
+        // Sanity check: It must have existed
+        if !self.subscriptions.remove(user_id, id) {
+            return Err(ApiError::unknown_subscription(id));
+        }
+
+        Ok(new_response((
+            STATUS_CODE_NO_CONTENT_NUMERIC,
+            STATUS_CODE_NO_CONTENT_ALPHA,
+        )))
+    }
+
+    /// Whether we are alive, which we must be to answer.
+    fn healthz(&self) -> Response {
+        json_response(
+            (STATUS_CODE_OK_NUMERIC, STATUS_CODE_OK_ALPHA),
+            object!{
+                "status" => "ok",
+            },
+        )
+    }
+
+    fn metrics(&self) -> Response {
+        let (content_type, body) = render();
+        let mut response = new_response((STATUS_CODE_OK_NUMERIC, STATUS_CODE_OK_ALPHA));
+        response.header(HEADER_CONTENT_TYPE, &content_type);
+        response.body(&body);
+        response
+    }
+
+    /// The response refusing the user, if they have sent too much lately.
+    fn limit_user(&self, fields: &Fields) -> Option<Response> {
+        // Anyone without a good user_id is turned away later anyway
+        let user_id = fields.try_get_user_id().ok()?;
+        self.rate_limits
+            .try_take_user(user_id)
+            .err()
+            .map(too_many_requests)
+    }
+
+    fn list_subscriptions(
+        &self,
+        principal: Option<&Principal>,
+        user_id: &str,
+    ) -> Result<Response, ApiError> {
+        let user_id = authorize_user_id(principal, user_id)?;
+
+        let mut subscriptions = JsonValue::new_array();
+        for subscription in self.subscriptions.list(user_id) {
+            // This will always succeed because subscriptions is an array
+            subscriptions.push(subscription.to_json()).unwrap();
+        }
+
+        Ok(json_response(
+            (STATUS_CODE_OK_NUMERIC, STATUS_CODE_OK_ALPHA),
+            object!{
+                "subscriptions" => subscriptions,
+            },
+        ))
+    }
+
+    /// Opts the user in or out of notifications at the webhook.
+    fn set_notify(
+        &self,
+        principal: Option<&Principal>,
+        user_id: &str,
+        notify: bool,
+    ) -> Result<Response, ApiError> {
+        let user_id = authorize_user_id(principal, user_id)?;
+        self.sessions.with_session(user_id, |s| s.notify = notify);
+
+        Ok(new_response((
+            STATUS_CODE_NO_CONTENT_NUMERIC,
+            STATUS_CODE_NO_CONTENT_ALPHA,
+        )))
+    }
+
+    fn slack(&self, request: &Request) -> Result<ResponseFuture, ApiError> {
+        // This will always succeed because the route is only served when configured
+        let slack = self.slack.as_ref().unwrap();
+
+        let ok = (STATUS_CODE_OK_NUMERIC, STATUS_CODE_OK_ALPHA);
+        match slack.try_parse(request)? {
+            SlackRequest::Challenge(challenge) => Ok(ready(json_response(
+                ok,
+                object!{
+                    "challenge" => challenge,
+                },
+            ))),
+            SlackRequest::Command {
+                response_url,
+                text,
+                user_id,
+            } => {
+                // Slack gives up on commands after three seconds, so the answer follows later
+                let event = Event::Message(Message::new(user_id, text));
+                if !self.deferred.defer_command(slack.clone(), response_url, event) {
+                    return Err(ApiError::unavailable("Unable to answer right now"));
                 }
 
-                Event::Join(Join::new(user_id.unwrap(), name.unwrap()))
+                Ok(ready(json_response(ok, JsonValue::new_object())))
             }
-            ACTION_MESSAGE => {
-                // Sanity check: We should have text
-                if text.is_none() {
-                    return bad_request();
+            SlackRequest::Events(updates) => {
+                // Should the events not be queued, Slack's retry mustn't be taken for a duplicate
+                let event_id = updates["event_id"].as_str().map(|id| id.to_owned());
+                let answered = self.answer_channel(slack.clone(), updates);
+                if answered.is_err() {
+                    forget_delivery(event_id.as_ref().map(|id| id.as_str()));
                 }
 
-                Event::Message(Message::new(user_id.unwrap(), text.unwrap()))
+                answered
             }
-            _ => return bad_request(),
-        };
+            SlackRequest::Ignored => Ok(ready(json_response(ok, JsonValue::new_object()))),
+        }
+    }
 
-        // Process the chat logic and produce a one-liner response
-        let chat_response = self.harris.respond(&msg);
+    /// Answers a CORS preflight, which may be for any route.
+    fn preflight(&self, request: &Request) -> Response {
+        let headers = self.cors.preflight_headers(
+            try_get_header(request, HEADER_ORIGIN).as_ref().map(|o| o.as_str()),
+            try_get_header(request, HEADER_ACCESS_CONTROL_REQUEST_METHOD)
+                .as_ref()
+                .map(|m| m.as_str()),
+            try_get_header(request, HEADER_ACCESS_CONTROL_REQUEST_HEADERS)
+                .as_ref()
+                .map(|h| h.as_str()),
+        );
 
-        // Respond to the client using json
-        let mut response = Response::new();
-        response.header(HEADER_CONTENT_TYPE, MIME_TYPE_APPLICATION_JSON);
-        response.body(&object!{
+        match headers {
+            Ok(headers) => {
+                let mut response = new_response((
+                    STATUS_CODE_NO_CONTENT_NUMERIC,
+                    STATUS_CODE_NO_CONTENT_ALPHA,
+                ));
+                for (key, val) in headers {
+                    response.header(key, &val);
+                }
+
+                response
+            }
+            Err(e) => ApiError::cors_rejected(&e).into_response(),
+        }
+    }
 
-            "messages" => array![object!{
-                "type" => "text",
-                "text" => chat_response,
-            }],
-        }.dump());
+    /// Whether we can answer chat messages: the NLU model works, the stores are usable and
+    /// (if we were asked to check) the upstreams can be reached.
+    fn readyz(&self) -> Response {
+        let mut results = vec![
+            ("nlu", self.harris.try_check_nlu()),
+            ("sessions", self.sessions.try_check()),
+            ("subscriptions", self.subscriptions.try_check()),
+        ];
+        if let Some(probe) = self.health.try_probe_upstream() {
+            results.extend(probe);
+        }
 
-        // See if this request contains a CORS header and include a response if so
-        // TODO: A real application might limit the acceptable hosts via config file, etc
-        if try_get_header(request, HEADER_ORIGIN).is_some() {
-            response.header(
-                HEADER_ACCESS_CONTROL_ALLOW_ORIGIN,
-                HEADER_ACCESS_CONTROL_ALLOW_ORIGIN_STAR,
-            );
+        let mut checks = JsonValue::new_object();
+        let mut is_ready = true;
+        for (name, result) in results {
+            match result {
+                Ok(_) => checks[name] = "ok".into(),
+                Err(e) => {
+                    checks[name] = e.into();
+                    is_ready = false;
+                }
+            }
         }
 
-        response
+        let status_code = if is_ready {
+            (STATUS_CODE_OK_NUMERIC, STATUS_CODE_OK_ALPHA)
+        } else {
+            (
+                STATUS_CODE_SERVICE_UNAVAILABLE_NUMERIC,
+                STATUS_CODE_SERVICE_UNAVAILABLE_ALPHA,
+            )
+        };
+
+        json_response(
+            status_code,
+            object!{
+                "status" => if is_ready { "ready" } else { "not_ready" },
+                "checks" => checks,
+            },
+        )
     }
-}
 
-impl Default for Router {
-    fn default() -> Self {
-        Self {
-            harris: Default::default(),
+    /// Answers anything but a preflight.
+    fn respond(&self, request: &Request, method: &str) -> ResponseFuture {
+        // Rate limits come before anything else so floods are cheap to turn away; the
+        // orchestrator's probes are never limited though, or a flood would get us restarted
+        let ip = self.rate_limits.client_ip(
+            self.peer,
+            try_get_header(request, HEADER_X_REAL_IP).as_ref().map(|i| i.as_str()),
+            try_get_header(request, HEADER_X_FORWARDED_FOR).as_ref().map(|f| f.as_str()),
+        );
+        let limited = match request.path() {
+            ROUTE_HEALTHZ | ROUTE_METRICS | ROUTE_READYZ | ROUTE_VERSION => Ok(()),
+            _ => self.rate_limits.try_take_request(ip),
+        };
+        let response = match limited {
+            Err(retry_after) => ready(too_many_requests(retry_after)),
+            Ok(_) => match self.try_authenticate(request) {
+                Ok(principal) => self.route(request, method, principal.as_ref()),
+                Err(e) => {
+                    let mut response = e.into_response();
+                    response.header(HEADER_WWW_AUTHENTICATE, "Bearer");
+                    ready(response)
+                }
+            },
+        };
+
+        // Browsers need the CORS headers on every response, errors included, or they can't
+        // read them
+        let origin = try_get_header(request, HEADER_ORIGIN);
+        let headers = self.cors.headers(origin.as_ref().map(|o| o.as_str()));
+        Box::new(response.map(move |mut response| {
+            for (key, val) in headers {
+                response.header(key, &val);
+            }
+
+            response
+        }))
+    }
+
+    fn route(
+        &self,
+        request: &Request,
+        method: &str,
+        principal: Option<&Principal>,
+    ) -> ResponseFuture {
+        let response = match request.path() {
+            ROUTE_CHAT_MESSAGES if method == METHOD_POST => {
+                return self.chat_messages(request, principal)
+                    .unwrap_or_else(|e| ready(e.into_response()))
+            }
+            ROUTE_CHAT_MESSAGES => method_not_allowed(method, METHOD_POST),
+            ROUTE_HEALTHZ if method == METHOD_GET => self.healthz(),
+            ROUTE_HEALTHZ => method_not_allowed(method, METHOD_GET),
+            ROUTE_METRICS if method == METHOD_GET => self.metrics(),
+            ROUTE_METRICS => method_not_allowed(method, METHOD_GET),
+            path if path.starts_with(ROUTE_NOTIFICATIONS) => {
+                let user_id = &path[ROUTE_NOTIFICATIONS.len()..];
+                match method {
+                    METHOD_DELETE => self.set_notify(principal, user_id, false),
+                    METHOD_PUT => self.set_notify(principal, user_id, true),
+                    _ => Ok(method_not_allowed(method, METHOD_PUT)),
+                }.unwrap_or_else(|e| e.into_response())
+            }
+            ROUTE_READYZ if method == METHOD_GET => self.readyz(),
+            ROUTE_READYZ => method_not_allowed(method, METHOD_GET),
+            ROUTE_VERSION if method == METHOD_GET => json_response(
+                (STATUS_CODE_OK_NUMERIC, STATUS_CODE_OK_ALPHA),
+                self.health.version_to_json(),
+            ),
+            ROUTE_VERSION => method_not_allowed(method, METHOD_GET),
+            path if path.starts_with(ROUTE_CHANNELS) => if method == METHOD_POST {
+                return self.channel(request, &path[ROUTE_CHANNELS.len()..])
+                    .unwrap_or_else(|e| ready(e.into_response()));
+            } else {
+                method_not_allowed(method, METHOD_POST)
+            },
+            ROUTE_SLACK if self.slack.is_none() => {
+                ApiError::not_found(ROUTE_SLACK).into_response()
+            }
+            ROUTE_SLACK if method == METHOD_POST => {
+                return self.slack(request)
+                    .unwrap_or_else(|e| ready(e.into_response()))
+            }
+            ROUTE_SLACK => method_not_allowed(method, METHOD_POST),
+            path if path == ROUTE_SUBSCRIPTIONS
+                || path.starts_with(&format!("{}/", ROUTE_SUBSCRIPTIONS)) =>
+            {
+                return self.route_subscriptions(request, method, path, principal)
+            }
+            path => ApiError::not_found(path).into_response(),
+        };
+
+        ready(response)
+    }
+
+    fn route_subscriptions(
+        &self,
+        request: &Request,
+        method: &str,
+        path: &str,
+        principal: Option<&Principal>,
+    ) -> ResponseFuture {
+        // These are the routes: /subscriptions, /subscriptions/{user_id} and
+        // /subscriptions/{user_id}/{id}
+        let ids: Vec<&str> = path[ROUTE_SUBSCRIPTIONS.len()..]
+            .split('/')
+            .filter(|i| !i.is_empty())
+            .collect();
+        let response = match (ids.len(), method) {
+            (0, METHOD_POST) => {
+                return self.create_subscription(request, principal)
+                    .unwrap_or_else(|e| ready(e.into_response()))
+            }
+            (0, _) => Ok(method_not_allowed(method, METHOD_POST)),
+            (1, METHOD_GET) => self.list_subscriptions(principal, ids[0]),
+            (1, _) => Ok(method_not_allowed(method, METHOD_GET)),
+            (2, METHOD_DELETE) => self.delete_subscription(principal, ids[0], ids[1]),
+            (2, _) => Ok(method_not_allowed(method, METHOD_DELETE)),
+            _ => Err(ApiError::not_found(path)),
+        };
+
+        ready(response.unwrap_or_else(|e| e.into_response()))
+    }
+
+    /// Who the request is from, if the route needs to know. Slack and the channels sign their
+    /// requests instead, so only our own API is checked.
+    fn try_authenticate(&self, request: &Request) -> Result<Option<Principal>, ApiError> {
+        let path = request.path();
+        let is_protected = path == ROUTE_CHAT_MESSAGES || path == ROUTE_SUBSCRIPTIONS
+            || path.starts_with(&format!("{}/", ROUTE_SUBSCRIPTIONS))
+            || path.starts_with(ROUTE_NOTIFICATIONS);
+        if !is_protected || !self.auth.is_enabled() {
+            return Ok(None);
         }
+
+        let credentials = try_get_credentials(request);
+        self.auth
+            .authenticate(credentials.as_ref().map(|c| c.as_str()))
+            .map(Some)
+            .map_err(|e| ApiError::unauthorized(&e))
     }
 }
 
@@ -230,14 +1281,118 @@ impl Service for Router {
     type Request = Request;
     type Response = Response;
     type Error = Error;
-    type Future = future::Ok<Response, Error>;
+    type Future = Box<Future<Item = Response, Error = Error>>;
 
     fn call(&self, request: Request) -> Self::Future {
-        future::ok(match request.path() {
-            ROUTE_CHAT_MESSAGES if request.method().to_uppercase().trim() == METHOD_POST => {
-                self.chat_messages(&request)
+        let started = Instant::now();
+
+        // Anything logged while answering is tagged with this id, which the client also gets back
+        let request_id = logging::request_id(
+            try_get_header(&request, HEADER_X_REQUEST_ID).map(|id| id.trim().to_owned()),
+        );
+        let mut log = RequestLog::begin(request_id.clone());
+
+        LAST_STATUS_CODE.with(|s| s.set(STATUS_CODE_OK_NUMERIC));
+        let method = request.method().to_uppercase().trim().to_owned();
+        let response = log.enter(|| {
+            if method == METHOD_OPTIONS {
+                ready(self.preflight(&request))
+            } else {
+                self.respond(&request, &method)
             }
-            _ => not_found(),
-        })
+        });
+        let answering = Answering {
+            log: Some(log),
+            response: response,
+            status: LAST_STATUS_CODE.with(|s| s.get()),
+        };
+
+        let route = route_label(request.path());
+        let method = method_label(&method);
+        let answered = answering.map(move |(mut response, status, log)| {
+            response.header(HEADER_X_REQUEST_ID, &request_id);
+
+            let elapsed = started.elapsed();
+            observe_http_request(route, method, status, elapsed);
+
+            // The orchestrator's probes and scrapes would drown out everything else
+            let level = match route {
+                ROUTE_HEALTHZ | ROUTE_METRICS | ROUTE_READYZ => Level::Debug,
+                _ => Level::Info,
+            };
+            log.end(level, vec![
+                ("method", method.into()),
+                ("route", route.into()),
+                ("status", status.into()),
+                ("duration_ms", to_millis(elapsed).into()),
+            ]);
+
+            response
+        });
+
+        // Replies don't fail, so neither do responses
+        Box::new(answered.map_err(|_| Error::new(ErrorKind::Other, "Unanswered request")))
+    }
+}
+
+/// A response being worked on, with its request's log and status code. Other requests on the
+/// worker are worked on while it waits on upstream services, so these are put back in place
+/// each time it is polled.
+struct Answering {
+    log: Option<RequestLog>,
+    response: ResponseFuture,
+    status: u32,
+}
+
+impl Future for Answering {
+    type Item = (Response, u32, RequestLog);
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        LAST_STATUS_CODE.with(|s| s.set(self.status));
+        let polled = {
+            let response = &mut self.response;
+            // This will always succeed because the log is only taken once we are ready
+            self.log.as_mut().unwrap().enter(|| response.poll())
+        };
+        self.status = LAST_STATUS_CODE.with(|s| s.get());
+
+        match polled? {
+            // This will always succeed because the log is only taken here
+            Async::Ready(response) => Ok(Async::Ready((
+                response,
+                self.status,
+                self.log.take().unwrap(),
+            ))),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_after_rounds_up() {
+        assert_eq!(to_retry_after_secs(Duration::from_millis(0)), 1);
+        assert_eq!(to_retry_after_secs(Duration::from_millis(200)), 1);
+        assert_eq!(to_retry_after_secs(Duration::from_secs(2)), 2);
+        assert_eq!(to_retry_after_secs(Duration::from_millis(2_001)), 3);
+    }
+
+    #[test]
+    fn routes_are_labelled_without_ids() {
+        assert_eq!(route_label("/chat/messages"), "/chat/messages");
+        assert_eq!(route_label("/healthz"), "/healthz");
+        assert_eq!(route_label("/channels/telegram"), "/channels/{name}");
+        assert_eq!(route_label("/notifications/42"), "/notifications/{user_id}");
+        assert_eq!(route_label("/subscriptions"), "/subscriptions");
+        assert_eq!(route_label("/subscriptions/42"), "/subscriptions/{user_id}");
+        assert_eq!(route_label("/subscriptions/42/"), "/subscriptions/{user_id}");
+        assert_eq!(route_label("/subscriptions/42/7"), "/subscriptions/{user_id}/{id}");
+        assert_eq!(route_label("/subscriptions/42/7/8"), "other");
+        assert_eq!(route_label("/subscriptionsx"), "other");
+        assert_eq!(route_label("/wp-admin"), "other");
     }
 }
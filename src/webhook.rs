@@ -0,0 +1,625 @@
+use std::cmp::min;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+
+use hmac::{Hmac, Mac};
+
+use json::JsonValue;
+
+use num_cpus;
+
+use reqwest::header::{ContentType, Headers};
+use reqwest::Client;
+
+use sha2::Sha256;
+
+use channel::{dispatch, Channel};
+use cli::{get_webhook_dead_letters_file, get_webhook_retries, get_webhook_secret,
+          get_webhook_url};
+use harris::{Event, Harris};
+use logging::{self, to_millis, Level, RequestLog};
+use session::SessionStore;
+use shutdown::Shutdown;
+use slack::SlackChannel;
+use subscription::SubscriptionStore;
+use upstream::Failure;
+use web::reply_to_json;
+
+// Panics
+const PANIC_POISONED_WEBHOOKS: &'static str = "Poisoned webhooks";
+
+// Headers
+const HEADER_DELIVERY: &'static str = "X-Telemacher-Delivery";
+const HEADER_SIGNATURE: &'static str = "X-Telemacher-Signature";
+const HEADER_TIMESTAMP: &'static str = "X-Telemacher-Timestamp";
+
+// Retries wait 1, 2, 4.. seconds, but never longer than 2^this
+const MAX_BACKOFF_EXPONENT: u32 = 8;
+
+// How often the scheduler looks for deliveries which are due to be tried again
+const RETRY_POLL_MILLIS: u64 = 250;
+
+// How many deliveries are posted at once; the rest wait their turn
+const WORKERS: usize = 8;
+
+/// What the delivery workers share.
+struct Courier {
+    client: Client,
+    dead_letters: Mutex<String>,
+    /// Deliveries which have been posted but not yet delivered or buried
+    pending: AtomicUsize,
+    retries: u32,
+    secret: Option<String>,
+    stopping: AtomicBool,
+}
+
+/// What the deferred workers are asked to answer. Each is queued with the id of the request
+/// which deferred it, so that what happens while answering is logged under the same id.
+enum Deferral {
+    /// A Slack slash command, whose reply is posted to the command's response_url
+    Command(Arc<SlackChannel>, String, Event),
+    /// A chat message, whose reply is posted to the webhook with the request id
+    Event(u64, Event),
+    /// Updates from a chat network, whose replies go back over the network
+    Updates(Arc<Channel + Sync>, JsonValue),
+}
+
+/// A payload on its way to a webhook.
+struct Delivery {
+    attempts: u32,
+    id: String,
+    payload: String,
+    url: String,
+}
+
+/// Posts json payloads to webhooks in the background, signing each one with the shared secret
+/// (if there is one) and retrying with exponential backoff. Payloads which still can't be
+/// delivered are appended to the dead letters file.
+#[derive(Clone)]
+pub struct Webhooks {
+    courier: Arc<Courier>,
+    next_id: Arc<AtomicUsize>,
+    sender: Arc<Mutex<Sender<Delivery>>>,
+    url: Option<Arc<String>>,
+}
+
+impl Webhooks {
+    pub fn start() -> Self {
+        let courier = Courier {
+            client: Client::new(),
+            dead_letters: Mutex::new(get_webhook_dead_letters_file()),
+            pending: AtomicUsize::new(0),
+            retries: get_webhook_retries(),
+            secret: get_webhook_secret(),
+            stopping: AtomicBool::new(false),
+        };
+
+        Self::start_with(courier, get_webhook_url())
+    }
+
+    /// Starts a fixed number of workers, so however many deliveries there are (and however
+    /// slow the webhook is) they only ever post a few at a time. Failed deliveries wait out
+    /// their backoff with the scheduler rather than holding up a worker.
+    fn start_with(courier: Courier, url: Option<String>) -> Self {
+        let courier = Arc::new(courier);
+        let (sender, receiver) = channel();
+        let (retry_sender, retry_receiver) = channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..WORKERS {
+            let courier = courier.clone();
+            let receiver = receiver.clone();
+            let retry_sender = retry_sender.clone();
+            thread::spawn(move || deliver_forever(&courier, &receiver, &retry_sender));
+        }
+
+        {
+            let courier = courier.clone();
+            let sender = sender.clone();
+            thread::spawn(move || retry_forever(&courier, &retry_receiver, &sender));
+        }
+
+        Self {
+            courier: courier,
+            next_id: Arc::new(AtomicUsize::new(1)),
+            sender: Arc::new(Mutex::new(sender)),
+            url: url.map(Arc::new),
+        }
+    }
+
+    /// Waits for the deliveries we have to go out before we stop. Deliveries waiting to retry
+    /// are tried once more straight away, and buried if that fails too.
+    pub fn drain(&self, shutdown: &Shutdown) {
+        self.courier.stopping.store(true, Ordering::SeqCst);
+        shutdown.wait_for("webhook deliveries", &self.courier.pending);
+    }
+
+    /// Whether there is a configured webhook for `post` to use.
+    pub fn is_configured(&self) -> bool {
+        self.url.is_some()
+    }
+
+    /// Posts to the configured webhook, returning false if there isn't one.
+    pub fn post(&self, payload: &JsonValue) -> bool {
+        match self.url {
+            Some(ref url) => {
+                self.post_to(url, payload);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn post_to(&self, url: &str, payload: &JsonValue) {
+        // The id stays the same across retries so receivers can ignore duplicates
+        let delivery = Delivery {
+            attempts: 0,
+            id: format!(
+                "{}-{}",
+                Utc::now().timestamp(),
+                self.next_id.fetch_add(1, Ordering::Relaxed)
+            ),
+            payload: payload.dump(),
+            url: url.to_owned(),
+        };
+
+        self.courier.pending.fetch_add(1, Ordering::SeqCst);
+        let sender = self.sender.lock().expect(PANIC_POISONED_WEBHOOKS);
+        if sender.send(delivery).is_err() {
+            self.courier.pending.fetch_sub(1, Ordering::SeqCst);
+            logging::error("Webhook delivery has stopped");
+        }
+    }
+}
+
+/// Answers chat messages in the background, for clients which would rather not wait on a
+/// request: the replies are posted to the configured webhook, or sent back over the chat
+/// network the messages came from.
+#[derive(Clone)]
+pub struct Deferred {
+    next_request_id: Arc<AtomicUsize>,
+    /// Deferrals which have been queued but whose replies haven't been posted or sent yet
+    pending: Arc<AtomicUsize>,
+    sender: Arc<Mutex<Sender<(String, Deferral)>>>,
+    webhooks: Webhooks,
+}
+
+impl Deferred {
+    pub fn start(
+        sessions: SessionStore,
+        subscriptions: SubscriptionStore,
+        webhooks: Webhooks,
+    ) -> Self {
+        // Each worker has its own Harris, much like each HTTP connection does
+        let (sender, receiver) = channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let pending = Arc::new(AtomicUsize::new(0));
+        for _ in 0..num_cpus::get() {
+            let pending = pending.clone();
+            let receiver = receiver.clone();
+            let sessions = sessions.clone();
+            let subscriptions = subscriptions.clone();
+            let webhooks = webhooks.clone();
+            thread::spawn(move || {
+                respond_forever(receiver, pending, sessions, subscriptions, webhooks)
+            });
+        }
+
+        Self {
+            next_request_id: Arc::new(AtomicUsize::new(1)),
+            pending: pending,
+            sender: Arc::new(Mutex::new(sender)),
+            webhooks: webhooks,
+        }
+    }
+
+    /// Queues the event, returning the request id its reply will carry. Nothing is queued if
+    /// there is no webhook to post the reply to.
+    pub fn defer(&self, event: Event) -> Option<u64> {
+        // Sanity check: The reply needs somewhere to go
+        if !self.webhooks.is_configured() {
+            return None;
+        }
+
+        let request_id = self.next_request_id.fetch_add(1, Ordering::Relaxed) as u64;
+        if self.try_queue(Deferral::Event(request_id, event)) {
+            Some(request_id)
+        } else {
+            None
+        }
+    }
+
+    /// Queues a Slack slash command so Slack can be told it arrived straight away (it gives up
+    /// after three seconds), returning whether it was queued. The reply goes to the
+    /// response_url.
+    pub fn defer_command(
+        &self,
+        slack: Arc<SlackChannel>,
+        response_url: String,
+        event: Event,
+    ) -> bool {
+        self.try_queue(Deferral::Command(slack, response_url, event))
+    }
+
+    /// Queues the updates from a chat network so the network can be told they arrived straight
+    /// away, returning whether they were queued. The replies go back over the network.
+    pub fn defer_updates(&self, channel: Arc<Channel + Sync>, updates: JsonValue) -> bool {
+        self.try_queue(Deferral::Updates(channel, updates))
+    }
+
+    /// Waits for the deferrals we have queued to be answered before we stop.
+    pub fn drain(&self, shutdown: &Shutdown) {
+        shutdown.wait_for("async replies", &self.pending);
+    }
+
+    fn try_queue(&self, deferral: Deferral) -> bool {
+        let request_id = logging::current_request_id().unwrap_or_else(|| logging::request_id(None));
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        let sender = self.sender.lock().expect(PANIC_POISONED_WEBHOOKS);
+        if sender.send((request_id, deferral)).is_err() {
+            self.pending.fetch_sub(1, Ordering::SeqCst);
+            return false;
+        }
+
+        true
+    }
+}
+
+/// Posts deliveries as they come, handing failures to the scheduler to try again later, until
+/// they run out of retries (which they do straight away once we are stopping).
+fn deliver_forever(
+    courier: &Courier,
+    receiver: &Mutex<Receiver<Delivery>>,
+    retries: &Sender<(Instant, Delivery)>,
+) {
+    loop {
+        // Only hold the lock while waiting, not while posting
+        let next = {
+            let receiver = receiver.lock().expect(PANIC_POISONED_WEBHOOKS);
+            receiver.recv()
+        };
+        let mut delivery = match next {
+            Ok(delivery) => delivery,
+            Err(_) => return,
+        };
+
+        let error = match try_post(&courier.client, courier.secret.as_ref(), &delivery) {
+            Ok(_) => {
+                courier.pending.fetch_sub(1, Ordering::SeqCst);
+                continue;
+            }
+            Err(e) => e,
+        };
+
+        // Sanity check: A webhook which refuses what we sent will refuse it every time
+        delivery.attempts += 1;
+        if !error.is_transient || delivery.attempts > courier.retries
+            || courier.stopping.load(Ordering::SeqCst)
+        {
+            bury(&courier.dead_letters, &delivery, &error.message);
+            courier.pending.fetch_sub(1, Ordering::SeqCst);
+            continue;
+        }
+
+        let backoff = 1 << min(delivery.attempts - 1, MAX_BACKOFF_EXPONENT);
+        let retry_at = Instant::now() + Duration::from_secs(backoff);
+
+        // This will always succeed because the scheduler outlives the workers
+        retries.send((retry_at, delivery)).ok();
+    }
+}
+
+/// Appends an undeliverable payload to the dead letters file, one json object per line.
+fn bury(dead_letters: &Mutex<String>, delivery: &Delivery, error: &str) {
+    let path = dead_letters.lock().expect(PANIC_POISONED_WEBHOOKS);
+    let line = object!{
+        "id" => delivery.id.as_str(),
+        "url" => delivery.url.as_str(),
+        "attempts" => delivery.attempts,
+        "error" => error,
+        "failed_at" => Utc::now().timestamp(),
+        "payload" => delivery.payload.as_str(),
+    }.dump();
+    let buried = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path.as_str())
+        .and_then(|mut file| writeln!(file, "{}", line));
+    if let Err(e) = buried {
+        logging::error(format!("Unable to write dead letter {}: {}", &delivery.id, e));
+    }
+}
+
+fn respond_forever(
+    receiver: Arc<Mutex<Receiver<(String, Deferral)>>>,
+    pending: Arc<AtomicUsize>,
+    sessions: SessionStore,
+    subscriptions: SubscriptionStore,
+    webhooks: Webhooks,
+) {
+    let harris = Harris::new(sessions, subscriptions);
+    loop {
+        // Only hold the lock while waiting, not while answering
+        let next = {
+            let receiver = receiver.lock().expect(PANIC_POISONED_WEBHOOKS);
+            receiver.recv()
+        };
+        let (request_id, deferral) = match next {
+            Ok(next) => next,
+            Err(_) => return,
+        };
+
+        let started = Instant::now();
+        let mut log = RequestLog::begin(request_id);
+        let deferred = log.enter(|| match deferral {
+            Deferral::Command(slack, response_url, event) => {
+                let reply = harris.respond_blocking(&event);
+                if let Err(e) = slack.respond(&response_url, reply) {
+                    logging::error(format!("Unable to answer the slack command: {}", e));
+                }
+                "slack command"
+            }
+            Deferral::Event(request_id, event) => {
+                let reply = harris.respond_blocking(&event);
+                webhooks.post(&object!{
+                    "type" => "reply",
+                    "request_id" => request_id,
+                    "user_id" => event.user_id(),
+                    "messages" => reply_to_json(reply),
+                });
+                "reply"
+            }
+            Deferral::Updates(channel, updates) => {
+                dispatch(channel.as_ref(), &harris, &updates);
+                channel.name()
+            }
+        });
+        log.end(Level::Info, vec![
+            ("deferred", deferred.into()),
+            ("duration_ms", to_millis(started.elapsed()).into()),
+        ]);
+
+        pending.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Holds failed deliveries until their backoff is over and then queues them for the workers
+/// again; once we are stopping they are all queued straight away, for one last try.
+fn retry_forever(
+    courier: &Courier,
+    receiver: &Receiver<(Instant, Delivery)>,
+    sender: &Sender<Delivery>,
+) {
+    let mut waiting = vec![];
+    loop {
+        match receiver.recv_timeout(Duration::from_millis(RETRY_POLL_MILLIS)) {
+            Ok(retry) => waiting.push(retry),
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+
+        let now = Instant::now();
+        let is_stopping = courier.stopping.load(Ordering::SeqCst);
+        let (due, not_due): (Vec<_>, Vec<_>) = waiting
+            .into_iter()
+            .partition(|&(retry_at, _)| is_stopping || retry_at <= now);
+        waiting = not_due;
+        for (_, delivery) in due {
+            sender.send(delivery).ok();
+        }
+    }
+}
+
+/// The HMAC-SHA256 of the message, hex encoded.
+pub fn hmac_sha256_hex(secret: &str, message: &[u8]) -> String {
+    // This will always succeed because HMAC accepts keys of any length
+    let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes()).unwrap();
+    mac.input(message);
+    mac.result()
+        .code()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Signs "{timestamp}.{payload}" so receivers can tell the payload came from us, recently.
+fn sign(secret: &str, timestamp: i64, payload: &str) -> String {
+    hmac_sha256_hex(secret, format!("{}.{}", timestamp, payload).as_bytes())
+}
+
+/// Posts the delivery once. Only failing to connect, a 429 or a 5xx are worth trying again.
+fn try_post(client: &Client, secret: Option<&String>, delivery: &Delivery) -> Result<(), Failure> {
+    let timestamp = Utc::now().timestamp();
+    let mut headers = Headers::new();
+    headers.set(ContentType::json());
+    headers.set_raw(HEADER_DELIVERY, delivery.id.to_owned());
+    headers.set_raw(HEADER_TIMESTAMP, timestamp.to_string());
+    if let Some(secret) = secret {
+        headers.set_raw(
+            HEADER_SIGNATURE,
+            format!("sha256={}", sign(secret, timestamp, &delivery.payload)),
+        );
+    }
+
+    let response = client
+        .post(delivery.url.as_str())
+        .headers(headers)
+        .body(delivery.payload.to_owned())
+        .send()
+        .map_err(|e| Failure::from(&e))?;
+
+    // Sanity check: Anything other than a 2xx is a failure
+    if !response.status().is_success() {
+        return Err(Failure::from(response.status()));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Read;
+    use std::process;
+
+    use json;
+
+    use fake_server::FakeServer;
+
+    use super::*;
+
+    fn courier(dead_letters: &str, retries: u32, secret: Option<&str>) -> Courier {
+        Courier {
+            client: Client::new(),
+            dead_letters: Mutex::new(dead_letters.to_owned()),
+            pending: AtomicUsize::new(0),
+            retries: retries,
+            secret: secret.map(|s| s.to_owned()),
+            stopping: AtomicBool::new(false),
+        }
+    }
+
+    fn delivery(url: &str) -> Delivery {
+        Delivery {
+            attempts: 0,
+            id: "1-1".to_owned(),
+            payload: "{\"type\":\"notification\"}".to_owned(),
+            url: url.to_owned(),
+        }
+    }
+
+    fn wait_for_nothing_pending(webhooks: &Webhooks) {
+        for _ in 0..100 {
+            if webhooks.courier.pending.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        panic!("Deliveries are still pending");
+    }
+
+    #[test]
+    fn hmac_sha256_hex_matches_known_answer() {
+        assert_eq!(
+            hmac_sha256_hex("key", b"The quick brown fox jumps over the lazy dog"),
+            "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+
+    #[test]
+    fn post_is_signed() {
+        let server = FakeServer::start("200 OK", "");
+        let secret = "secret".to_owned();
+        let delivery = delivery(&server.url);
+        assert!(try_post(&Client::new(), Some(&secret), &delivery).is_ok());
+
+        let request = server.next_request();
+        let timestamp = request
+            .header("x-telemacher-timestamp")
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert_eq!(request.body, delivery.payload);
+        assert_eq!(request.header("x-telemacher-delivery"), Some("1-1"));
+        assert_eq!(
+            request.header("x-telemacher-signature").unwrap(),
+            format!("sha256={}", sign(&secret, timestamp, &delivery.payload))
+        );
+    }
+
+    #[test]
+    fn refused_post_fails() {
+        let server = FakeServer::start("500 Internal Server Error", "");
+        let error = try_post(&Client::new(), None, &delivery(&server.url)).unwrap_err();
+        assert!(error.message.contains("500"));
+        assert!(error.is_transient);
+
+        let server = FakeServer::start("429 Too Many Requests", "");
+        let error = try_post(&Client::new(), None, &delivery(&server.url)).unwrap_err();
+        assert!(error.is_transient);
+
+        let server = FakeServer::start("400 Bad Request", "");
+        let error = try_post(&Client::new(), None, &delivery(&server.url)).unwrap_err();
+        assert!(!error.is_transient);
+    }
+
+    #[test]
+    fn delivered_once() {
+        let server = FakeServer::start("200 OK", "");
+        let webhooks = Webhooks::start_with(courier("unused", 2, None), Some(server.url.clone()));
+        assert!(webhooks.post(&object!{ "type" => "notification" }));
+        wait_for_nothing_pending(&webhooks);
+
+        assert_eq!(server.next_request().body, "{\"type\":\"notification\"}");
+        assert!(server.is_quiet());
+    }
+
+    #[test]
+    fn undeliverable_is_buried() {
+        let server = FakeServer::start("500 Internal Server Error", "");
+        let url = server.url.clone();
+        let path = env::temp_dir().join(format!("telemacher-dead-letters-{}.jsonl", process::id()));
+        let path = path.to_str().unwrap().to_owned();
+        let webhooks = Webhooks::start_with(courier(&path, 1, None), Some(url.clone()));
+        assert!(webhooks.post(&object!{ "type" => "notification" }));
+        wait_for_nothing_pending(&webhooks);
+
+        // Tried, and tried again after a second
+        server.next_request();
+        server.next_request();
+
+        let mut contents = String::new();
+        File::open(&path)
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        fs::remove_file(&path).ok();
+        let dead_letter = json::parse(contents.trim()).unwrap();
+        assert_eq!(dead_letter["attempts"].as_u32(), Some(2));
+        assert_eq!(dead_letter["url"].as_str(), Some(url.as_str()));
+        assert_eq!(
+            dead_letter["payload"].as_str(),
+            Some("{\"type\":\"notification\"}")
+        );
+    }
+
+    #[test]
+    fn refused_is_buried_without_retrying() {
+        let server = FakeServer::start("400 Bad Request", "");
+        let path = env::temp_dir().join(format!("telemacher-refused-{}.jsonl", process::id()));
+        let path = path.to_str().unwrap().to_owned();
+        let webhooks = Webhooks::start_with(courier(&path, 2, None), Some(server.url.clone()));
+        assert!(webhooks.post(&object!{ "type" => "notification" }));
+        wait_for_nothing_pending(&webhooks);
+
+        // Tried the once
+        server.next_request();
+        assert!(server.is_quiet());
+
+        let mut contents = String::new();
+        File::open(&path)
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        fs::remove_file(&path).ok();
+        let dead_letter = json::parse(contents.trim()).unwrap();
+        assert_eq!(dead_letter["attempts"].as_u32(), Some(1));
+        assert!(dead_letter["error"].as_str().unwrap().contains("400"));
+    }
+
+    #[test]
+    fn without_webhook_nothing_is_posted() {
+        let webhooks = Webhooks::start_with(courier("unused", 0, None), None);
+        assert!(!webhooks.is_configured());
+        assert!(!webhooks.post(&object!{ "type" => "notification" }));
+    }
+}